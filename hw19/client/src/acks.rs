@@ -0,0 +1,50 @@
+use shared::Capabilities;
+use std::collections::VecDeque;
+
+/// chat messages sent to the server that are waiting for `Message::Accepted`/`Message::Rejected`
+///
+/// the server acks messages in the same order as they were sent, so the ack always belongs to the oldest pending message
+#[derive(Default)]
+pub struct PendingAcks {
+    /// false if the server doesn't send acks at all
+    enabled: bool,
+    pending: VecDeque<Pending>,
+}
+
+pub struct Pending {
+    /// short description of the message (e.g. file name)
+    pub description: String,
+    /// false for edits, reactions etc. of already stored messages
+    pub new_message: bool,
+}
+
+impl PendingAcks {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self { enabled: capabilities.contains(Capabilities::MESSAGE_IDS), pending: VecDeque::new() }
+    }
+
+    pub fn sent(&mut self, description: String) {
+        self.push(description, true);
+    }
+
+    /// edit/delete/reaction - the ack carries id of the changed message
+    pub fn sent_change(&mut self, description: String) {
+        self.push(description, false);
+    }
+
+    fn push(&mut self, description: String, new_message: bool) {
+        if self.enabled {
+            self.pending.push_back(Pending { description, new_message });
+        }
+    }
+
+    /// returns the message the ack belongs to
+    pub fn acked(&mut self) -> Option<Pending> {
+        self.pending.pop_front()
+    }
+
+    /// messages that will never be acked (the connection was lost); the server may or may not have stored them
+    pub fn unconfirmed(self) -> impl Iterator<Item = Pending> {
+        self.pending.into_iter()
+    }
+}
@@ -0,0 +1,94 @@
+//! delays between reconnection attempts - growing exponentially, with random jitter,
+//! so that clients dropped at once (e.g. by server restart) don't all come back at the same moment
+
+use crate::ReconnectPolicy;
+use std::time::Duration;
+
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    /// attempts made so far
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff { policy, attempt: 0 }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// delay before the next attempt, `None` when there are no attempts left
+    ///
+    /// note: the delay is at least half of the exponential one, so it still grows with every attempt
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.policy.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None;
+        }
+        let exponential = self.policy.initial_delay.saturating_mul(1 << self.attempt.min(16)).min(self.policy.max_delay);
+        self.attempt += 1;
+        let half = exponential / 2;
+        Some(half + half.mul_f64(rand::random::<f64>()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy { initial_delay: Duration::from_millis(100), max_delay: Duration::from_secs(10), max_attempts }
+    }
+
+    #[test]
+    fn test_delay_is_between_half_and_whole_exponential_delay() {
+        // jitter is random, so more rounds
+        for _ in 0..100 {
+            let mut backoff = Backoff::new(policy(None));
+            for attempt in 0..10 {
+                let exponential = (Duration::from_millis(100) * (1 << attempt)).min(Duration::from_secs(10));
+                let delay = backoff.next_delay().unwrap();
+                assert!(delay >= exponential / 2 && delay <= exponential, "attempt {}: {:?} not in {:?}", attempt, delay, exponential);
+            }
+        }
+    }
+
+    #[test]
+    fn test_delay_grows_until_max_delay() {
+        for _ in 0..100 {
+            let mut backoff = Backoff::new(policy(None));
+            let mut previous = Duration::ZERO;
+            // 100ms * 2^6 is the last one under 10s
+            for _ in 0..=6 {
+                let delay = backoff.next_delay().unwrap();
+                assert!(delay >= previous, "{:?} < {:?}", delay, previous);
+                previous = delay;
+            }
+        }
+    }
+
+    #[test]
+    fn test_delay_stays_under_max_delay_forever() {
+        let mut backoff = Backoff::new(policy(None));
+        for _ in 0..1000 {
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay <= Duration::from_secs(10));
+        }
+        assert_eq!(backoff.attempt(), 1000);
+    }
+
+    #[test]
+    fn test_there_are_only_max_attempts() {
+        let mut backoff = Backoff::new(policy(Some(3)));
+        for attempt in 1..=3 {
+            assert!(backoff.next_delay().is_some());
+            assert_eq!(backoff.attempt(), attempt);
+        }
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempt(), 3);
+
+        assert_eq!(Backoff::new(policy(Some(0))).next_delay(), None);
+    }
+}
@@ -0,0 +1,502 @@
+use crate::acks::PendingAcks;
+use crate::recent::RecentMessages;
+use crate::backoff::Backoff;
+use crate::transfer::{self, Transfers};
+use crate::{ClientConfig, Credentials, Event, Request};
+use anyhow::{anyhow, Context, Result};
+use futures::SinkExt;
+use log::{debug, error, info, warn};
+use shared::codec::CodecKind;
+use shared::frame::WireFormat;
+use shared::framed::{self, MessageReader, MessageWriter};
+use shared::tls::{self, StreamReader, StreamWriter};
+use shared::{handshake, Capabilities, Message, ReceiveMessageError, DEFAULT_ROOM, PROTOCOL_VERSION};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinError, JoinSet};
+
+/// requests made while reconnecting wait for the connection, but not without limit
+const MAX_WAITING_REQUESTS: usize = 100;
+
+/// offer of a file read in its own task, with the file and whoever waits for the result
+type ReadOffer = (Result<Message>, PathBuf, Option<oneshot::Sender<Result<()>>>);
+
+/// connection to the server, served by a task (see `serve`)
+///
+/// when the connection is lost, the same user connects again and everything but the stream (and acks) is kept
+pub(crate) struct Connection {
+    user: String,
+    capabilities: Capabilities,
+    reader: MessageReader,
+    writer: MessageWriter,
+    transfers: Transfers,
+    /// offered files being read (checksum); sent when done, see `offer_ready`
+    offers: JoinSet<ReadOffer>,
+    acks: PendingAcks,
+    recent: RecentMessages,
+    idle_timeout: Duration,
+    /// the server announced it's going down, reported as the reason of the disconnection
+    shutdown_notice: Option<String>,
+    /// the last stored message received; the server continues after it when the client connects again
+    last_id: Option<u64>,
+    /// room the user is in (if not the default one), joined again after reconnection
+    room: Option<String>,
+    /// requests made while reconnecting, sent once connected again
+    waiting: VecDeque<Request>,
+}
+
+/// authentication failed, connecting again with the same credentials makes no sense
+#[derive(Debug, thiserror::Error)]
+#[error("Authentication failed: {0}")]
+struct AuthFailed(String);
+
+/// serves the connection; when it's lost, connects again according to the reconnect policy
+pub(crate) async fn serve(mut connection: Connection, mut config: ClientConfig, mut requests: mpsc::Receiver<(Request, oneshot::Sender<Result<()>>)>, events: mpsc::UnboundedSender<Event>) {
+    // the user exists now
+    if let Credentials::Register(password) = &config.credentials {
+        config.credentials = Credentials::Login(password.clone());
+    }
+    loop {
+        let Some(reason) = connection.run(&mut requests, &events).await else {
+            // the client quit
+            return;
+        };
+        let Some(policy) = config.reconnect.clone() else {
+            let _ = events.send(Event::Disconnected { reason });
+            return;
+        };
+        let mut backoff = Backoff::new(policy);
+        loop {
+            let Some(delay) = backoff.next_delay() else {
+                let _ = events.send(Event::Disconnected { reason: format!("{} (gave up after {} attempts)", reason, backoff.attempt()) });
+                return;
+            };
+            let _ = events.send(Event::Reconnecting { reason: reason.clone(), attempt: backoff.attempt(), delay });
+            if !connection.wait(delay, &mut requests).await {
+                return;
+            }
+            match connection.reconnect(&config, &events).await {
+                Ok(()) => break,
+                Err(e) if e.downcast_ref::<AuthFailed>().is_some() => {
+                    let _ = events.send(Event::Disconnected { reason: e.to_string() });
+                    return;
+                },
+                Err(e) => warn!("Unable to connect: {}", e),
+            }
+        }
+        let _ = events.send(Event::Reconnected);
+        connection.send_waiting().await;
+    }
+}
+
+impl Connection {
+    /// connects and logs in
+    pub(crate) async fn open(config: &ClientConfig) -> Result<Connection> {
+        let (user, capabilities, reader, writer) = handshake(config, &config.user, None).await?;
+        Ok(Connection {
+            user,
+            capabilities,
+            reader,
+            writer,
+            transfers: Transfers::new(),
+            offers: JoinSet::new(),
+            acks: PendingAcks::new(capabilities),
+            recent: RecentMessages::default(),
+            idle_timeout: config.idle_timeout,
+            shutdown_notice: None,
+            last_id: None,
+            room: None,
+            waiting: VecDeque::new(),
+        })
+    }
+
+    pub(crate) fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// features supported by both sides
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// connects again as the same user; the server replays what the client missed
+    async fn reconnect(&mut self, config: &ClientConfig, events: &mpsc::UnboundedSender<Event>) -> Result<()> {
+        let (_, capabilities, reader, writer) = handshake(config, &self.user, self.last_id).await?;
+        if capabilities != self.capabilities {
+            warn!("Server supports different features than before ({:?} -> {:?})", self.capabilities, capabilities);
+        }
+        self.capabilities = capabilities;
+        self.reader = reader;
+        self.writer = writer;
+        self.shutdown_notice = None;
+        for name in self.transfers.connection_lost() {
+            let _ = events.send(Event::UploadFailed { name, reason: "Connection was lost, offer the file again to resume the upload".into() });
+        }
+        let acks = std::mem::replace(&mut self.acks, PendingAcks::new(capabilities));
+        for pending in acks.unconfirmed() {
+            let reason = "Connection was lost before the server confirmed it, it may or may not be stored".to_string();
+            let _ = events.send(Event::Rejected { description: pending.description, reason });
+        }
+        if let Some(room) = self.room.clone() {
+            self.writer.send(&Message::JoinRoom { name: room }).await?;
+        }
+        Ok(())
+    }
+
+    /// waits before reconnecting; requests made meanwhile are kept until the client is connected again
+    ///
+    /// returns false if the client quit
+    async fn wait(&mut self, delay: Duration, requests: &mut mpsc::Receiver<(Request, oneshot::Sender<Result<()>>)>) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select!(
+                _ = &mut sleep => return true,
+                request = requests.recv() => {
+                    let Some((request, reply)) = request else {
+                        return false;
+                    };
+                    let result = if self.waiting.len() < MAX_WAITING_REQUESTS {
+                        self.waiting.push_back(request);
+                        Ok(())
+                    } else {
+                        Err(anyhow!("Not connected, too many messages waiting"))
+                    };
+                    let _ = reply.send(result);
+                },
+            )
+        }
+    }
+
+    /// sends requests made while reconnecting; nobody waits for the results anymore, so the errors are just logged
+    async fn send_waiting(&mut self) {
+        while let Some(request) = self.waiting.pop_front() {
+            self.handle_request(request, None).await;
+        }
+    }
+
+    /// serves requests of the client and messages from the server until the connection is lost; returns why,
+    /// or `None` when the client quit
+    async fn run(&mut self, requests: &mut mpsc::Receiver<(Request, oneshot::Sender<Result<()>>)>, events: &mpsc::UnboundedSender<Event>) -> Option<String> {
+        let heartbeat = self.capabilities.contains(Capabilities::HEARTBEAT);
+        let mut heartbeat_interval = tokio::time::interval(shared::HEARTBEAT_INTERVAL);
+        let mut last_heard = Instant::now();
+        let reason = loop {
+            tokio::select!(
+                request = requests.recv() => {
+                    let (request, reply) = request?;
+                    self.handle_request(request, Some(reply)).await;
+                },
+                Some(offer) = self.offers.join_next(), if !self.offers.is_empty() => self.offer_ready(offer).await,
+                // note: chunks go one by one between other messages, so that the server is still read (and pinged) during long uploads
+                (message, completed) = self.transfers.next_upload_message(), if self.transfers.is_uploading() => {
+                    if let Err(e) = self.writer.send(&message).await {
+                        error!("Unable to send file to server. Error: {}", e);
+                    }
+                    // the server acks the upload when it gets the completion, in order with other messages
+                    if let Some(name) = completed {
+                        self.acks.sent(name);
+                    }
+                },
+                // note: receiving is cancel safe, half read message stays in the reader when other branch wins
+                message = self.reader.receive() => {
+                    last_heard = Instant::now();
+                    match message {
+                        Ok(message) => self.handle_message(message, events).await,
+                        Err(ReceiveMessageError::DeserializationError(e)) => error!("Server sent malformed message. Error: {}", e),
+                        Err(ReceiveMessageError::RemoteDisconnected(e)) => break self.shutdown_notice.take().unwrap_or_else(|| format!("Server disconnected. Error: {}", e)),
+                        Err(ReceiveMessageError::GeneralStreamError(e)) => break self.shutdown_notice.take().unwrap_or_else(|| format!("Server stream problems. Error: {}", e)),
+                        Err(e @ ReceiveMessageError::FrameTooLarge { .. }) => break format!("Server sent too big message. Error: {}", e),
+                    }
+                },
+                _ = heartbeat_interval.tick(), if heartbeat => {
+                    if last_heard.elapsed() > self.idle_timeout {
+                        break format!("Server is not responding for {:?}", last_heard.elapsed());
+                    }
+                    if let Err(e) = self.writer.send(&Message::Ping).await {
+                        error!("Unable to ping server. Error: {}", e);
+                    }
+                }
+            )
+        };
+        Some(reason)
+    }
+
+    /// sends the message right away; offered file is read in its own task first, it may take a while (see `offer_ready`)
+    ///
+    /// the result goes to `reply`, it's just logged without it (requests made while reconnecting)
+    async fn handle_request(&mut self, request: Request, reply: Option<oneshot::Sender<Result<()>>>) {
+        let result = match request {
+            Request::Send(message) => self.send_message(message).await,
+            Request::Offer { path, kind } => {
+                let user = self.user.clone();
+                self.offers.spawn(async move { (Transfers::offer(&user, &path, kind).await, path, reply) });
+                return;
+            },
+        };
+        report(reply, result);
+    }
+
+    /// the offered file was read, the offer can be sent
+    async fn offer_ready(&mut self, offer: Result<ReadOffer, JoinError>) {
+        let (offer, path, reply) = match offer {
+            Ok(offer) => offer,
+            Err(e) => { error!("Reading of offered file failed: {}", e); return; },
+        };
+        let result = match offer {
+            Ok(offer) => {
+                self.transfers.offered(&offer, path);
+                self.send_message(offer).await
+            },
+            Err(e) => Err(e),
+        };
+        report(reply, result);
+    }
+
+    async fn send_message(&mut self, message: Message) -> Result<()> {
+        // note: the server may support less after reconnection
+        if !self.capabilities.contains(message.required_capabilities()) {
+            return Err(anyhow!("Server doesn't support this kind of message"));
+        }
+        debug!("-> {:?}", message);
+        self.writer.send(&message).await?;
+
+        // offered files are acked once the upload is complete
+        let acks = &mut self.acks;
+        match message {
+            Message::Text { content, .. } => acks.sent(content),
+            Message::Direct { to, content, .. } => acks.sent(format!("{} (to {})", content, to)),
+            Message::Reply { message, .. } => match *message {
+                Message::Text { content, .. } => acks.sent(content),
+                _ => acks.sent("reply".into()),
+            },
+            Message::Edit { id, .. } => acks.sent_change(format!("edit of #{}", id)),
+            Message::Delete { id, .. } => acks.sent_change(format!("delete of #{}", id)),
+            Message::React { id, emoji } => acks.sent_change(format!("reaction {} to #{}", emoji, id)),
+            Message::File { name, .. } => acks.sent(name),
+            Message::Image { .. } => acks.sent("image".into()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, message: Message, events: &mpsc::UnboundedSender<Event>) {
+        let (id, message) = match message {
+            // note: one bogus id would move the resume cursor past everything the client hasn't received yet
+            Message::Stored { id, message, .. } if !message.is_storable() => {
+                error!("Server sent stored message #{} of unexpected kind, ignoring it: {:?}", id, message);
+                return;
+            },
+            Message::Stored { id, message, .. } => {
+                debug!("<- message #{}", id);
+                self.recent.remember_message(id, &message);
+                // note: room history may come after newer messages, it's not replayed again anyway
+                self.last_id = self.last_id.max(Some(id));
+                (Some(id), *message)
+            },
+            message => (None, message),
+        };
+        let (message, quote) = match message {
+            Message::Reply { parent, message } => (*message, Some(self.recent.quote(parent))),
+            message => (message, None),
+        };
+        let event = match self.handle_chat_message(id, message, quote).await {
+            Ok(event) => event,
+            Err(e) => { error!("{}", e); None },
+        };
+        if let Some(event) = event {
+            // note: nobody listening is fine, the client is quitting
+            let _ = events.send(event);
+        }
+        if let Some(id) = id {
+            if let Err(e) = self.writer.send(&Message::Received { id }).await {
+                error!("Unable to confirm message #{}. Error: {}", id, e);
+            }
+        }
+    }
+
+    /// handles the message; returns what should be reported to the client
+    async fn handle_chat_message(&mut self, id: Option<u64>, message: Message, quote: Option<String>) -> Result<Option<Event>> {
+        let event = match message {
+            Message::Accepted { id, .. } => self.acks.acked().map(|pending| {
+                if pending.new_message {
+                    self.recent.remember(id, format!("[{}]: {}", self.user, pending.description));
+                }
+                Event::Accepted { id, description: pending.description }
+            }),
+            Message::Rejected { reason } => {
+                let description = self.acks.acked().map(|pending| pending.description).unwrap_or_default();
+                Some(Event::Rejected { description, reason })
+            },
+            Message::File { from, name, content } => {
+                let path = save_general_file(&name, &content, "files").await?;
+                Some(Event::FileReceived { from, path })
+            },
+            Message::Image { from, content } => {
+                let name = format!("{}.png", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis());
+                let path = save_general_file(&name, &content, "images").await?;
+                Some(Event::FileReceived { from, path })
+            },
+            Message::FileAccept { transfer_id, checksum, next_chunk } => {
+                self.transfers.upload(transfer_id, &checksum, next_chunk).await?;
+                None
+            },
+            Message::FileOffer { transfer_id: Some(transfer_id), ref from, ref name, kind, ref checksum, .. } => {
+                self.transfers.receive_offer(transfer_id, from, name, kind, checksum).await?;
+                Some(Event::Message { id, message, quote })
+            },
+            Message::FileChunk { transfer_id, index, data } => {
+                self.transfers.receive_chunk(transfer_id, index, &data).await?;
+                None
+            },
+            Message::FileComplete { transfer_id } => {
+                self.transfers.receive_complete(transfer_id).await?.map(|(from, path)| Event::FileReceived { from, path })
+            },
+            Message::FileFailed { transfer_id, reason } => match self.transfers.cancel_upload(transfer_id) {
+                // not completely sent, it's not waiting for ack
+                Some(name) => Some(Event::UploadFailed { name, reason }),
+                None => match self.transfers.receive_failed(transfer_id).await? {
+                    Some(name) => {
+                        // failed upload is never accepted
+                        self.acks.acked();
+                        Some(Event::UploadFailed { name, reason })
+                    },
+                    None => None,
+                },
+            },
+            Message::Pong => {
+                debug!("<- pong");
+                None
+            },
+            Message::RoomJoined { ref name } => {
+                self.room = (name != DEFAULT_ROOM).then(|| name.clone());
+                Some(Event::Message { id, message, quote })
+            },
+            Message::ServerShutdown { ref reason } => {
+                self.shutdown_notice = Some(match reason {
+                    Some(reason) => format!("Server shut down: {}", reason),
+                    None => "Server shut down".into(),
+                });
+                Some(Event::Message { id, message, quote })
+            },
+            message => Some(Event::Message { id, message, quote }),
+        };
+        Ok(event)
+    }
+}
+
+/// hands the result of the request to whoever waits for it, or just logs the error if nobody does
+fn report(reply: Option<oneshot::Sender<Result<()>>>, result: Result<()>) {
+    match reply {
+        // note: the client not waiting anymore is fine
+        Some(reply) => { let _ = reply.send(result); },
+        None => if let Err(e) = result { error!("{}", e) },
+    }
+}
+
+/// returns where the file was saved
+async fn save_general_file(name: &str, content: &[u8], directory: &str) -> Result<PathBuf> {
+    let name = transfer::local_file_name(name)?;
+    let dir = Path::new(directory);
+    if !dir.exists() {
+        tokio::fs::create_dir(dir).await?;
+    }
+    let file_path = dir.join(name);
+    tokio::fs::File::create(&file_path)
+        .await?
+        .write_all(content)
+        .await?;
+    Ok(file_path)
+}
+
+/// opens the connection (TLS if configured), introduces the client and logs in; empty `user` means the local address is used
+///
+/// returns the name of the user, capabilities supported by both sides and the framed stream
+async fn handshake(config: &ClientConfig, user: &str, last_id: Option<u64>) -> Result<(String, Capabilities, MessageReader, MessageWriter)> {
+    info!("Connecting to {}:{}", config.host, config.port);
+    let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    let local_addr = stream.local_addr()?.to_string();
+    let user = if user.is_empty() { local_addr.clone() } else { user.to_string() };
+
+    let (stream_reader, stream_writer) = match &config.ca_cert {
+        Some(ca_cert) => {
+            let connector = tls::connector(ca_cert).context("Unable to load CA certificate")?;
+            let server_name = config.server_name.as_deref().unwrap_or(&config.host);
+            tls::connect(&connector, server_name, stream).await.context("TLS handshake failed")?
+        },
+        None => tls::plain(stream),
+    };
+    let (mut stream_reader, mut stream_writer) = match &config.faults {
+        Some(faults) => faults.wrap(stream_reader, stream_writer),
+        None => (stream_reader, stream_writer),
+    };
+    info!("Connecting as {}, user {}", local_addr, user);
+    let credentials = config.credentials.to_message();
+    let capabilities = try_send_hello(&mut stream_reader, &mut stream_writer, &user, credentials, config.max_frame_size, config.codec, config.compression).await?;
+    if capabilities.contains(Capabilities::RESUME) {
+        if let Err(e) = (Message::Resume { last_id }).send(&mut stream_writer, WireFormat::HELLO).await {
+            return Err(anyhow!("Problems when sending resume message to server: {}", e));
+        }
+    }
+
+    // note: hello was read frame by frame, so no part of later messages is lost when the stream gets framed
+    let format = WireFormat::negotiate(capabilities);
+    Ok((user, capabilities, MessageReader::new(stream_reader, format, config.max_frame_size), framed::writer(stream_writer, format, config.max_frame_size)))
+}
+
+/// introduces the client to the server
+///
+/// only the requested codec is advertised (hello messages are always bincode)
+///
+/// returns capabilities supported by both sides; only these features may be used later
+async fn try_send_hello(stream_reader: &mut StreamReader, stream_writer: &mut StreamWriter, user: &str, credentials: Message, max_frame_size: usize, codec: CodecKind, compression: bool) -> Result<Capabilities> {
+
+    let mut capabilities = Capabilities::all()
+        .without(Capabilities::JSON_CODEC.union(Capabilities::MSGPACK_CODEC))
+        .union(codec.capability());
+    if !compression {
+        capabilities = capabilities.without(Capabilities::COMPRESSION);
+    }
+    let msg = Message::ClientHello{ version: PROTOCOL_VERSION, from: user.into(), capabilities };
+
+    // note: now idea how to just call
+    //    msg.send_async(stream_writer).await?;
+    // so that it's converted to Result<()>
+    // it complains:
+    //    `dyn std::error::Error` cannot be shared between threads safely
+    //    the trait `Sync` is not implemented for `dyn std::error::Error` etc.
+    // somewhere used anyhow::from_boxed (https://github.com/dtolnay/anyhow/issues/83), but it's obviously not possible anymore (anyhow::error::from_boxed is private)
+    if let Err(e) = msg.send(stream_writer, WireFormat::HELLO).await {
+         return Err(anyhow!("Problems when sending hello message to server: {}", e));
+    }
+    match Message::receive(stream_reader, max_frame_size, WireFormat::HELLO).await? {
+        Message::ServerHello { version, capabilities: server_capabilities } => {
+            handshake::check_version(version).map_err(|reason| anyhow!(reason))?;
+            let capabilities = server_capabilities.intersection(capabilities);
+            if capabilities.contains(Capabilities::AUTH) {
+                try_authenticate(stream_reader, stream_writer, credentials, max_frame_size).await?;
+            } else {
+                warn!("Server doesn't support authentication, connecting without password");
+            }
+            info!("Connected as {} (server protocol version {}, {})", user, version, WireFormat::negotiate(capabilities));
+            Ok(capabilities)
+        },
+        Message::ServerRefused { reason } => Err(anyhow!("Server refused connection: {}", reason)),
+        _ => Err(anyhow!("Unexpected message from server")),
+    }
+}
+
+async fn try_authenticate(stream_reader: &mut StreamReader, stream_writer: &mut StreamWriter, credentials: Message, max_frame_size: usize) -> Result<()> {
+    if let Err(e) = credentials.send(stream_writer, WireFormat::HELLO).await {
+        return Err(anyhow!("Problems when sending credentials to server: {}", e));
+    }
+    match Message::receive(stream_reader, max_frame_size, WireFormat::HELLO).await? {
+        Message::AuthOk => Ok(()),
+        Message::AuthFailed { reason } => Err(AuthFailed(reason).into()),
+        _ => Err(anyhow!("Unexpected message from server")),
+    }
+}
@@ -0,0 +1,456 @@
+//! chat client as a library - connects and logs in, sends typed messages and gives back stream of events
+//!
+//! the connection is served by a background task (receiving, heartbeats, file transfers, confirmations),
+//! so the events may be awaited together with anything else (e.g. in `select!`) without losing half read messages
+//!
+//! typical use: `ChatClient::connect`, then `send_*` methods and `next_event` (or `ChatClient` as a `Stream`) in a loop until `Event::Disconnected`
+//!
+//! lost connection is opened again (see `ReconnectPolicy`), the server then replays everything after the last received message
+
+mod acks;
+mod backoff;
+mod connection;
+mod recent;
+mod transfer;
+
+use anyhow::{anyhow, Context, Result};
+use futures::Stream;
+use log::info;
+use shared::codec::CodecKind;
+use shared::fault::FaultInjector;
+use shared::transfer::TransferKind;
+use shared::{Capabilities, Message, PresenceState};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// how the user proves who they are
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// without password, the server has to allow it (and the name must not be registered)
+    Anonymous,
+    Login(String),
+    /// registers the user with the password instead of logging in
+    Register(String),
+}
+
+impl Credentials {
+    fn to_message(&self) -> Message {
+        match self {
+            Credentials::Anonymous => Message::Login { password: None },
+            Credentials::Login(password) => Message::Login { password: Some(password.clone()) },
+            Credentials::Register(password) => Message::Register { password: password.clone() },
+        }
+    }
+}
+
+/// where and how to connect
+pub struct ClientConfig {
+    pub host: String,
+    pub port: u16,
+    /// empty name means the local address is used
+    pub user: String,
+    pub credentials: Credentials,
+    /// biggest message (in bytes) accepted from server
+    pub max_frame_size: usize,
+    /// wire format used after handshake; bincode is used if the server doesn't support it
+    pub codec: CodecKind,
+    /// offer compression of big frames to the server
+    pub compression: bool,
+    /// connect over TLS and trust only server certificates signed by this CA (PEM)
+    pub ca_cert: Option<PathBuf>,
+    /// name the server certificate has to be issued for; `host` is used by default
+    pub server_name: Option<String>,
+    /// time without any message from server after which the server is considered dead
+    pub idle_timeout: Duration,
+    pub faults: Option<FaultInjector>,
+    /// `None` means the client is disconnected for good when the connection is lost
+    pub reconnect: Option<ReconnectPolicy>,
+}
+
+/// how to connect again when the connection is lost
+///
+/// the first connection is not retried, failing to connect at all is more likely a wrong address than a network blip
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// delay before the first attempt, doubled with every failed one (with random jitter)
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// `None` means trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy { initial_delay: Duration::from_millis(500), max_delay: Duration::from_secs(30), max_attempts: None }
+    }
+}
+
+impl ClientConfig {
+    /// anonymous user with default settings
+    pub fn new(host: &str, port: u16) -> Self {
+        ClientConfig {
+            host: host.into(),
+            port,
+            user: String::new(),
+            credentials: Credentials::Anonymous,
+            max_frame_size: shared::DEFAULT_MAX_FRAME_SIZE,
+            codec: CodecKind::Bincode,
+            compression: true,
+            ca_cert: None,
+            server_name: None,
+            idle_timeout: Duration::from_secs(shared::DEFAULT_IDLE_TIMEOUT_SECS),
+            faults: None,
+            reconnect: Some(ReconnectPolicy::default()),
+        }
+    }
+}
+
+/// what happened on the connection
+#[derive(Debug)]
+pub enum Event {
+    /// message from the server or other users
+    ///
+    /// `id` is set for messages stored by the server (so that they can be edited, reacted to...);
+    /// replies are unwrapped, `quote` then describes the parent message (or just its id if the client hasn't seen it)
+    Message { id: Option<u64>, message: Message, quote: Option<String> },
+    /// own message was stored by the server
+    Accepted { id: u64, description: String },
+    /// own message or its change was refused by the server
+    Rejected { description: String, reason: String },
+    /// file or image sent by other user was saved
+    FileReceived { from: String, path: PathBuf },
+    UploadFailed { name: String, reason: String },
+    /// the connection was lost, next attempt to connect again is made after `delay`
+    ///
+    /// requests made meanwhile are sent once the client is connected again
+    Reconnecting { reason: String, attempt: u32, delay: Duration },
+    /// connected (and logged in) again
+    Reconnected,
+    /// the connection is closed, no more events follow
+    Disconnected { reason: String },
+}
+
+/// things the background task does on behalf of the client
+enum Request {
+    Send(Message),
+    /// chunked transfer of the file
+    Offer { path: PathBuf, kind: TransferKind },
+}
+
+/// connected (and logged in) client
+pub struct ChatClient {
+    user: String,
+    capabilities: Capabilities,
+    requests: mpsc::Sender<(Request, oneshot::Sender<Result<()>>)>,
+    events: mpsc::UnboundedReceiver<Event>,
+    task: JoinHandle<()>,
+}
+
+impl ChatClient {
+    pub async fn connect(config: ClientConfig) -> Result<Self> {
+        let connection = connection::Connection::open(&config).await?;
+        let (user, capabilities) = (connection.user().to_string(), connection.capabilities());
+        let (requests_tx, requests_rx) = mpsc::channel(16);
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(connection::serve(connection, config, requests_rx, events_tx));
+        Ok(ChatClient { user, capabilities, requests: requests_tx, events: events_rx, task })
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// features supported by both sides
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// waits for the next event; `None` once the connection is closed
+    ///
+    /// cancellation safe
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// closes the connection
+    pub async fn quit(self) {
+        drop(self.requests);
+        if let Err(e) = self.task.await {
+            log::error!("Connection task failed: {}", e);
+        }
+        info!("Disconnected");
+    }
+
+    /// sends any message; fails if the server doesn't support it or the connection is closed
+    ///
+    /// while the client is reconnecting, the message waits and succeeds right away
+    pub async fn send(&self, message: Message) -> Result<()> {
+        if !self.capabilities.contains(message.required_capabilities()) {
+            return Err(anyhow!("Server doesn't support this kind of message"));
+        }
+        self.request(Request::Send(message)).await
+    }
+
+    async fn request(&self, request: Request) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send((request, tx)).await.map_err(|_| anyhow!("Not connected"))?;
+        rx.await.map_err(|_| anyhow!("Not connected"))?
+    }
+
+    pub async fn send_text(&self, content: &str) -> Result<()> {
+        self.send(Message::Text { from: self.user.clone(), content: content.into() }).await
+    }
+
+    /// private message, the server keeps it until the recipient connects
+    pub async fn send_direct(&self, to: &str, content: &str) -> Result<()> {
+        self.send(Message::Direct { from: self.user.clone(), to: to.into(), content: content.into() }).await
+    }
+
+    pub async fn send_file(&self, path: &Path) -> Result<()> {
+        self.send_transfer(path, TransferKind::File).await
+    }
+
+    pub async fn send_image(&self, path: &Path) -> Result<()> {
+        self.send_transfer(path, TransferKind::Image).await
+    }
+
+    /// chunked transfer is preferred, whole file in one message is used only with servers not supporting it
+    async fn send_transfer(&self, path: &Path, kind: TransferKind) -> Result<()> {
+        if self.capabilities.contains(Capabilities::FILE_TRANSFER) {
+            return self.request(Request::Offer { path: path.to_path_buf(), kind }).await;
+        }
+        let content = tokio::fs::read(path).await?;
+        let message = match kind {
+            TransferKind::File => {
+                let name = path.file_name().context("Unable to get file name")?.to_str().context("Unable to get file name")?;
+                Message::File { from: self.user.clone(), name: name.into(), content }
+            },
+            TransferKind::Image => Message::Image { from: self.user.clone(), content },
+        };
+        self.send(message).await
+    }
+
+    pub async fn reply(&self, parent: u64, content: &str) -> Result<()> {
+        let reply = Message::Text { from: self.user.clone(), content: content.into() };
+        self.send(Message::Reply { parent, message: Box::new(reply) }).await
+    }
+
+    pub async fn edit(&self, id: u64, content: &str) -> Result<()> {
+        self.send(Message::Edit { id, from: self.user.clone(), content: content.into() }).await
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<()> {
+        self.send(Message::Delete { id, from: self.user.clone() }).await
+    }
+
+    /// adds the reaction, or removes it if it's already there
+    pub async fn react(&self, id: u64, emoji: &str) -> Result<()> {
+        self.send(Message::React { id, emoji: emoji.into() }).await
+    }
+
+    pub async fn set_presence(&self, state: PresenceState, status: Option<&str>) -> Result<()> {
+        self.send(Message::Presence { from: self.user.clone(), state, status: status.map(Into::into) }).await
+    }
+
+    pub async fn typing(&self) -> Result<()> {
+        self.send(Message::Typing { from: self.user.clone() }).await
+    }
+
+    pub async fn create_room(&self, name: &str) -> Result<()> {
+        self.send(Message::CreateRoom { name: name.into() }).await
+    }
+
+    pub async fn join_room(&self, name: &str) -> Result<()> {
+        self.send(Message::JoinRoom { name: name.into() }).await
+    }
+
+    /// back to the default room
+    pub async fn leave_room(&self) -> Result<()> {
+        self.send(Message::LeaveRoom).await
+    }
+
+    /// the list comes as `Message::RoomList` event
+    pub async fn list_rooms(&self) -> Result<()> {
+        self.send(Message::ListRooms).await
+    }
+
+    /// stored messages containing all the words; they come as `Message::SearchResults` event
+    pub async fn search(&self, query: &str) -> Result<()> {
+        self.send(Message::Search { query: query.into() }).await
+    }
+}
+
+impl Stream for ChatClient {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared::frame::WireFormat;
+    use shared::PROTOCOL_VERSION;
+    use tokio::net::TcpListener;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    /// what the client gets to know from the scripted server
+    fn server_capabilities() -> Capabilities {
+        Capabilities::AUTH.union(Capabilities::MESSAGE_IDS).union(Capabilities::RESUME).union(Capabilities::FILE_TRANSFER)
+    }
+
+    async fn receive(reader: &mut (impl tokio::io::AsyncRead + Unpin), format: WireFormat) -> Message {
+        Message::receive(reader, shared::DEFAULT_MAX_FRAME_SIZE, format).await.unwrap()
+    }
+
+    fn config(port: u16) -> ClientConfig {
+        ClientConfig {
+            user: "alice".into(),
+            credentials: Credentials::Login("secret".into()),
+            reconnect: None,
+            ..ClientConfig::new("127.0.0.1", port)
+        }
+    }
+
+    /// accepts the client and plays the server side of the handshake; the client has to resume after `last_id`
+    async fn scripted_handshake(listener: &TcpListener, last_id: Option<u64>) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        let Message::ClientHello { version, from, capabilities } = receive(&mut reader, WireFormat::HELLO).await else { panic!("hello expected") };
+        assert_eq!((version, from.as_str()), (PROTOCOL_VERSION, "alice"));
+        assert!(capabilities.contains(server_capabilities()));
+        Message::ServerHello { version: PROTOCOL_VERSION, capabilities: server_capabilities() }.send(&mut writer, WireFormat::HELLO).await.unwrap();
+        assert_eq!(receive(&mut reader, WireFormat::HELLO).await, Message::Login { password: Some("secret".into()) });
+        Message::AuthOk.send(&mut writer, WireFormat::HELLO).await.unwrap();
+        assert_eq!(receive(&mut reader, WireFormat::HELLO).await, Message::Resume { last_id });
+        (reader, writer)
+    }
+
+    /// the server side of the connection, played message by message
+    async fn scripted_server(listener: TcpListener) {
+        let (mut reader, mut writer) = scripted_handshake(&listener, None).await;
+        let format = WireFormat::negotiate(server_capabilities());
+        assert_eq!(receive(&mut reader, format).await, Message::Text { from: "alice".into(), content: "hello".into() });
+        Message::Accepted { id: 1, time: 0 }.send(&mut writer, format).await.unwrap();
+        let reply = Message::Text { from: "bob".into(), content: "hi alice".into() };
+        Message::Stored { id: 2, time: 0, message: Box::new(reply) }.send(&mut writer, format).await.unwrap();
+        assert_eq!(receive(&mut reader, format).await, Message::Received { id: 2 });
+        // closes the connection
+    }
+
+    #[tokio::test]
+    async fn test_client_sends_and_receives_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(scripted_server(listener));
+
+        let mut client = ChatClient::connect(config(port)).await.unwrap();
+        assert_eq!(client.user(), "alice");
+        assert_eq!(client.capabilities(), server_capabilities());
+
+        client.send_text("hello").await.unwrap();
+        match client.next_event().await {
+            Some(Event::Accepted { id: 1, description }) => assert_eq!(description, "hello"),
+            other => panic!("unexpected event {:?}", other),
+        }
+        match client.next_event().await {
+            Some(Event::Message { id: Some(2), message: Message::Text { from, content }, quote: None }) => assert_eq!((from.as_str(), content.as_str()), ("bob", "hi alice")),
+            other => panic!("unexpected event {:?}", other),
+        }
+        server.await.unwrap();
+        assert!(matches!(client.next_event().await, Some(Event::Disconnected { .. })));
+        assert!(client.next_event().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_resumes_after_the_last_stored_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut reader, mut writer) = scripted_handshake(&listener, None).await;
+            let format = WireFormat::negotiate(server_capabilities());
+            let text = Message::Text { from: "bob".into(), content: "hi".into() };
+            // stored messages are never stored again, it's not from the server's storage
+            let bogus = Message::Stored { id: 5, time: 0, message: Box::new(text.clone()) };
+            Message::Stored { id: u64::MAX, time: 0, message: Box::new(bogus) }.send(&mut writer, format).await.unwrap();
+            Message::Stored { id: 3, time: 0, message: Box::new(text) }.send(&mut writer, format).await.unwrap();
+            assert_eq!(receive(&mut reader, format).await, Message::Received { id: 3 });
+            drop((reader, writer));
+            // the connection is lost, the client continues after the last real message
+            scripted_handshake(&listener, Some(3)).await
+        });
+
+        let reconnect = ReconnectPolicy { initial_delay: Duration::from_millis(10), max_delay: Duration::from_millis(10), max_attempts: Some(1) };
+        let mut client = ChatClient::connect(ClientConfig { reconnect: Some(reconnect), ..config(port) }).await.unwrap();
+        assert!(matches!(client.next_event().await, Some(Event::Message { id: Some(3), .. })));
+        assert!(matches!(client.next_event().await, Some(Event::Reconnecting { .. })));
+        assert!(matches!(client.next_event().await, Some(Event::Reconnected)));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_is_read_during_upload() {
+        const CHUNKS: u64 = 20;
+        let path = std::env::temp_dir().join(format!("chat_upload_{}.bin", std::process::id()));
+        let content: Vec<u8> = (0..CHUNKS as usize * shared::transfer::CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut reader, mut writer) = scripted_handshake(&listener, None).await;
+            let format = WireFormat::negotiate(server_capabilities());
+            let Message::FileOffer { transfer_id: None, size, checksum, .. } = receive(&mut reader, format).await else { panic!("offer expected") };
+            assert_eq!(size, content.len() as u64);
+            Message::FileAccept { transfer_id: 7, checksum, next_chunk: 0 }.send(&mut writer, format).await.unwrap();
+            let text = Message::Text { from: "bob".into(), content: "hi".into() };
+            Message::Stored { id: 3, time: 0, message: Box::new(text) }.send(&mut writer, format).await.unwrap();
+
+            let (mut chunks, mut confirmed_during_upload) = (vec![], false);
+            loop {
+                match receive(&mut reader, format).await {
+                    Message::FileChunk { transfer_id: 7, index, data } => chunks.push((index, data.len())),
+                    Message::Received { id: 3 } => confirmed_during_upload = true,
+                    Message::FileComplete { transfer_id: 7 } => break,
+                    other => panic!("unexpected message {:?}", other),
+                }
+            }
+            // the message was handled without waiting for the end of the upload
+            assert!(confirmed_during_upload);
+            assert_eq!(chunks, (0..CHUNKS).map(|index| (index, shared::transfer::CHUNK_SIZE)).collect::<Vec<_>>());
+            Message::Accepted { id: 4, time: 0 }.send(&mut writer, format).await.unwrap();
+            (reader, writer)
+        });
+
+        let mut client = ChatClient::connect(config(port)).await.unwrap();
+        client.send_file(&path).await.unwrap();
+        assert!(matches!(client.next_event().await, Some(Event::Message { id: Some(3), .. })));
+        match client.next_event().await {
+            Some(Event::Accepted { id: 4, description }) => assert!(description.starts_with("chat_upload_"), "{}", description),
+            other => panic!("unexpected event {:?}", other),
+        }
+        server.await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_client_is_not_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(matches!(receive(&mut stream, WireFormat::HELLO).await, Message::ClientHello { .. }));
+            Message::ServerRefused { reason: "User alice already connected".into() }.send(&mut stream, WireFormat::HELLO).await.unwrap();
+        });
+
+        let error = ChatClient::connect(config(port)).await.err().unwrap();
+        assert!(error.to_string().contains("User alice already connected"), "{}", error);
+        server.await.unwrap();
+    }
+}
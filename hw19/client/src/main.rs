@@ -1,15 +1,12 @@
-use shared::{Message, chaos, ReceiveMessageError};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::{net::tcp::OwnedWriteHalf, io::AsyncWriteExt}; //https://github.com/Miosso/rust-workspace
-use tokio::io::AsyncReadExt;
-use tokio::fs::File;
-use tokio::net::TcpStream;
-use std::net::SocketAddr;
-use std::path::Path;
-use std::time::SystemTime;
+use client::{ChatClient, ClientConfig, Credentials, Event, ReconnectPolicy};
+use shared::{Message, PresenceState};
+use shared::fault::FaultInjector;
+use shared::codec::CodecKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use clap::Parser;
-use log::{info, debug, warn, error};
-use anyhow::{Result, Context,anyhow};
+use log::{info, warn, error};
+use anyhow::{Result, Context, anyhow};
 
 // looks like common code for client and server, but this is not typical dry sample
 #[derive(Parser)]
@@ -20,177 +17,218 @@ struct ConnectionArgs {
     host: String,
     #[arg(short = 'u', long, default_value = "")]
     user: String,
+    /// biggest message (in bytes) accepted from server
+    #[arg(long, default_value_t = shared::DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// wire format used after handshake (bincode, json, msgpack); bincode is used if the server doesn't support it
+    #[arg(long, default_value_t = CodecKind::Bincode)]
+    codec: CodecKind,
+    /// don't offer compression of big frames to the server
+    #[arg(long)]
+    no_compression: bool,
+    /// connect over TLS and trust only server certificates signed by this CA (PEM)
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    /// name the server certificate has to be issued for; `host` is used by default
+    #[arg(long, requires = "ca_cert")]
+    server_name: Option<String>,
+    /// registers the user on the server (with password) instead of logging in
+    #[arg(long, requires = "user")]
+    register: bool,
+    /// seconds without any message from server after which the server is considered dead
+    #[arg(long, default_value_t = shared::DEFAULT_IDLE_TIMEOUT_SECS)]
+    idle_timeout: u64,
+    /// quit when the connection is lost instead of connecting again
+    #[arg(long)]
+    no_reconnect: bool,
+    /// give up reconnecting after this many failed attempts (tries forever by default)
+    #[arg(long, conflicts_with = "no_reconnect")]
+    reconnect_attempts: Option<u32>,
 }
 
-async fn process_stdin_command(user_name: &str, command: &str, tcpstream: &mut OwnedWriteHalf) -> Result<(), Box<dyn std::error::Error>> {
-    async fn file_to_message(user_name: &str, file_path: &str) -> Result<Message> {
-        let path = Path::new(file_path);
-        let mut content = Vec::new();
-        File::open(path)
-            .await?
-            .read_to_end(&mut content)
-            .await?;
-        Ok(Message::File {
-            from: user_name.into(),
-            name: path.file_name().context("Unable to get file name")?.to_str().context("Unable to get file name")?.into(),
-            content,
-        })
-    }
-
-    async fn image_to_message(user_name: &str, file_path: &str) -> Result<Message> {
-        let Message::File { from:_, name: _, content } = 
-            file_to_message(user_name, file_path).await.context("Image processing failed")?
-            else {
-                panic!("Unexpected type");
-            };
-        
-        let path = Path::new(file_path);
-        match path
-            .extension()
-            .context("Unable to get extension")?
-            .to_str()
-            .context("Unable to get extension")?
-        {
-            ".png" => Ok(Message::Image{ from: user_name.into(), content}),
-            _ => Ok(Message::Image{ from: user_name.into(), content}),
-        }
+/// password is taken from `CHATAPP_PASSWORD` environment variable or asked for; without user name the client connects anonymously
+fn credentials(args: &ConnectionArgs) -> Result<Credentials> {
+    if args.user.is_empty() {
+        return Ok(Credentials::Anonymous);
     }
+    let password = match std::env::var("CHATAPP_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => rpassword::prompt_password(format!("Password for {}: ", args.user)).context("Unable to read password")?,
+    };
+    Ok(if args.register { Credentials::Register(password) } else { Credentials::Login(password) })
+}
 
+async fn process_stdin_command(client: &ChatClient, command: &str) -> Result<()> {
     let command = command.trim();
-    let message = if command.starts_with(".file ") {
-        file_to_message(user_name, &command[".file ".len()..]).await?
-    } else if command.starts_with(".image ") {
-        image_to_message(user_name, &command[".image ".len()..]).await?
+    if let Some(file_path) = command.strip_prefix(".file ") {
+        client.send_file(Path::new(file_path)).await
+    } else if let Some(file_path) = command.strip_prefix(".image ") {
+        client.send_image(Path::new(file_path)).await
+    } else if let Some(name) = command.strip_prefix(".create ") {
+        client.create_room(name.trim()).await
+    } else if let Some(name) = command.strip_prefix(".join ") {
+        client.join_room(name.trim()).await
+    } else if command == ".leave" {
+        client.leave_room().await
+    } else if command == ".rooms" {
+        client.list_rooms().await
+    } else if let Some(query) = command.strip_prefix(".search ") {
+        client.search(query.trim()).await
+    } else if let Some(rest) = command.strip_prefix(".msg ") {
+        let Some((to, content)) = rest.trim().split_once(' ') else {
+            return Err(anyhow!("Usage: .msg <user> <text>"));
+        };
+        client.send_direct(to, content.trim()).await
+    } else if let Some(rest) = command.strip_prefix(".status ") {
+        let (state, status) = match rest.trim().split_once(' ') {
+            Some((state, status)) => (state, Some(status.trim())),
+            None => (rest.trim(), None),
+        };
+        let state = state.parse::<PresenceState>().map_err(|e| anyhow!(e))?;
+        client.set_presence(state, status).await
+    } else if let Some(rest) = command.strip_prefix(".edit ") {
+        let Some((id, content)) = rest.trim().split_once(' ') else {
+            return Err(anyhow!("Usage: .edit <id> <text>"));
+        };
+        client.edit(id.parse()?, content.trim()).await
+    } else if let Some(id) = command.strip_prefix(".delete ") {
+        client.delete(id.trim().parse()?).await
+    } else if let Some(rest) = command.strip_prefix(".react ") {
+        let Some((id, emoji)) = rest.trim().split_once(' ') else {
+            return Err(anyhow!("Usage: .react <id> <emoji>"));
+        };
+        client.react(id.parse()?, emoji.trim()).await
+    } else if let Some(rest) = command.strip_prefix(".reply ") {
+        let Some((parent, content)) = rest.trim().split_once(' ') else {
+            return Err(anyhow!("Usage: .reply <id> <text>"));
+        };
+        client.reply(parent.parse()?, content.trim()).await
+    } else if command == ".typing" {
+        // note: stdin is read by lines, so the client can't tell on its own that the user is typing
+        client.typing().await
     } else {
-        Message::Text{ from: user_name.into(), content: command.into() }
-    };
-
-    debug!("-> {:?}", message);
-    message.send(tcpstream).await
+        client.send_text(command).await
+    }
 }
 
-async fn handle_message(current_user: &str, message: &Message) {
-    async fn save_general_file(name: &str, content: &[u8], directory: &str) -> Result<()> {
-        let dir = Path::new(directory);
-        if !dir.exists() {
-            tokio::fs::create_dir(dir).await?;
-        }
-        let file_path = dir.join(name);
-        File::create(file_path)
-            .await?
-            .write_all(content)
-            .await?;
-        Ok(())
-    }
+fn id_suffix(id: Option<u64>) -> String {
+    id.map(|id| format!(" #{}", id)).unwrap_or_default()
+}
 
-    async fn save_file(name: &str, content: &[u8]) -> Result<()> {
-        save_general_file(name, content, "files").await
+fn print_event(current_user: &str, event: Event) {
+    match event {
+        Event::Message { id, message, quote } => {
+            // replies are shown with a quote of the parent message
+            if let Some(quote) = quote {
+                println!("|{}|  > {}", current_user, quote);
+            }
+            print_message(current_user, id, &message);
+        },
+        Event::Accepted { id, description } => info!("Message '{}' stored as #{}", description, id),
+        Event::Rejected { description, reason } => println!("|{}|Message '{}' was rejected: {}", current_user, description, reason),
+        Event::FileReceived { from, path } => println!("|{}|[{}]: Received {}", current_user, from, path.display()),
+        Event::UploadFailed { name, reason } => println!("|{}|Upload of {} failed: {}", current_user, name, reason),
+        Event::Reconnecting { reason, attempt, delay } => warn!("{}. Reconnecting in {:.1?} (attempt {})...", reason, delay, attempt),
+        Event::Reconnected => println!("|{}|Connected again", current_user),
+        Event::Disconnected { reason } => error!("{}", reason),
     }
+}
 
-    async fn save_img(content: &[u8]) -> Result<()> {
-        let name = format!(
-            "{}.png",
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)?
-                .as_millis()
-                .to_string()
-        );
-        save_general_file(&name, content, "images").await
-    }
-    let message_result = match message {
-        Message::File { from, name, content } => {
-            println!("|{}|[{}]: Receiving {}", current_user, from, name);
-            save_file(name, content).await
+/// `id` is set for messages stored by the server, it's shown so that the user can react to the message
+fn print_message(current_user: &str, id: Option<u64>, message: &Message) {
+    match message {
+        Message::Text{ from, content} => {
+            println!("|{}|[{}]{}: {}", current_user, from, id_suffix(id), content);
         }
-        Message::Image{ from, content} => {
-            println!("|{}|[{}]: Receiving image...", current_user, from);
-            save_img(content).await
+        Message::Direct { from, content, .. } => {
+            println!("|{}|[{} -> you]{}: {}", current_user, from, id_suffix(id), content);
         }
-        Message::Text{ from, content} => {
-            println!("|{}|[{}]: {}", current_user, from, content);
-            Ok(())
+        Message::Reactions { id, reactions } => {
+            let reactions = reactions.iter().map(|(emoji, count)| format!("{} {}", emoji, count)).collect::<Vec<_>>();
+            println!("|{}|Reactions to #{}: {}", current_user, id, if reactions.is_empty() { "none".to_string() } else { reactions.join("  ") });
+        }
+        Message::Presence { from, state, status } => {
+            match status {
+                Some(status) => println!("|{}|[{}]: ...is {} ({})", current_user, from, state, status),
+                None => println!("|{}|[{}]: ...is {}", current_user, from, state),
+            }
+        }
+        Message::Edit { id, from, content } => {
+            println!("|{}|[{}]: {} (edited #{})", current_user, from, content, id);
+        }
+        Message::Delete { id, from } => {
+            println!("|{}|[{}]: ...deleted message #{}", current_user, from, id);
+        }
+        Message::Typing { from } => {
+            println!("|{}|[{}]: ...is typing", current_user, from);
         }
-        Message::ClientHello { from } => {
+        Message::ClientHello { from, .. } => {
             println!("|{}|[{}]: ...connected", current_user, from);
-            Ok(())
         }
         Message::ClientQuit { from } => {
             println!("|{}|[{}]: ...disconnected", current_user, from);
-            Ok(())
         },
-        _ => {
-            println!("|{}|Unexpected message: {:?}", current_user, message);
-            Ok(())
-        }
-    };
-    if let Err(e) = message_result {
-        error!("{}", e);
-    }
-}
-
-async fn process_incomming_message_from_server(current_user: &str, message: &Result<Message, ReceiveMessageError>) -> bool {
-    use shared::ReceiveMessageError::*;
-
-    match message {
-        Ok(m) => handle_message(current_user, &m).await,
-        Err(GeneralStreamError(e)) => { 
-            error!("Server stream problems. Error: {}", e);
+        Message::FileOffer { from, name, size, .. } => {
+            println!("|{}|[{}]: Receiving {} ({} bytes)...", current_user, from, name, size);
         },
-        Err(DeserializationError(e)) => { 
-            error!("Server sent malformed message. Error: {}", e);
+        Message::RoomJoined { name } => {
+            println!("|{}|You are in room {}", current_user, name);
         },
-        Err(RemoteDisconnected(e)) => { 
-            error!("Server disconnected. Error: {}", e);
+        Message::RoomFailed { reason } => {
+            println!("|{}|{}", current_user, reason);
         },
-    }
-    !message.is_err()
-}
-
-async fn try_send_hello(stream_reader: &mut OwnedReadHalf, stream_writer: &mut OwnedWriteHalf, user: &str) -> Result<()> {
-
-    let msg = Message::ClientHello{ from: user.into() };
-
-    // note: now idea how to just call
-    //    msg.send_async(stream_writer).await?; 
-    // so that it's converted to Result<()>
-    // it complains: 
-    //    `dyn std::error::Error` cannot be shared between threads safely
-    //    the trait `Sync` is not implemented for `dyn std::error::Error` etc.
-    // somewhere used anyhow::from_boxed (https://github.com/dtolnay/anyhow/issues/83), but it's obviously not possible anymore (anyhow::error::from_boxed is private)
-    if let Err(e) = msg.send(stream_writer).await {
-         return Err(anyhow!("Problems when sending hello message to server: {}", e));
-    }
-    if let Message::ServerHello = Message::receive(stream_reader).await? {
-        info!("Connected as {}", user);
-        Ok(())
-    } else {
-        Err(anyhow!("Unexpected message from server"))
+        Message::RoomList { rooms } => {
+            println!("|{}|Rooms:", current_user);
+            for (name, members) in rooms {
+                println!("|{}|  {} ({} connected)", current_user, name, members);
+            }
+        },
+        Message::SearchResults { query, results } => {
+            println!("|{}|Found {} messages for '{}':", current_user, results.len(), query);
+            for hit in results {
+                let place = if hit.room.is_empty() { "private" } else { &hit.room };
+                println!("|{}|  #{} [{}] {}: {}", current_user, hit.id, place, hit.from, hit.text);
+            }
+        },
+        Message::ServerShutdown { reason } => {
+            match reason {
+                Some(reason) => println!("|{}|Server is shutting down: {}", current_user, reason),
+                None => println!("|{}|Server is shutting down", current_user),
+            }
+        },
+        _ => {
+            println!("|{}|Unexpected message: {:?}", current_user, message);
+        }
     }
 }
 
-#[allow(unreachable_code)]
 #[tokio::main]
 async fn main() -> Result<()> {
     shared::logging::init();
-    if chaos::enabled() {
-        warn!("Chaos monkey is enabled");
-    }
+    let faults = FaultInjector::from_env().map_err(|e| anyhow!(e))?;
 
     let args = ConnectionArgs::parse();
-    info!("Connecting to {}:{}", args.host, args.port);
-
-    let remote_addr = SocketAddr::new(args.host.parse()?, args.port);
-    let stream = TcpStream::connect(&remote_addr).await?;
-    let local_addr = stream.local_addr()?.to_string();
-
-    let user = if args.user.is_empty() {  local_addr.clone()} 
-                    else {args.user };
-    let (mut stream_reader, mut stream_writer) = stream.into_split();
-    info!("Connecting as {}, user {}", local_addr, user);
-    if let Err(e) = try_send_hello(&mut stream_reader, &mut stream_writer, &user).await {
-        info!("Server closed connection. {}", e);
-        return Ok(());
-    }
+    let config = ClientConfig {
+        credentials: credentials(&args)?,
+        host: args.host,
+        port: args.port,
+        user: args.user,
+        max_frame_size: args.max_frame_size,
+        codec: args.codec,
+        compression: !args.no_compression,
+        ca_cert: args.ca_cert,
+        server_name: args.server_name,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        faults: faults.clone(),
+        reconnect: (!args.no_reconnect).then(|| ReconnectPolicy { max_attempts: args.reconnect_attempts, ..Default::default() }),
+    };
+    let mut client = match ChatClient::connect(config).await {
+        Ok(client) => client,
+        Err(e) => {
+            info!("Server closed connection. {}", e);
+            return Ok(());
+        }
+    };
 
     let mut rx_stdin = async_stdin::recv_from_stdin(1);
     loop {
@@ -200,16 +238,21 @@ async fn main() -> Result<()> {
                 if command == ".quit" {
                     break;
                 }
-                if let Err(e) = process_stdin_command(&user, &command, &mut stream_writer).await {
+                if let Err(e) = process_stdin_command(&client, command).await {
                     error!("{}", e);
                 }
             },
-            message = Message::receive(&mut stream_reader) => {
-                if !process_incomming_message_from_server(&user, &message).await {
+            event = client.next_event() => {
+                let Some(event) = event else {
                     break;
-                }
-            }           
+                };
+                print_event(client.user(), event);
+            },
         )
     }
+    client.quit().await;
+    if let Some(faults) = faults {
+        info!("Faults injected: {}", faults.stats());
+    }
     Ok(())
-}
\ No newline at end of file
+}
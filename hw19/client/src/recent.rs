@@ -0,0 +1,49 @@
+use shared::Message;
+use std::collections::{HashMap, VecDeque};
+
+/// how many messages are remembered for quoting
+const CAPACITY: usize = 1000;
+
+/// short descriptions of recently seen stored messages, so that replies can quote them
+#[derive(Default)]
+pub struct RecentMessages {
+    messages: HashMap<u64, String>,
+    /// ids, the oldest first
+    order: VecDeque<u64>,
+}
+
+impl RecentMessages {
+    pub fn remember(&mut self, id: u64, description: String) {
+        if self.messages.insert(id, description).is_none() {
+            self.order.push_back(id);
+        }
+        if self.order.len() > CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remember_message(&mut self, id: u64, message: &Message) {
+        if let Some(description) = describe(message) {
+            self.remember(id, description);
+        }
+    }
+
+    /// the parent if it's known, otherwise just its id
+    pub fn quote(&self, id: u64) -> String {
+        self.messages.get(&id).cloned().unwrap_or_else(|| format!("#{}", id))
+    }
+}
+
+fn describe(message: &Message) -> Option<String> {
+    match message.unwrap_reply() {
+        Message::Text { from, content } => Some(format!("[{}]: {}", from, content)),
+        Message::Direct { from, content, .. } => Some(format!("[{}]: {}", from, content)),
+        Message::Image { from, .. } => Some(format!("[{}]: image", from)),
+        Message::File { from, name, .. } => Some(format!("[{}]: {}", from, name)),
+        Message::FileOffer { from, name, .. } => Some(format!("[{}]: {}", from, name)),
+        _ => None,
+    }
+}
+
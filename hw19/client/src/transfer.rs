@@ -0,0 +1,213 @@
+use shared::Message;
+use shared::transfer::{self, Checksum, TransferKind, CHUNK_SIZE};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{Result, Context, anyhow};
+use log::{debug, error, info};
+
+/// name of file from another client without any path, so that it can't be written outside of the target directory
+pub fn local_file_name(name: &str) -> Result<&str> {
+    Path::new(name).file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid file name '{}'", name))
+}
+
+struct IncomingTransfer {
+    from: String,
+    checksum: String,
+    /// chunks are written here; the file is renamed to `target` once it's verified
+    part: PathBuf,
+    target: PathBuf,
+}
+
+/// accepted upload whose chunks are being sent
+struct Upload {
+    transfer_id: u64,
+    name: String,
+    file: File,
+    /// the next chunk to send
+    index: u64,
+}
+
+/// state of chunked transfers (see `shared::transfer`) of one client
+#[derive(Default)]
+pub struct Transfers {
+    /// offered files waiting for `FileAccept`; checksum -> path
+    offered: HashMap<String, PathBuf>,
+    /// accepted uploads sent chunk by chunk, one after another (see `next_upload_message`)
+    uploads: VecDeque<Upload>,
+    /// completely sent uploads waiting for the server to verify them; transfer id -> file name
+    uploading: HashMap<u64, String>,
+    incoming: HashMap<u64, IncomingTransfer>,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// reads the file (without loading it into memory) and creates offer for the server; the file has to be
+    /// registered by `offered` before the offer is sent
+    ///
+    /// note: reading a big file takes a while, so it doesn't need the transfers and can run in its own task
+    ///
+    /// offering the same file again (e.g. after reconnect) resumes its upload
+    pub async fn offer(user_name: &str, path: &Path, kind: TransferKind) -> Result<Message> {
+        let name = path.file_name().context("Unable to get file name")?.to_str().context("Unable to get file name")?;
+
+        let mut file = File::open(path).await?;
+        let mut checksum = Checksum::new();
+        let mut size = 0u64;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            checksum.update(&buffer[..read]);
+            size += read as u64;
+        }
+        let checksum = checksum.finish();
+        Ok(Message::FileOffer { transfer_id: None, from: user_name.into(), name: name.into(), kind, size, checksum })
+    }
+
+    /// the offer of the file is going to be sent, the server may accept it
+    pub fn offered(&mut self, offer: &Message, path: PathBuf) {
+        if let Message::FileOffer { checksum, .. } = offer {
+            self.offered.insert(checksum.clone(), path);
+        }
+    }
+
+    /// starts upload of the offered file from `next_chunk`; the chunks are taken by `next_upload_message`
+    pub async fn upload(&mut self, transfer_id: u64, checksum: &str, next_chunk: u64) -> Result<()> {
+        let path = self.offered.remove(checksum).context("Server accepted file that was not offered")?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if next_chunk > 0 {
+            info!("Resuming upload of {} from chunk {}", name, next_chunk);
+        }
+        let file = File::open(&path).await?;
+        self.uploads.push_back(Upload { transfer_id, name, file, index: next_chunk });
+        Ok(())
+    }
+
+    pub fn is_uploading(&self) -> bool {
+        !self.uploads.is_empty()
+    }
+
+    /// reads the next chunk of the current upload, or its completion; the name of the file is returned with the completion
+    ///
+    /// cancellation safe - the chunk is read again, the upload moves on only when the message is returned
+    ///
+    /// note: file that can't be read is completed too, the server then fails the transfer (wrong checksum),
+    /// so the upload ends the same way as any other failed one
+    ///
+    /// panics when there is no upload, see `is_uploading`
+    pub async fn next_upload_message(&mut self) -> (Message, Option<String>) {
+        let upload = self.uploads.front_mut().expect("No upload in progress");
+        let transfer_id = upload.transfer_id;
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        let read = async {
+            upload.file.seek(SeekFrom::Start(transfer::chunk_offset(upload.index))).await?;
+            (&mut upload.file).take(CHUNK_SIZE as u64).read_to_end(&mut data).await
+        };
+        match read.await {
+            Ok(_) if !data.is_empty() => {
+                let index = upload.index;
+                upload.index += 1;
+                debug!("-> chunk {} of {}", index, upload.name);
+                return (Message::FileChunk { transfer_id, index, data }, None);
+            },
+            Ok(_) => {},
+            Err(e) => error!("Unable to read {}, upload ends: {}", upload.name, e),
+        }
+        let Upload { name, .. } = self.uploads.pop_front().expect("No upload in progress");
+        self.uploading.insert(transfer_id, name.clone());
+        (Message::FileComplete { transfer_id }, Some(name))
+    }
+
+    /// the server failed the upload before it was completely sent; returns name of the file
+    pub fn cancel_upload(&mut self, transfer_id: u64) -> Option<String> {
+        let position = self.uploads.iter().position(|upload| upload.transfer_id == transfer_id)?;
+        self.uploads.remove(position).map(|upload| upload.name)
+    }
+
+    /// unfinished uploads can't continue on another connection; they're resumed by offering the files again
+    pub fn connection_lost(&mut self) -> Vec<String> {
+        self.uploads.drain(..).map(|upload| upload.name).collect()
+    }
+
+    pub async fn receive_offer(&mut self, transfer_id: u64, from: &str, name: &str, kind: TransferKind, checksum: &str) -> Result<()> {
+        if self.incoming.contains_key(&transfer_id) {
+            debug!("Transfer {} is resumed", transfer_id);
+            return Ok(());
+        }
+        let name = local_file_name(name)?;
+        let target = match kind {
+            TransferKind::File => Path::new("files").join(name),
+            TransferKind::Image => Path::new("images").join(format!("{}.png", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis())),
+        };
+        let dir = target.parent().context("Unable to get directory")?;
+        if !dir.exists() {
+            tokio::fs::create_dir(dir).await?;
+        }
+        // the id makes the name unique; chunks are written at their offsets so the part may be reused when resuming
+        let part = dir.join(format!("{}.{}.part", name, transfer_id));
+        self.incoming.insert(transfer_id, IncomingTransfer { from: from.into(), checksum: checksum.into(), part, target });
+        Ok(())
+    }
+
+    pub async fn receive_chunk(&mut self, transfer_id: u64, index: u64, data: &[u8]) -> Result<()> {
+        let Some(transfer) = self.incoming.get(&transfer_id) else {
+            debug!("Ignoring chunk of unknown transfer {}", transfer_id);
+            return Ok(());
+        };
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&transfer.part).await?;
+        file.seek(SeekFrom::Start(transfer::chunk_offset(index))).await?;
+        file.write_all(data).await?;
+        Ok(())
+    }
+
+    /// verifies received content; returns who sent the file and where it's stored
+    ///
+    /// returns `None` if the completed transfer was our own upload
+    pub async fn receive_complete(&mut self, transfer_id: u64) -> Result<Option<(String, PathBuf)>> {
+        if let Some(name) = self.uploading.remove(&transfer_id) {
+            info!("Upload of {} finished", name);
+            return Ok(None);
+        }
+        let transfer = self.incoming.remove(&transfer_id).context("Completed unknown transfer")?;
+
+        let mut file = File::open(&transfer.part).await?;
+        let mut checksum = Checksum::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            checksum.update(&buffer[..read]);
+        }
+        if checksum.finish() != transfer.checksum {
+            tokio::fs::remove_file(&transfer.part).await?;
+            return Err(anyhow!("Checksum of file from {} doesn't match, file discarded", transfer.from));
+        }
+        tokio::fs::rename(&transfer.part, &transfer.target).await?;
+        Ok(Some((transfer.from, transfer.target)))
+    }
+
+    /// the server refused the transfer; returns name of the file if it was our upload
+    pub async fn receive_failed(&mut self, transfer_id: u64) -> Result<Option<String>> {
+        if let Some(name) = self.uploading.remove(&transfer_id) {
+            return Ok(Some(name));
+        }
+        if let Some(transfer) = self.incoming.remove(&transfer_id) {
+            if transfer.part.exists() {
+                tokio::fs::remove_file(&transfer.part).await?;
+            }
+        }
+        Ok(None)
+    }
+}
@@ -1,2 +1,2 @@
 set RUST_LOG=info
-cargo run -- -s 127.0.0.1 -p 8080
\ No newline at end of file
+cargo run -- -s 127.0.0.1 -p 8080 --allow-anonymous
\ No newline at end of file
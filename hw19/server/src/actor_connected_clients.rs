@@ -1,54 +1,302 @@
-use log::{error, info, debug};
-use shared::Message;
-use std::collections::HashMap;
-use tokio::net::tcp::OwnedWriteHalf;
+use log::{error, info, debug, warn};
+use shared::{Message, Capabilities, PresenceState, SearchHit, transfer, DEFAULT_ROOM};
+use std::collections::{HashMap, HashSet};
+use shared::framed::MessageWriter;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::{CancellationToken, DropGuard};
 use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
 use crate::actor_db;
 use actor_db::DbMessage;
 use crate::metrics;
+use crate::db::DIRECT_MESSAGES_ROOM;
+use crate::outbound::{Delivery, OutboundQueue, QueueError, Replay, SlowClientPolicy};
+use futures::stream::{self, StreamExt};
+
+pub struct ConnectedClient {
+    /// messages are written to the client by its own task
+    outbound: OutboundQueue,
+    /// stops the task reading from the client when the server disconnects it (i.e. when the client is dropped)
+    ///
+    /// note: the reader has usually ended already (that's why the client is removed), except when the client is evicted
+    reader: DropGuard,
+    /// features negotiated during handshake
+    capabilities: Capabilities,
+    /// chat messages of the client go to this room, it receives only messages from this room
+    room: String,
+    /// set by the client, only kept in memory
+    presence: PresenceState,
+    status: Option<String>,
+}
+
+impl ConnectedClient {
+    /// queues the message; returns false if it's not going to be sent
+    ///
+    /// client with full queue is added to `slow` when it should be disconnected
+    fn queue(&self, user_name: &str, msg: Arc<Message>, delivery: Option<Delivery>, policy: SlowClientPolicy, slow: &mut Vec<String>) -> bool {
+        queued(user_name, self.outbound.push(msg, delivery), policy, slow)
+    }
+
+    /// queues replay of missed messages like `queue` does with one message
+    ///
+    /// note: dropped replay is not lost, the delivery cursor stays before it
+    fn queue_replay(&self, user_name: &str, replay: Replay, policy: SlowClientPolicy, slow: &mut Vec<String>) -> bool {
+        queued(user_name, self.outbound.push_replay(replay), policy, slow)
+    }
+}
+
+/// applies the slow client policy to the result of queueing
+fn queued(user_name: &str, result: Result<(), QueueError>, policy: SlowClientPolicy, slow: &mut Vec<String>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(QueueError::Full) if policy == SlowClientPolicy::Drop => {
+            warn!("Queue of {} is full, message dropped", user_name);
+            metrics::outbound_dropped_up();
+            false
+        },
+        Err(QueueError::Full) => {
+            slow.push(user_name.to_string());
+            false
+        },
+        Err(QueueError::Closed) => {
+            debug!("Connection of {} is closed, message not sent", user_name);
+            false
+        },
+    }
+}
 
-#[derive(Debug)]
 pub struct ConnectedClients {
-    clients: HashMap<String, OwnedWriteHalf>,
+    clients: HashMap<String, ConnectedClient>,
+    /// names taken by clients still in handshake (logging in etc.), see `CheckUserCanConnect`
+    reserved: HashSet<String>,
+    /// running chunked transfers; transfer id -> (uploading user, room the upload goes to, offered size)
+    uploads: HashMap<u64, (String, String, u64)>,
+    /// capacity of outbound queue of every client
+    queue_size: usize,
+    policy: SlowClientPolicy,
+    /// written stored messages are reported to db (delivery cursors)
+    db: ActorRef<DbMessage>,
 }
 
 impl ConnectedClients {
-    pub fn add(&mut self, user_name: String, stream_writer: OwnedWriteHalf) {
+    pub fn add(&mut self, user_name: String, capabilities: Capabilities, stream_writer: MessageWriter, reader: CancellationToken) {
         debug!("New client: {:?}", user_name);
-        self.clients.insert(user_name, stream_writer);
+        debug!("Client {} uses {}", user_name, stream_writer.encoder().format);
+        let (db, user) = (self.db.clone(), user_name.clone());
+        let outbound = OutboundQueue::spawn(user_name.clone(), stream_writer, self.queue_size, move |Delivery { message_id, room }| {
+            if let Err(e) = db.cast(DbMessage::MarkWritten { message_id, user_name: user.clone(), room }) {
+                error!("Unable to mark message {} as sent to {}: {}", message_id, user, e);
+            }
+        });
+        self.reserved.remove(&user_name);
+        self.clients.insert(user_name, ConnectedClient { outbound, reader: reader.drop_guard(), capabilities, room: DEFAULT_ROOM.to_string(), presence: PresenceState::Online, status: None });
     }
 
-    pub fn new() -> Self {
-        Self { clients: HashMap::new() }
+    pub fn new(queue_size: usize, policy: SlowClientPolicy, db: ActorRef<DbMessage>) -> Self {
+        Self { clients: HashMap::new(), reserved: HashSet::new(), uploads: HashMap::new(), queue_size, policy, db }
     }
 
-    pub fn remove(&mut self, client_to_remove: &str) {
+    /// takes the name for a client in handshake; returns false if it's used by connected client or another handshake
+    ///
+    /// note: check and reservation is one step, two clients with the same name can't both pass
+    pub fn reserve(&mut self, user_name: &str) -> bool {
+        !self.clients.contains_key(user_name) && self.reserved.insert(user_name.to_string())
+    }
+
+    /// returns false if the client was not connected
+    pub fn remove(&mut self, client_to_remove: &str) -> bool {
         debug!("all clients: {:?}", self.clients.keys());
         debug!("client to remove: {:?}", client_to_remove);
-        match self.clients.remove(client_to_remove) {
-            Some(_) => (),
-            None => debug!("Client {} already removed.", client_to_remove),
-        }
+        let removed = match self.clients.remove(client_to_remove) {
+            Some(_) => true,
+            None => { debug!("Client {} already removed.", client_to_remove); false },
+        };
 
         if self.clients.is_empty() {
             info!("No clients connected.");
         }
+        removed
     }
 
-    pub async fn broadcast_message(&mut self, incomming_message: (Message, String)) {
+    /// disconnects clients that can't keep up with the messages; others are told they quit
+    ///
+    /// note: telling others may find more slow clients
+    fn evict(&mut self, mut slow: Vec<String>) {
+        while let Some(user_name) = slow.pop() {
+            if !self.remove(&user_name) {
+                continue;
+            }
+            warn!("Client {} is too slow, disconnecting", user_name);
+            metrics::slow_client_evictions_up();
+            metrics::users_down();
+            let msg = Arc::new(Message::ClientQuit { from: user_name });
+            for (client, connected) in self.clients.iter() {
+                connected.queue(client, msg.clone(), None, self.policy, &mut slow);
+            }
+        }
+    }
+
+    /// tells all clients the server is going down and disconnects them; returns names of the disconnected clients
+    ///
+    /// messages queued before the notice are still written, clients that don't take them in `timeout` are just disconnected
+    pub async fn shutdown(&mut self, reason: Option<String>, timeout: Duration) -> Vec<String> {
+        let notice = Arc::new(Message::ServerShutdown { reason });
+        let mut user_names = vec![];
+        let mut draining = vec![];
+        for (user_name, ConnectedClient { outbound, reader, capabilities, .. }) in self.clients.drain() {
+            // the reader would announce the client quit, but there is nobody to tell
+            drop(reader);
+            if capabilities.contains(Capabilities::SHUTDOWN_NOTICE) && outbound.push(notice.clone(), None).is_err() {
+                warn!("Unable to tell {} about shutdown", user_name);
+            }
+            metrics::users_down();
+            let name = user_name.clone();
+            draining.push(async move {
+                if !outbound.drain(timeout).await {
+                    warn!("Queue of {} was not written out in {:?}, disconnected", name, timeout);
+                }
+            });
+            user_names.push(user_name);
+        }
+        futures::future::join_all(draining).await;
+        self.uploads.clear();
+        user_names
+    }
+
+    /// sends the message to all other clients, or only to those in `room`
+    pub fn broadcast_message(&mut self, incomming_message: (Message, String), room: Option<&str>) {
         debug!("all clients : {:?}", self.clients.keys());
-        info!("message: {:?}", incomming_message);
+        match &incomming_message.0 {
+            // chunks are too big and too many to be logged
+            Message::FileChunk { transfer_id, index, .. } => debug!("chunk {} of transfer {}", index, transfer_id),
+            _ => info!("message: {:?}", incomming_message),
+        }
 
         let (msg, message_origin_client) = incomming_message;
 
-        for (client,write_stream) in self.clients.iter_mut() {
-            if *client != message_origin_client {
-                match msg.send(write_stream).await {
-                        Ok(_) => { info!("  ... sent to {:?}", client); },
-                        Err(e) => error!("Error sending message: {}", e),
-                }
+        let required = msg.required_capabilities();
+        let msg = Arc::new(msg);
+        let mut slow = vec![];
+        for (client, connected) in self.clients.iter() {
+            if *client == message_origin_client || room.is_some_and(|room| room != connected.room) {
+                continue;
+            }
+            if !connected.capabilities.contains(required) {
+                info!("  ... skipping {:?}, it doesn't support the message", client);
+                continue;
+            }
+            if connected.queue(client, msg.clone(), None, self.policy, &mut slow) {
+                debug!("  ... queued for {:?}", client);
+            }
+        }
+        self.evict(slow);
+    }
+
+    /// broadcasts chat message stored under `id` to the room; clients supporting it get the message wrapped in `Message::Stored`
+    ///
+    /// the message is marked as sent to a client once it's written to its socket
+    pub fn broadcast_stored_message(&mut self, id: u64, time: u64, message: Message, message_origin_client: &str, room: &str) {
+        debug!("all clients : {:?}", self.clients.keys());
+        info!("message #{} to {}: {:?}", id, room, message);
+
+        let mut slow = vec![];
+        for (client, connected) in self.clients.iter() {
+            if *client == message_origin_client || connected.room != room {
+                continue;
             }
+            let msg = stored_message_for(connected.capabilities, id, time, &message);
+            if !connected.capabilities.contains(msg.required_capabilities()) {
+                info!("  ... skipping {:?}, it doesn't support the message", client);
+                continue;
+            }
+            let delivery = Delivery { message_id: id, room: room.to_string() };
+            if connected.queue(client, Arc::new(msg), Some(delivery), self.policy, &mut slow) {
+                debug!("  ... queued for {:?}", client);
+            }
+        }
+        self.evict(slow);
+    }
+
+    /// sends message only to given client (e.g. reply to its request); nothing is sent if the client doesn't support the message
+    pub fn send_to(&mut self, user_name: &str, msg: &Message) {
+        let Some(connected) = self.clients.get(user_name) else {
+            debug!("Client {} not connected, message not sent", user_name);
+            return;
+        };
+        if !connected.capabilities.contains(msg.required_capabilities()) {
+            debug!("Client {} doesn't support the message, not sent", user_name);
+            return;
+        }
+        let mut slow = vec![];
+        connected.queue(user_name, Arc::new(msg.clone()), None, self.policy, &mut slow);
+        self.evict(slow);
+    }
+
+    /// sends stored private message to its recipient (wrapped in `Message::Stored` if supported);
+    /// if it's not written, it stays in db until the recipient connects
+    pub fn send_direct_message(&mut self, id: u64, time: u64, message: Message, recipient: &str) {
+        let Some(connected) = self.clients.get(recipient) else {
+            debug!("Client {} not connected, direct message {} waits", recipient, id);
+            return;
+        };
+        if !connected.capabilities.contains(message.required_capabilities()) {
+            debug!("Client {} doesn't support direct messages, message {} waits", recipient, id);
+            return;
+        }
+        let msg = stored_message_for(connected.capabilities, id, time, &message);
+        let delivery = Delivery { message_id: id, room: DIRECT_MESSAGES_ROOM.to_string() };
+        let mut slow = vec![];
+        connected.queue(recipient, Arc::new(msg), Some(delivery), self.policy, &mut slow);
+        self.evict(slow);
+    }
+
+    /// sends messages stored while the client was offline (or in another room); they are marked as sent once they are written
+    ///
+    /// note: the client takes them at its own pace, nobody waits for it
+    pub fn replay(&mut self, user_name: &str, missing: Vec<actor_db::MissingMessage>) {
+        let Some(connected) = self.clients.get(user_name) else {
+            return;
+        };
+        let capabilities = connected.capabilities;
+        let missing: Vec<_> = missing.into_iter().filter(|m| capabilities.contains(m.message.unwrap_reply().required_capabilities())).collect();
+        if missing.is_empty() {
+            return;
         }
+        debug!("Replaying {} messages to {}", missing.len(), user_name);
+        let mut slow = vec![];
+        connected.queue_replay(user_name, replay(self.db.clone(), capabilities, missing), self.policy, &mut slow);
+        self.evict(slow);
+    }
+
+    /// room the upload goes to and the offered size, `None` if the user is not uploading the transfer
+    fn upload(&self, user_name: &str, transfer_id: u64) -> Option<(String, u64)> {
+        match self.uploads.get(&transfer_id) {
+            Some((uploader, room, size)) if uploader == user_name => Some((room.clone(), *size)),
+            _ => None,
+        }
+    }
+
+    /// the uploader and everybody who got the offer learn that the transfer won't be completed
+    fn fail_upload(&mut self, user_name: String, room: &str, transfer_id: u64, reason: String) {
+        self.uploads.remove(&transfer_id);
+        let failed = Message::FileFailed { transfer_id, reason };
+        self.send_to(&user_name, &failed);
+        self.broadcast_message((failed, user_name), Some(room));
+    }
+
+    pub fn room_of(&self, user_name: &str) -> String {
+        self.clients.get(user_name).map(|c| c.room.clone()).unwrap_or_else(|| DEFAULT_ROOM.to_string())
+    }
+
+    fn room_members(&self, room: &str) -> u32 {
+        self.clients.values().filter(|c| c.room == room).count() as u32
+    }
+
+    /// presence of all connected clients
+    pub fn presence(&self) -> HashMap<String, (PresenceState, Option<String>)> {
+        self.clients.iter()
+            .map(|(user_name, c)| (user_name.clone(), (c.presence, c.status.clone())))
+            .collect()
     }
 
     pub fn get_clients(&self) -> Vec<String> {
@@ -59,8 +307,116 @@ impl ConnectedClients {
 }
 
 
+/// room names are shown in client prompts, so they are kept short and without spaces
+fn check_room_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 50 || name.contains(char::is_whitespace) {
+        return Err(format!("Invalid room name '{}', use up to 50 characters without spaces", name));
+    }
+    Ok(())
+}
+
+/// stored message as the client gets it - with id if supported; clients without threads get replies as plain messages
+fn stored_message_for(capabilities: Capabilities, id: u64, time: u64, message: &Message) -> Message {
+    let message = if capabilities.contains(Capabilities::THREADS) { message } else { message.unwrap_reply() };
+    if capabilities.contains(Capabilities::MESSAGE_IDS) {
+        Message::Stored { id, time, message: Box::new(message.clone()) }
+    } else {
+        message.clone()
+    }
+}
+
+/// missed messages as they are written to the client; see `ConnectedClients::replay`
+fn replay(db: ActorRef<DbMessage>, capabilities: Capabilities, missing: Vec<actor_db::MissingMessage>) -> Replay {
+    stream::iter(missing)
+        .flat_map(move |actor_db::MissingMessage { id, time, room, message }| {
+            let msg = Arc::new(stored_message_for(capabilities, id, time, &message));
+            let delivery = Delivery { message_id: id, room };
+            match message {
+                Message::FileOffer { transfer_id: Some(transfer_id), size, .. } => replay_transfer(db.clone(), msg, transfer_id, size, delivery),
+                _ => stream::iter(Some((msg, Some(delivery)))).boxed(),
+            }
+        })
+        .boxed()
+}
+
+/// replays stored transfer (`offer` with id, possibly wrapped in `Message::Stored`) - the offer, all chunks and completion
+///
+/// chunks are read from db one by one as the client takes them; the transfer is delivered only with the completion,
+/// so it stops without it when a chunk is missing
+fn replay_transfer(db: ActorRef<DbMessage>, offer: Arc<Message>, transfer_id: u64, size: u64, delivery: Delivery) -> Replay {
+    let count = transfer::chunk_count(size);
+    let chunks = stream::unfold(Some(0), move |index: Option<u64>| {
+        let (db, delivery) = (db.clone(), delivery.clone());
+        async move {
+            let index = index?;
+            if index == count {
+                return Some(((Arc::new(Message::FileComplete { transfer_id }), Some(delivery)), None));
+            }
+            match ractor::call!(db, DbMessage::GetFileChunk, transfer_id, index) {
+                Ok(Some(data)) => Some(((Arc::new(Message::FileChunk { transfer_id, index, data }), None), Some(index + 1))),
+                Ok(None) => {
+                    error!("Chunk {} of transfer {} is missing", index, transfer_id);
+                    None
+                },
+                Err(e) => {
+                    error!("Unable to get chunk {} of transfer {}: {}", index, transfer_id, e);
+                    None
+                },
+            }
+        }
+    });
+    stream::iter(Some((offer, None))).chain(chunks).boxed()
+}
+
+/// message from the client that is just passed on to the other clients; returned back as error if clients can't send it
+///
+/// note: everything else is either handled by the server or sent only by the server (`Stored`, `Accepted`, ...),
+/// a client could pretend to be the server otherwise
+///
+/// note: `from` is always the sender, nobody can speak for others
+fn relayed(user_name: &str, message: Message) -> Result<Message, Message> {
+    match message {
+        Message::ClientHello { version, capabilities, .. } => Ok(Message::ClientHello { version, from: user_name.to_string(), capabilities }),
+        Message::ClientQuit { .. } => Ok(Message::ClientQuit { from: user_name.to_string() }),
+        message => Err(message),
+    }
+}
+
+/// found message as the client gets it - only the searchable text, no content of files
+fn search_hit(found: actor_db::StoredMessage) -> SearchHit {
+    let text = match found.message.unwrap_reply() {
+        Message::Text { content, .. } | Message::Direct { content, .. } => content.clone(),
+        Message::File { name, .. } | Message::FileOffer { name, .. } => name.clone(),
+        _ => String::new(),
+    };
+    let time = found.time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    SearchHit { id: found.id, time, from: found.user_name, room: found.room, text }
+}
+
+/// file names end up on disks of other clients, so they can't contain a path
+fn check_file_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(format!("Invalid file name '{}'", name));
+    }
+    Ok(())
+}
+
+/// reaction is a single emoji (possibly composed of several chars), not a text
+fn check_emoji(emoji: &str) -> Result<(), String> {
+    if emoji.is_empty() || emoji.chars().count() > 8 || emoji.chars().any(|c| c.is_whitespace() || c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid reaction {}", emoji));
+    }
+    Ok(())
+}
+
+/// how long the server waits for clients to take the rest of their messages when shutting down
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct ConnectedClientsActor {
-    pub db: ActorRef<crate::actor_db::DbMessage>
+    pub db: ActorRef<crate::actor_db::DbMessage>,
+    /// capacity of outbound queue of every client
+    pub queue_size: usize,
+    pub slow_client_policy: SlowClientPolicy,
 }
 
 pub enum ConnectedClientsActorMessage
@@ -71,9 +427,269 @@ pub enum ConnectedClientsActorMessage
     },
     NewClient {
         user_name: String,
-        stream_writer: OwnedWriteHalf
+        capabilities: Capabilities,
+        /// replay starts after this message instead of the last time the user was online
+        resume_after: Option<u64>,
+        stream_writer: MessageWriter,
+        /// cancelled when the server disconnects the client
+        reader: CancellationToken,
     },
+    /// reserves the name for the client in handshake if nobody uses it; `NewClient` takes the reservation over,
+    /// failed handshake gives it back with `ReleaseUserName` (see `NameReservation`)
     CheckUserCanConnect(String, RpcReplyPort<bool>),    // todo: struct?
+    ReleaseUserName(String),
+    /// presence of connected users (for web)
+    GetPresence(RpcReplyPort<HashMap<String, (PresenceState, Option<String>)>>),
+    /// server is stopping (optional reason for clients); replies once all clients are disconnected
+    Shutdown(Option<String>, RpcReplyPort<()>),
+}
+
+/// name reserved by `CheckUserCanConnect`; it's released when dropped, unless the client got connected
+///
+/// the handshake may fail anywhere (or time out), so the guard is simpler than releasing on every error
+pub struct NameReservation {
+    actor: ActorRef<ConnectedClientsActorMessage>,
+    user_name: Option<String>,
+}
+
+impl NameReservation {
+    pub fn new(actor: ActorRef<ConnectedClientsActorMessage>, user_name: String) -> Self {
+        Self { actor, user_name: Some(user_name) }
+    }
+
+    /// the name is taken over by `NewClient`, which has to be sent right after
+    pub fn connected(mut self) {
+        self.user_name = None;
+    }
+}
+
+impl Drop for NameReservation {
+    fn drop(&mut self) {
+        if let Some(user_name) = self.user_name.take() {
+            if let Err(e) = self.actor.cast(ConnectedClientsActorMessage::ReleaseUserName(user_name)) {
+                debug!("Unable to release user name: {}", e);
+            }
+        }
+    }
+}
+
+impl ConnectedClientsActor {
+    async fn handle_transfer_message(&self, user_name: String, message: Message, clients: &mut ConnectedClients) {
+        match message {
+            Message::FileOffer { transfer_id: None, name, kind, size, checksum, .. } => {
+                if let Err(reason) = check_file_name(&name) {
+                    error!("Client {} offered file: {}", user_name, reason);
+                    return;
+                }
+                let transfer = actor_db::NewTransfer { user_name: user_name.clone(), name: name.clone(), kind, size, checksum: checksum.clone() };
+                let Ok(Some((transfer_id, next_chunk))) = ractor::call!(self.db, DbMessage::StartFileTransfer, transfer) else {
+                    error!("Unable to start transfer of {} from {}", name, user_name);
+                    return;
+                };
+                info!("Transfer {} of {} from {} starts at chunk {}", transfer_id, name, user_name, next_chunk);
+                let room = clients.room_of(&user_name);
+                clients.uploads.insert(transfer_id, (user_name.clone(), room.clone(), size));
+                clients.send_to(&user_name, &Message::FileAccept { transfer_id, checksum: checksum.clone(), next_chunk });
+
+                let offer = Message::FileOffer { transfer_id: Some(transfer_id), from: user_name.clone(), name, kind, size, checksum };
+                clients.broadcast_message((offer, user_name), Some(&room));
+            },
+            Message::FileChunk { transfer_id, index, data } => {
+                let Some((room, size)) = clients.upload(&user_name, transfer_id) else {
+                    error!("Client {} sent chunk of unknown transfer {}", user_name, transfer_id);
+                    return;
+                };
+                // note: the chunk would be stored and forwarded at its offset, so it has to fit into the offered file
+                if transfer::chunk_len(size, index) != Some(data.len()) {
+                    error!("Client {} sent chunk {} of {} bytes, it doesn't fit into transfer {} of {} bytes", user_name, index, data.len(), transfer_id, size);
+                    let reason = format!("Chunk {} doesn't match the offered size", index);
+                    clients.fail_upload(user_name, &room, transfer_id, reason);
+                    return;
+                }
+                let chunk = Message::FileChunk { transfer_id, index, data };
+                clients.broadcast_message((chunk.clone(), user_name), Some(&room));
+                let Message::FileChunk { data, .. } = chunk else { unreachable!() };
+                self.db.cast(DbMessage::StoreFileChunk { transfer_id, index, data }).expect("Save to db failed.");
+            },
+            Message::FileComplete { transfer_id } => {
+                let Some((room, _)) = clients.upload(&user_name, transfer_id) else {
+                    error!("Client {} completed unknown transfer {}", user_name, transfer_id);
+                    return;
+                };
+                clients.uploads.remove(&transfer_id);
+                let result = ractor::call!(self.db, DbMessage::CompleteFileTransfer, user_name.clone(), room.clone(), transfer_id)
+                    .unwrap_or_else(|e| Err(e.to_string()));
+                match result {
+                    Ok((id, time)) => {
+                        metrics::messages_up();
+                        clients.send_to(&user_name, &message);
+                        clients.send_to(&user_name, &Message::Accepted { id, time });
+                        // the transfer became stored message only now
+                        clients.broadcast_stored_message(id, time, message, &user_name, &room);
+                    },
+                    Err(reason) => clients.fail_upload(user_name, &room, transfer_id, reason),
+                };
+
+                self.db.cast(DbMessage::UpdateLastSeen { user_names: clients.get_clients() }).expect("Unable to update users's last presence.")
+            },
+            _ => error!("Unexpected transfer message from {}: {:?}", user_name, message),
+        }
+    }
+
+    /// stores the message and broadcasts it; the sender gets id of the message (or rejection)
+    async fn handle_chat_message(&self, user_name: String, message: Message, clients: &mut ConnectedClients) {
+        let room = clients.room_of(&user_name);
+        if let Message::File { name, .. } = message.unwrap_reply() {
+            if let Err(reason) = check_file_name(name) {
+                clients.send_to(&user_name, &Message::Rejected { reason });
+                return;
+            }
+        }
+        if let Message::Reply { parent, message: reply } = &message {
+            let valid = match reply.as_ref() {
+                Message::Text { .. } | Message::Image { .. } | Message::File { .. } => 
+                    ractor::call!(self.db, DbMessage::CheckParent, *parent, room.clone()).unwrap_or_else(|e| Err(e.to_string())),
+                _ => Err("Only text, image and file messages can be replies".into()),
+            };
+            if let Err(reason) = valid {
+                clients.send_to(&user_name, &Message::Rejected { reason });
+                return;
+            }
+        }
+        let stored = ractor::call!(self.db, DbMessage::StoreChatMessage, user_name.clone(), room.clone(), message.clone())
+            .unwrap_or_else(|e| { error!("Save to db failed: {}", e); None });
+        let Some((id, time)) = stored else {
+            clients.send_to(&user_name, &Message::Rejected { reason: "Unable to store message".into() });
+            return;
+        };
+        metrics::messages_up();
+        clients.send_to(&user_name, &Message::Accepted { id, time });
+
+        clients.broadcast_stored_message(id, time, message, &user_name, &room);
+    }
+
+    /// stores private message and sends it to the recipient if connected, otherwise it waits in db
+    async fn handle_direct_message(&self, user_name: String, to: String, content: String, clients: &mut ConnectedClients) {
+        let exists = ractor::call!(self.db, DbMessage::UserExists, to.clone()).unwrap_or(false);
+        if !exists {
+            clients.send_to(&user_name, &Message::Rejected { reason: format!("User {} doesn't exist", to) });
+            return;
+        }
+        // note: sender is taken from the connection, not from the message
+        let message = Message::Direct { from: user_name.clone(), to: to.clone(), content };
+        let stored = ractor::call!(self.db, DbMessage::StoreDirectMessage, user_name.clone(), to.clone(), message.clone())
+            .unwrap_or_else(|e| { error!("Save to db failed: {}", e); None });
+        let Some((id, time)) = stored else {
+            clients.send_to(&user_name, &Message::Rejected { reason: "Unable to store message".into() });
+            return;
+        };
+        metrics::messages_up();
+        clients.send_to(&user_name, &Message::Accepted { id, time });
+
+        clients.send_direct_message(id, time, message, &to);
+    }
+
+    /// edit/delete of stored message by its author; the change goes to the room of the message (or to the recipient of direct message)
+    async fn handle_change_message(&self, user_name: String, message: Message, clients: &mut ConnectedClients) {
+        let (id, result, change) = match message {
+            Message::Edit { id, content, .. } => (
+                id,
+                ractor::call!(self.db, DbMessage::EditMessage, user_name.clone(), id, content.clone()),
+                Message::Edit { id, from: user_name.clone(), content },
+            ),
+            Message::Delete { id, .. } => (
+                id,
+                ractor::call!(self.db, DbMessage::DeleteMessage, user_name.clone(), id),
+                Message::Delete { id, from: user_name.clone() },
+            ),
+            _ => return,
+        };
+        match result.unwrap_or_else(|e| Err(e.to_string())) {
+            Err(reason) => clients.send_to(&user_name, &Message::Rejected { reason }),
+            Ok(changed) => {
+                clients.send_to(&user_name, &Message::Accepted { id, time: changed.time });
+                match changed.recipient {
+                    Some(recipient) => clients.send_to(&recipient, &change),
+                    None => clients.broadcast_message((change, user_name), Some(&changed.room)),
+                }
+            }
+        }
+    }
+
+    /// toggles reaction of the user; current counts go to everybody who can see the message (including the user)
+    async fn handle_reaction(&self, user_name: String, id: u64, emoji: String, clients: &mut ConnectedClients) {
+        let result = match check_emoji(&emoji) {
+            Ok(()) => ractor::call!(self.db, DbMessage::ToggleReaction, user_name.clone(), id, emoji).unwrap_or_else(|e| Err(e.to_string())),
+            Err(reason) => Err(reason),
+        };
+        let (changed, reactions) = match result {
+            Ok(res) => res,
+            Err(reason) => {
+                clients.send_to(&user_name, &Message::Rejected { reason });
+                return;
+            }
+        };
+        clients.send_to(&user_name, &Message::Accepted { id, time: changed.time });
+
+        let reactions = Message::Reactions { id, reactions };
+        match changed.recipient {
+            Some(recipient) => {
+                clients.send_to(&changed.author, &reactions);
+                clients.send_to(&recipient, &reactions);
+            },
+            None => {
+                clients.send_to(&user_name, &reactions);
+                clients.broadcast_message((reactions, user_name), Some(&changed.room));
+            }
+        }
+    }
+
+    /// create/join/leave/list rooms; after joining the client gets messages of the room it hasn't got yet
+    async fn handle_room_message(&self, user_name: String, message: Message, clients: &mut ConnectedClients) {
+        let target = match message {
+            Message::ListRooms => {
+                let rooms = ractor::call!(self.db, DbMessage::GetRooms).unwrap_or_else(|e| { error!("Unable to get rooms: {}", e); vec![] });
+                let rooms = rooms.into_iter().map(|room| { let members = clients.room_members(&room); (room, members) }).collect();
+                clients.send_to(&user_name, &Message::RoomList { rooms });
+                return;
+            },
+            Message::CreateRoom { name } => {
+                let created = match check_room_name(&name) {
+                    Ok(()) => ractor::call!(self.db, DbMessage::CreateRoom, name.clone(), user_name.clone()).unwrap_or_else(|e| Err(e.to_string())),
+                    Err(reason) => Err(reason),
+                };
+                if let Err(reason) = created {
+                    clients.send_to(&user_name, &Message::RoomFailed { reason });
+                    return;
+                }
+                info!("Room {} created by {}", name, user_name);
+                name
+            },
+            Message::JoinRoom { name } => {
+                let rooms = ractor::call!(self.db, DbMessage::GetRooms).unwrap_or_default();
+                if !rooms.contains(&name) {
+                    clients.send_to(&user_name, &Message::RoomFailed { reason: format!("Room {} doesn't exist", name) });
+                    return;
+                }
+                name
+            },
+            Message::LeaveRoom => DEFAULT_ROOM.to_string(),
+            _ => {
+                error!("Unexpected room message from {}: {:?}", user_name, message);
+                return;
+            },
+        };
+
+        let Some(connected) = clients.clients.get_mut(&user_name) else {
+            return;
+        };
+        info!("Client {} moves from {} to {}", user_name, connected.room, target);
+        connected.room = target.clone();
+        clients.send_to(&user_name, &Message::RoomJoined { name: target.clone() });
+
+        let history = ractor::call!(self.db, DbMessage::GetUnsentMessages, user_name.clone(), target).unwrap_or_default();
+        clients.replay(&user_name, history);
+    }
 }
 
 #[async_trait]
@@ -83,48 +699,162 @@ impl Actor for ConnectedClientsActor {
     type Arguments = ();
 
     async fn pre_start(&self, _myself: ActorRef<Self::Msg>, _: ()) -> Result<Self::State, ActorProcessingErr> {
-        let clients = ConnectedClients::new();
+        let clients = ConnectedClients::new(self.queue_size, self.slow_client_policy, self.db.clone());
         Ok(clients)
     }
 
     async fn handle(&self, _myself: ActorRef<Self::Msg>, message: Self::Msg, clients: &mut Self::State) -> Result<(), ActorProcessingErr> {
         match message {
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: message @ (Message::FileOffer { .. } | Message::FileChunk { .. } | Message::FileComplete { .. }) } => {
+                self.handle_transfer_message(user_name, message, clients).await;
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: message @ (Message::CreateRoom { .. } | Message::JoinRoom { .. } | Message::LeaveRoom | Message::ListRooms) } => {
+                self.handle_room_message(user_name, message, clients).await;
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Received { id } } => {
+                self.db.cast(DbMessage::MarkReceived { message_id: id, user_name }).expect("Unable to mark message as received.");
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Direct { to, content, .. } } => {
+                self.handle_direct_message(user_name, to, content, clients).await;
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Presence { state, status, .. } } => {
+                if let Some(connected) = clients.clients.get_mut(&user_name) {
+                    connected.presence = state;
+                    connected.status = status.clone();
+                }
+                let presence = Message::Presence { from: user_name.clone(), state, status };
+                clients.broadcast_message((presence, user_name), None);
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Typing { .. } } => {
+                let room = clients.room_of(&user_name);
+                clients.broadcast_message((Message::Typing { from: user_name.clone() }, user_name), Some(&room));
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: message @ (Message::Edit { .. } | Message::Delete { .. }) } => {
+                self.handle_change_message(user_name, message, clients).await;
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::React { id, emoji } } => {
+                self.handle_reaction(user_name, id, emoji, clients).await;
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Search { query } } => {
+                let found = ractor::call!(self.db, DbMessage::Search, query.clone(), Some(user_name.clone()))
+                    .unwrap_or_else(|e| { error!("Unable to search messages: {}", e); vec![] });
+                let results = found.into_iter().map(search_hit).collect();
+                clients.send_to(&user_name, &Message::SearchResults { query, results });
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::ServerShutdown { reason } } => {
+                // note: only the server tells clients it's stopping (see `ConnectedClients::shutdown`), others would show shutdown that never happened
+                warn!("Client {} pretends the server is shutting down ({:?}), ignoring it", user_name, reason);
+            },
+            ConnectedClientsActorMessage::IncommingChatMessage { user_name, message: Message::Ping } => {
+                clients.send_to(&user_name, &Message::Pong);
+            },
             ConnectedClientsActorMessage::IncommingChatMessage { user_name, message } => {
                 debug!("Message from channel {:?}: {:?}", user_name, message);
                     
                 if matches!(message, Message::ClientQuit{from:_}) {
-                    clients.remove(&user_name);
+                    // dropping the writer closes the connection (important when the client is just silent)
+                    if !clients.remove(&user_name) {
+                        // already cleaned up, nothing to announce
+                        return Ok(());
+                    }
                     metrics::users_down();
                 } 
 
                 match message {
                     Message::Text{ .. } | 
                     Message::Image { .. } | 
-                    Message::File { .. } => { 
-                        self.db.cast(DbMessage::StoreChatMessage { user_name: user_name.clone(), message: message.clone() }).expect("Save to db failed.");  //db::store_message(&user_name, &message).await,
-                        metrics::messages_up();
+                    Message::File { .. } |
+                    Message::Reply { .. } => self.handle_chat_message(user_name, message, clients).await,
+                    message => match relayed(&user_name, message) {
+                        Ok(message) => clients.broadcast_message((message, user_name), None),
+                        Err(message) => {
+                            error!("Client {} sent message it must not send, dropping it: {:?}", user_name, message);
+                            return Ok(());
+                        },
                     },
-                    _ => {}
                 };
 
-                clients.broadcast_message((message, user_name)).await;
-
                 self.db.cast(DbMessage::UpdateLastSeen { user_names: clients.get_clients() }).expect("Unable to update users's last presence.")
             },
-            ConnectedClientsActorMessage::NewClient { user_name, mut stream_writer } => {
-                let missing_messages = ractor::call!(self.db, DbMessage::GetMissingChatMessageSinceLastSeen, user_name.clone()).expect("Unable to get missing messages.");
-                for msg in missing_messages.iter() {
-                    msg.send(&mut stream_writer).await.unwrap();
+            ConnectedClientsActorMessage::NewClient { user_name, capabilities, resume_after, stream_writer, reader } => {
+                let mut missing_messages = match resume_after {
+                    // note: includes private messages too, even the ones written but lost with the previous connection
+                    Some(last_id) => ractor::call!(self.db, DbMessage::GetMessagesAfter, user_name.clone(), DEFAULT_ROOM.to_string(), last_id).expect("Unable to get missing messages."),
+                    None => ractor::call!(self.db, DbMessage::GetUnsentMessages, user_name.clone(), DEFAULT_ROOM.to_string()).expect("Unable to get missing messages."),
+                };
+                // private messages sent while the user was offline
+                missing_messages.extend(ractor::call!(self.db, DbMessage::GetUnsentMessages, user_name.clone(), DIRECT_MESSAGES_ROOM.to_string()).expect("Unable to get direct messages."));
+                missing_messages.sort_by_key(|m| m.id);
+                missing_messages.dedup_by_key(|m| m.id);
+                clients.add(user_name.clone(), capabilities, stream_writer, reader);
+                clients.replay(&user_name, missing_messages);
+                // the new client doesn't know who is away etc.
+                for (other, (state, status)) in clients.presence() {
+                    if other != user_name && (state != PresenceState::Online || status.is_some()) {
+                        clients.send_to(&user_name, &Message::Presence { from: other, state, status });
+                    }
+                }
+            },
+            ConnectedClientsActorMessage::Shutdown(reason, reply) => {
+                info!("Disconnecting {} clients", clients.clients.len());
+                let user_names = clients.shutdown(reason, SHUTDOWN_DRAIN_TIMEOUT).await;
+                // they were all online until now
+                self.db.cast(DbMessage::UpdateLastSeen { user_names }).expect("Unable to update users's last presence.");
+                if reply.send(()).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            ConnectedClientsActorMessage::GetPresence(reply) => {
+                if reply.send(clients.presence()).is_err() {
+                    error!("Error sending reply");
                 }
-                clients.add(user_name, stream_writer);
             },
             ConnectedClientsActorMessage::CheckUserCanConnect(user_name, reply ) => {
-                let already_connected = clients.clients.contains_key(&user_name);
-                if reply.send(!already_connected).is_err() {
+                if reply.send(clients.reserve(&user_name)).is_err() {
                     error!("Error sending reply");
+                    clients.reserved.remove(&user_name);
                 }
             },
+            ConnectedClientsActorMessage::ReleaseUserName(user_name) => {
+                debug!("Handshake of {} failed, name released", user_name);
+                clients.reserved.remove(&user_name);
+            },
         }
         Ok(())
     }    
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_joining_and_leaving_is_relayed_as_the_sender() {
+        let hello = Message::ClientHello { version: 1, from: "bob".into(), capabilities: Capabilities::all() };
+        assert_eq!(relayed("hugo", hello), Ok(Message::ClientHello { version: 1, from: "hugo".into(), capabilities: Capabilities::all() }));
+        assert_eq!(relayed("hugo", Message::ClientQuit { from: "bob".into() }), Ok(Message::ClientQuit { from: "hugo".into() }));
+    }
+
+    #[test]
+    fn test_server_messages_from_client_are_not_relayed() {
+        let text = Message::Text { from: "hugo".into(), content: "hello".into() };
+        let forged = vec![
+            Message::Stored { id: u64::MAX, time: 0, message: Box::new(text) },
+            Message::Accepted { id: 1, time: 0 },
+            Message::Rejected { reason: "no".into() },
+            Message::Reactions { id: 1, reactions: vec![] },
+            Message::RoomList { rooms: vec![] },
+            Message::RoomJoined { name: "general".into() },
+            Message::SearchResults { query: "x".into(), results: vec![] },
+            Message::FileAccept { transfer_id: 1, checksum: "x".into(), next_chunk: 0 },
+            Message::FileFailed { transfer_id: 1, reason: "no".into() },
+            Message::ServerHello { version: 1, capabilities: Capabilities::all() },
+            Message::AuthOk,
+            Message::Pong,
+            Message::ServerShutdown { reason: Some("fake".into()) },
+        ];
+        for message in forged {
+            assert_eq!(relayed("hugo", message.clone()), Err(message));
+        }
+    }
+}
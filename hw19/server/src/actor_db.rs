@@ -1,7 +1,11 @@
 use crate::db;
-use shared::Message;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use shared::{Message, DEFAULT_ROOM};
+use shared::transfer::TransferKind;
 
 use ractor::{async_trait, Actor, ActorProcessingErr, ActorRef, RpcReplyPort};
+use std::future::Future;
 
 pub struct DbAccessActor;
 
@@ -11,65 +15,273 @@ pub struct UserData {
 }
 
 pub struct StoredMessage {
+    pub id: u64,
     pub user_name: String,
     pub time: std::time::SystemTime,
-    pub message: Message
+    pub room: String,
+    pub message: Message,
+    /// users that confirmed receiving the message
+    pub received_by: Vec<String>,
+    pub edited: bool,
+    /// tombstone, the content shouldn't be shown
+    pub deleted: bool,
+    pub reactions: db::ReactionCounts,
+    /// the message replies to this one
+    pub parent: Option<u64>,
+}
+
+impl From<db::MessageRecord> for StoredMessage {
+    fn from(r: db::MessageRecord) -> Self {
+        StoredMessage { id: r.id, time: r.time, user_name: r.author, room: r.room, message: r.message, received_by: r.received_by, edited: r.edited, deleted: r.deleted, reactions: r.reactions, parent: r.parent }
+    }
+}
+
+/// message stored while the user was offline (or in another room)
+pub struct MissingMessage {
+    pub id: u64,
+    /// ms since unix epoch
+    pub time: u64,
+    /// `db::DIRECT_MESSAGES_ROOM` for private messages
+    pub room: String,
+    pub message: Message,
+}
+
+/// file offered by client; see `shared::transfer`
+pub struct NewTransfer {
+    pub user_name: String,
+    pub name: String,
+    pub kind: TransferKind,
+    pub size: u64,
+    pub checksum: String,
 }
 
 pub enum DbMessage {
-    StoreChatMessage{ user_name: String, message: Message },
+    /// user, room; replies with id and time assigned to the message
+    StoreChatMessage(String, String, Message, RpcReplyPort<Option<(u64, u64)>>),
+    StoreFileChunk { transfer_id: u64, index: u64, data: Vec<u8> },
+    /// the message was written to the socket of the user; moves the delivery cursor of the user in the room
+    MarkWritten { message_id: u64, user_name: String, room: String },
+    /// the user confirmed receiving the message
+    MarkReceived { message_id: u64, user_name: String },
     UpdateLastSeen{ user_names: Vec<String> },
-    GetMissingChatMessageSinceLastSeen(String, RpcReplyPort<Vec<Message>>),
+    /// user, room (`db::DIRECT_MESSAGES_ROOM` for private messages); messages after the delivery cursor of the user
+    ///
+    /// user without cursor gets nothing from the default room (new user), all private messages and the last few messages of other rooms
+    GetUnsentMessages(String, String, RpcReplyPort<Vec<MissingMessage>>),
+    /// user, room, id of the last message the user received; messages of the room and private messages for the user stored after it
+    GetMessagesAfter(String, String, u64, RpcReplyPort<Vec<MissingMessage>>),
     GetAllUsersLastSeen(RpcReplyPort<Vec<UserData>>),
-    ListAllMessages(Option<String>, RpcReplyPort<Vec<StoredMessage>>),
+    /// optional user and room filter
+    ListAllMessages(Option<String>, Option<String>, RpcReplyPort<Vec<StoredMessage>>),
     ForgetUser { user_name: String},
+    /// replies with transfer id and the first chunk that is not stored yet
+    StartFileTransfer(NewTransfer, RpcReplyPort<Option<(u64, u64)>>),
+    /// user, room, transfer id; replies with id and time of the stored message, or with reason if the transfer is not complete
+    CompleteFileTransfer(String, String, u64, RpcReplyPort<Result<(u64, u64), String>>),
+    /// transfer id, chunk index
+    GetFileChunk(u64, u64, RpcReplyPort<Option<Vec<u8>>>),
+    GetFileContent(u64, RpcReplyPort<Option<(String, TransferKind, Vec<u8>)>>),
+    /// replies with password hash, `None` if the user is not registered
+    GetPasswordHash(String, RpcReplyPort<Result<Option<String>, String>>),
+    /// user, password hash; replies with reason if the user can't be registered
+    CreateUser(String, String, RpcReplyPort<Result<(), String>>),
+    /// room, user; replies with reason if the room can't be created
+    CreateRoom(String, String, RpcReplyPort<Result<(), String>>),
+    GetRooms(RpcReplyPort<Vec<String>>),
+    /// sender, recipient; replies with id and time assigned to the message
+    StoreDirectMessage(String, String, Message, RpcReplyPort<Option<(u64, u64)>>),
+    /// user is registered or was connected before
+    UserExists(String, RpcReplyPort<bool>),
+    /// user, message id, new content; replies with reason if the user can't edit the message
+    EditMessage(String, u64, String, RpcReplyPort<Result<db::MessageChange, String>>),
+    /// user, message id; replies like `EditMessage`
+    DeleteMessage(String, u64, RpcReplyPort<Result<db::MessageChange, String>>),
+    /// user, message id, emoji; replies with current reactions of the message, or with reason of refusal
+    ToggleReaction(String, u64, String, RpcReplyPort<Result<(db::MessageChange, db::ReactionCounts), String>>),
+    /// parent message id, room of the reply; replies with reason if the message can't be replied to
+    CheckParent(u64, String, RpcReplyPort<Result<(), String>>),
+    /// any message of the thread; replies with the whole thread
+    GetThread(u64, RpcReplyPort<Vec<StoredMessage>>),
+    /// words to find, user the results are limited to (`None` - everything, for web); replies with the best matches
+    Search(String, Option<String>, RpcReplyPort<Vec<StoredMessage>>),
+    /// replies once all writes sent to the actor before are done (stopping the actor drops them)
+    Flush(RpcReplyPort<()>),
+}
+
+/// how many messages are replayed when joining a room for the first time
+const ROOM_HISTORY_LIMIT: u32 = 50;
+
+/// runs read-only query on its own connection of the pool and replies with its result
+///
+/// note: only writes go one by one through the actor (in the order they came); reads don't wait for them, nor for each other
+fn spawn_read<T, F>(pool: &SqlitePool, reply: RpcReplyPort<T>, what: &'static str, query: impl FnOnce(SqlitePool) -> F)
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    let read = query(pool.clone());
+    tokio::spawn(async move {
+        if reply.send(read.await).is_err() {
+            error!("Error sending reply with {}", what);
+        }
+    });
 }
 
 #[async_trait]
 impl Actor for DbAccessActor {
     type Msg = DbMessage;
-    type State = ();
-    type Arguments = ();
+    /// the only pool of connections to the database
+    type State = SqlitePool;
+    /// path to the database file
+    type Arguments = PathBuf;
 
-    async fn pre_start(&self, _myself: ActorRef<Self::Msg>, _: ()) -> Result<Self::State, ActorProcessingErr> {
+    async fn pre_start(&self, _myself: ActorRef<Self::Msg>, path: PathBuf) -> Result<Self::State, ActorProcessingErr> {
+        Ok(db::open(&path).await?)
+    }
+
+    async fn post_stop(&self, _myself: ActorRef<Self::Msg>, pool: &mut Self::State) -> Result<(), ActorProcessingErr> {
+        db::close(pool).await;
         Ok(())
     }
 
-    async fn handle(&self, _myself: ActorRef<Self::Msg>, message: Self::Msg, _: &mut Self::State) -> Result<(), ActorProcessingErr> {
+    async fn handle(&self, _myself: ActorRef<Self::Msg>, message: Self::Msg, pool: &mut Self::State) -> Result<(), ActorProcessingErr> {
         match message {
-            DbMessage::StoreChatMessage{user_name, message} => {
-                db::store_message(&user_name, &message).await
+            DbMessage::StoreChatMessage(user_name, room, message, reply) => {
+                let stored = db::store_message(pool, &user_name, &room, &message).await;
+                if reply.send(stored).is_err() {
+                    error!("Error sending reply");
+                }
             },
-            DbMessage::GetMissingChatMessageSinceLastSeen(user_name, reply) => {
-                let messages = db::get_missing_messages(&user_name).await;
+            DbMessage::StoreFileChunk { transfer_id, index, data } => {
+                db::store_file_chunk(pool, transfer_id, index, &data).await
+            },
+            DbMessage::MarkWritten { message_id, user_name, room } => {
+                db::mark_written(pool, message_id, &user_name, &room).await
+            },
+            DbMessage::MarkReceived { message_id, user_name } => {
+                db::mark_received(pool, message_id, &user_name).await
+            },
+            DbMessage::GetUnsentMessages(user_name, room, reply) => {
+                let history = match room.as_str() {
+                    DEFAULT_ROOM => 0,
+                    db::DIRECT_MESSAGES_ROOM => u32::MAX,
+                    _ => ROOM_HISTORY_LIMIT,
+                };
+                let messages = db::get_unsent_messages(pool, &user_name, &room, history).await
+                    .into_iter()
+                    .map(|(id, time, room, message)| MissingMessage { id, time, room, message })
+                    .collect();
                 if reply.send(messages).is_err() {
                     error!("Error sending reply");
                 }
             },
+            DbMessage::Flush(reply) => {
+                if reply.send(()).is_err() {
+                    error!("Error sending reply");
+                }
+            },
             DbMessage::UpdateLastSeen { user_names} => {
-                db::update_online_users(&user_names).await;
+                db::update_online_users(pool, &user_names).await;
+            },
+            DbMessage::GetMessagesAfter(user_name, room, last_id, reply) => {
+                spawn_read(pool, reply, "messages", |pool| async move {
+                    db::get_messages_after(&pool, &user_name, &room, last_id).await
+                        .into_iter()
+                        .map(|(id, time, room, message)| MissingMessage { id, time, room, message })
+                        .collect()
+                });
             },
             DbMessage::GetAllUsersLastSeen(reply) => {
-                let user_data = 
-                    db::get_all_last_online_data().await
-                    .into_iter()
-                    .map(|(user, time)| UserData { user_name: user, last_seen: time})
-                    .collect();
-                if reply.send(user_data).is_err() {
+                spawn_read(pool, reply, "users", |pool| async move {
+                    db::get_all_last_online_data(&pool).await
+                        .into_iter()
+                        .map(|(user, time)| UserData { user_name: user, last_seen: time})
+                        .collect()
+                });
+            },
+            DbMessage::ListAllMessages(user, room, reply) => {
+                spawn_read(pool, reply, "messages", |pool| async move {
+                    db::get_all_messages(&pool, user, room).await
+                        .into_iter()
+                        .map(StoredMessage::from)
+                        .collect()
+                });
+            },
+            DbMessage::ForgetUser { user_name } => {
+                db::forget_user(pool, user_name).await;
+            },
+            DbMessage::StartFileTransfer(transfer, reply) => {
+                let res = db::start_file_transfer(pool, &transfer.user_name, &transfer.name, transfer.kind, transfer.size, &transfer.checksum).await;
+                if reply.send(res).is_err() {
                     error!("Error sending reply");
                 }
             },
-            DbMessage::ListAllMessages(user, reply) => {
-                let messages = db::get_all_messages(user).await
-                    .into_iter()
-                    .map(|(time, user_name, message)| StoredMessage {time, user_name, message })
-                    .collect();
-                if reply.send(messages).is_err() {
-                    error!("Error sending reply with messages");
+            DbMessage::CompleteFileTransfer(user_name, room, transfer_id, reply) => {
+                let res = db::complete_file_transfer(pool, &user_name, &room, transfer_id).await;
+                if reply.send(res).is_err() {
+                    error!("Error sending reply");
                 }
             },
-            DbMessage::ForgetUser { user_name } => {
-                db::forget_user(user_name).await;
+            DbMessage::GetFileChunk(transfer_id, index, reply) => {
+                spawn_read(pool, reply, "chunk", |pool| async move { db::get_file_chunk(&pool, transfer_id, index).await });
+            },
+            DbMessage::GetFileContent(transfer_id, reply) => {
+                spawn_read(pool, reply, "file content", |pool| async move { db::get_file_content(&pool, transfer_id).await });
+            },
+            DbMessage::GetPasswordHash(user_name, reply) => {
+                spawn_read(pool, reply, "password hash", |pool| async move { db::get_password_hash(&pool, &user_name).await });
+            },
+            DbMessage::CreateUser(user_name, password_hash, reply) => {
+                let res = db::create_user(pool, &user_name, &password_hash).await;
+                if reply.send(res).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::CreateRoom(room, user_name, reply) => {
+                let res = db::create_room(pool, &room, &user_name).await;
+                if reply.send(res).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::GetRooms(reply) => {
+                spawn_read(pool, reply, "rooms", |pool| async move { db::get_rooms(&pool).await });
+            },
+            DbMessage::StoreDirectMessage(user_name, recipient, message, reply) => {
+                let stored = db::store_direct_message(pool, &user_name, &recipient, &message).await;
+                if reply.send(stored).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::UserExists(user_name, reply) => {
+                spawn_read(pool, reply, "user", |pool| async move { db::user_exists(&pool, &user_name).await });
+            },
+            DbMessage::EditMessage(user_name, id, content, reply) => {
+                if reply.send(db::edit_message(pool, &user_name, id, &content).await).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::DeleteMessage(user_name, id, reply) => {
+                if reply.send(db::delete_message(pool, &user_name, id).await).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::ToggleReaction(user_name, id, emoji, reply) => {
+                if reply.send(db::toggle_reaction(pool, &user_name, id, &emoji).await).is_err() {
+                    error!("Error sending reply");
+                }
+            },
+            DbMessage::CheckParent(parent, room, reply) => {
+                spawn_read(pool, reply, "parent", |pool| async move { db::check_parent(&pool, parent, &room).await });
+            },
+            DbMessage::GetThread(id, reply) => {
+                spawn_read(pool, reply, "thread", |pool| async move {
+                    db::get_thread(&pool, id).await.into_iter().map(StoredMessage::from).collect()
+                });
+            },
+            DbMessage::Search(terms, user_name, reply) => {
+                spawn_read(pool, reply, "search results", |pool| async move {
+                    db::search_messages(&pool, &terms, user_name.as_deref()).await.into_iter().map(StoredMessage::from).collect()
+                });
             }
         }
         Ok(())
@@ -0,0 +1,40 @@
+//! passwords of registered users
+//!
+//! only argon2 hashes are stored (PHC string format, contains random salt of each user)
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use anyhow::{Result, anyhow};
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Unable to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_password_is_verified() {
+        let hash = hash_password("secret").unwrap();
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert!(!verify_password("secret", "not a hash"));
+    }
+
+    #[test]
+    fn test_same_passwords_have_different_hashes() {
+        assert_ne!(hash_password("secret").unwrap(), hash_password("secret").unwrap());
+    }
+}
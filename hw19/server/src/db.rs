@@ -1,19 +1,97 @@
-use sqlx::{SqlitePool, FromRow};
-use anyhow::Result;
+use sqlx::{SqliteConnection, SqlitePool, FromRow};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use anyhow::{Context, Result};
 use log::{info, debug, error};
-use std::{time::SystemTime, vec};
-use shared::Message;
-
-const DB_URL: &str = "sqlite://sqlite.db";
+use std::{collections::HashMap, path::Path, time::{Duration, SystemTime}, vec};
+use shared::{Message, DEFAULT_ROOM};
+use shared::transfer::{self, Checksum, TransferKind};
 
 #[allow(dead_code)]
 #[derive(Clone, FromRow, Debug)]
 struct DbMessage {
+    id: i64,
     time: i64, 
     client: String,
-    message: Vec<u8>
+    message: Vec<u8>,
+    room: String,
+    recipient: Option<String>,
+    /// time of the last edit / of deletion
+    edited: Option<i64>,
+    deleted: Option<i64>,
+    /// id of the message this one replies to
+    parent: Option<i64>,
+}
+
+impl DbMessage {
+    /// replies are stored as the inner message with `parent` set, they are wrapped again here
+    fn to_message(&self) -> Result<Message> {
+        let message = Message::deserialize(&self.message)?;
+        Ok(match self.parent {
+            Some(parent) => Message::Reply { parent: parent as u64, message: Box::new(message) },
+            None => message,
+        })
+    }
+}
+
+/// stored message with its delivery state, see `get_all_messages`
+pub struct MessageRecord {
+    pub id: u64,
+    pub time: SystemTime,
+    pub author: String,
+    pub room: String,
+    pub message: Message,
+    /// users that confirmed receiving the message
+    pub received_by: Vec<String>,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: ReactionCounts,
+    /// the message replies to this one
+    pub parent: Option<u64>,
+}
+
+/// emoji and count of users that reacted with it, in order of the first reaction
+pub type ReactionCounts = Vec<(String, u32)>;
+
+/// result of edit/delete - when it happened and where the message belongs, so that the change can be propagated
+pub struct MessageChange {
+    /// ms since unix epoch
+    pub time: u64,
+    pub author: String,
+    pub room: String,
+    /// `Some` for direct messages
+    pub recipient: Option<String>,
+}
+
+/// "room" of private messages; they have their own delivery cursor
+pub const DIRECT_MESSAGES_ROOM: &str = "";
+
+// state of message delivery to one recipient (`Deliveries.state`)
+const DELIVERY_SENT: i64 = 1;
+const DELIVERY_RECEIVED: i64 = 2;
+
+#[allow(dead_code)]
+#[derive(Clone, FromRow, Debug)]
+struct DbFileTransfer {
+    id: i64,
+    time: i64,
+    client: String,
+    name: String,
+    kind: i64,
+    size: i64,
+    checksum: String,
+    completed: bool,
 }
 
+impl DbFileTransfer {
+    fn kind(&self) -> TransferKind {
+        if self.kind == KIND_IMAGE { TransferKind::Image } else { TransferKind::File }
+    }
+}
+
+// how `TransferKind` is stored in `FileTransfers.kind`
+const KIND_FILE: i64 = 0;
+const KIND_IMAGE: i64 = 1;
+
 #[allow(dead_code)]
 #[derive(Clone, FromRow, Debug)]
 struct LastClientOnlinePresence {
@@ -21,74 +99,615 @@ struct LastClientOnlinePresence {
     client: String,
 }
 
-pub async fn ensure_db_exists() -> Result<()> {
-    create_if_needed(DB_URL).await
+/// how long a connection waits for a lock held by another one before the query fails
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CONNECTIONS: u32 = 8;
+
+/// opens pool of connections to the database at `path`; creates the database if it doesn't exist and brings its schema up to date
+///
+/// note: in WAL mode readers don't block the writer (and vice versa), NORMAL sync is safe with WAL
+pub async fn open(path: &Path) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT);
+    let db = SqlitePoolOptions::new()
+        .max_connections(MAX_CONNECTIONS)
+        .connect_with(options)
+        .await
+        .with_context(|| format!("Unable to open database {}", path.display()))?;
+    let version = migrate(&db).await?;
+    debug!("Database {} has schema version {}", path.display(), version);
+    index_new_messages(&db).await?;
+    Ok(db)
 }
 
-async fn create_if_needed(db_url: &str) -> Result<()> {
-    use sqlx::{Sqlite, migrate::MigrateDatabase};
+/// schema changes in order of application; version of the schema is the number of applied ones (see `SchemaVersion`)
+///
+/// note: never change a migration that was released, add a new one
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: the schema created by the first server version; `IF NOT EXISTS` - databases created back then already have it
+    &[
+        "CREATE TABLE IF NOT EXISTS Messages (time INTEGER, client VARCHAR(250) NOT NULL, message blob NOT NULL);",
+        "CREATE TABLE IF NOT EXISTS LastOnline (time INTEGER, client VARCHAR(250) NOT NULL PRIMARY KEY);",
+    ],
+    // 2: file transfers
+    &[
+        "CREATE TABLE IF NOT EXISTS FileTransfers (id INTEGER PRIMARY KEY AUTOINCREMENT, time INTEGER, client VARCHAR(250) NOT NULL, name VARCHAR(250) NOT NULL, kind INTEGER NOT NULL, size INTEGER NOT NULL, checksum VARCHAR(64) NOT NULL, completed BOOLEAN NOT NULL DEFAULT 0);",
+        "CREATE TABLE IF NOT EXISTS FileChunks (transfer_id INTEGER NOT NULL, idx INTEGER NOT NULL, data blob NOT NULL, PRIMARY KEY (transfer_id, idx));",
+    ],
+    // 3: message ids; sqlite can't add primary key to existing table, so `Messages` is copied - rowid becomes the id, the order stays
+    &[
+        "CREATE TABLE Messages_new (id INTEGER PRIMARY KEY AUTOINCREMENT, time INTEGER, client VARCHAR(250) NOT NULL, message blob NOT NULL);",
+        "INSERT INTO Messages_new (id, time, client, message) SELECT rowid, time, client, message from Messages order by rowid;",
+        "DROP TABLE Messages;",
+        "ALTER TABLE Messages_new RENAME TO Messages;",
+        "CREATE TABLE IF NOT EXISTS Deliveries (message_id INTEGER NOT NULL, client VARCHAR(250) NOT NULL, state INTEGER NOT NULL, time INTEGER, PRIMARY KEY (message_id, client));",
+    ],
+    // 4: registered users
+    &[
+        "CREATE TABLE IF NOT EXISTS Users (name VARCHAR(250) NOT NULL PRIMARY KEY, password_hash VARCHAR(250) NOT NULL, time INTEGER);",
+    ],
+    // 5: rooms; messages stored before were all in the default one
+    &[
+        "ALTER TABLE Messages ADD COLUMN room VARCHAR(250) NOT NULL DEFAULT 'general';",
+        "CREATE TABLE IF NOT EXISTS Rooms (name VARCHAR(250) NOT NULL PRIMARY KEY, client VARCHAR(250) NOT NULL, time INTEGER);",
+    ],
+    // 6: private messages
+    &[
+        "ALTER TABLE Messages ADD COLUMN recipient VARCHAR(250);",
+    ],
+    // 7: edits and deletes
+    &[
+        "ALTER TABLE Messages ADD COLUMN edited INTEGER;",
+        "ALTER TABLE Messages ADD COLUMN deleted INTEGER;",
+        "CREATE TABLE IF NOT EXISTS MessageEdits (message_id INTEGER NOT NULL, time INTEGER, message blob NOT NULL);",
+    ],
+    // 8: reactions
+    &[
+        "CREATE TABLE IF NOT EXISTS Reactions (message_id INTEGER NOT NULL, client VARCHAR(250) NOT NULL, emoji VARCHAR(32) NOT NULL, time INTEGER, PRIMARY KEY (message_id, client, emoji));",
+    ],
+    // 9: threads
+    &[
+        "ALTER TABLE Messages ADD COLUMN parent INTEGER;",
+    ],
+    // 10: delivery cursors
+    &[
+        "CREATE TABLE IF NOT EXISTS DeliveryCursors (client VARCHAR(250) NOT NULL, room VARCHAR(250) NOT NULL, last_id INTEGER NOT NULL, PRIMARY KEY (client, room));",
+    ],
+    // 11: indexes for the web (filter by user, order by time)
+    &[
+        "CREATE INDEX Messages_time ON Messages (time);",
+        "CREATE INDEX Messages_client ON Messages (client);",
+    ],
+    // 12: users seen before delivery cursors existed would be handled as new ones (nothing replayed, or all private messages again);
+    // messages up to their last presence count as delivered
+    &[
+        "INSERT OR IGNORE INTO DeliveryCursors (client, room, last_id) SELECT client, 'general', (SELECT coalesce(max(id), 0) from Messages WHERE time <= LastOnline.time) from LastOnline;",
+        "INSERT OR IGNORE INTO DeliveryCursors (client, room, last_id) SELECT client, '', (SELECT coalesce(max(id), 0) from Messages WHERE time <= LastOnline.time) from LastOnline;",
+    ],
+    // 13: full-text index, rowid is id of the message; messages are blobs, so it's filled in by `index_message` (existing ones by `index_new_messages`)
+    &[
+        "CREATE VIRTUAL TABLE MessagesSearch USING fts5(content, name, tokenize = 'unicode61 remove_diacritics 2');",
+    ],
+];
 
-    if !Sqlite::database_exists(db_url).await.unwrap_or(false) {
-        debug!("Creating database {}", db_url);
-        match Sqlite::create_database(db_url).await {
-            Ok(_) => info!("Create db success"),
-            Err(error) => panic!("error: {}", error),
+/// applies migrations missing in the database, each in its own transaction; returns version of the schema
+async fn migrate(db: &SqlitePool) -> Result<usize> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS SchemaVersion (version INTEGER NOT NULL PRIMARY KEY, time INTEGER);").execute(db).await?;
+    let (version,): (i64,) = sqlx::query_as("SELECT coalesce(max(version), 0) from SchemaVersion;")
+        .fetch_one(db)
+        .await?;
+    let mut version = version as usize;
+    if version == 0 {
+        version = record_unversioned_schema(db).await?;
+    }
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("Database schema version {} is newer than the supported one ({})", version, MIGRATIONS.len());
+    }
+    for (index, statements) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = index + 1;
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+        let mut tx = db.begin().await?;
+        for statement in statements.iter() {
+            sqlx::query(statement).execute(&mut *tx).await
+                .with_context(|| format!("Migration to schema version {} failed", version))?;
         }
+        sqlx::query("INSERT INTO SchemaVersion (version, time) VALUES (?, ?);")
+            .bind(version as i64)
+            .bind(time)
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+        info!("Database migrated to schema version {}", version);
+    }
+    Ok(MIGRATIONS.len())
+}
 
-        create_tables(db_url).await?;
-    } else {
-        debug!("Database already exists");
+/// databases created before versioning have the schema of the server version that created them (it created all tables at once,
+/// changes were never applied to existing databases) - the newest table/column tells which migrations the schema already has;
+/// they are recorded as applied, returns their count
+async fn record_unversioned_schema(db: &SqlitePool) -> Result<usize> {
+    // version, table, column that appeared with it; the newest first
+    const CHANGES: &[(usize, &str, Option<&str>)] = &[
+        (10, "DeliveryCursors", None),
+        (9, "Messages", Some("parent")),
+        (8, "Reactions", None),
+        (7, "Messages", Some("deleted")),
+        (6, "Messages", Some("recipient")),
+        (5, "Messages", Some("room")),
+        (4, "Users", None),
+        (3, "Messages", Some("id")),
+        (2, "FileTransfers", None),
+        (1, "Messages", None),
+    ];
+    let mut version = 0;
+    for (change_version, table, column) in CHANGES {
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) from pragma_table_info(?) WHERE (?) is null or name = (?);")
+            .bind(table)
+            .bind(column)
+            .bind(column)
+            .fetch_one(db)
+            .await?;
+        if count > 0 {
+            version = *change_version;
+            break;
+        }
     }
-    std::thread::sleep(std::time::Duration::from_millis(20));   // todo: now idea why this is needed; running like "cargo test -- --show-output --test-threads=1"
-    Ok(())
+    if version > 0 {
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+        let mut tx = db.begin().await?;
+        for applied in 1..=version {
+            sqlx::query("INSERT INTO SchemaVersion (version, time) VALUES (?, ?);")
+                .bind(applied as i64)
+                .bind(time)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        info!("Database created before versioning has schema version {}", version);
+    }
+    Ok(version)
 }
 
-async fn create_tables(db_url: &str) -> Result<()> {
-    let db = SqlitePool::connect(db_url).await?;
-    let result = sqlx::query("CREATE TABLE Messages (time INTEGER, client VARCHAR(250) NOT NULL, message blob NOT NULL);").execute(&db).await.unwrap();
-    debug!("Create user table result: {:?}", result);
-    let result = sqlx::query("CREATE TABLE LastOnline (time INTEGER, client VARCHAR(250) NOT NULL PRIMARY KEY);").execute(&db).await.unwrap();
-    debug!("Create last online result: {:?}", result);
+/// writes everything from the WAL to the database file and closes all connections
+pub async fn close(db: &SqlitePool) {
+    if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE);").execute(db).await {
+        error!("Error checkpointing DB: {}", e);
+    }
     db.close().await;
-    Ok(())
 }
 
-pub async fn store_message(user_name: &str, message: &Message) {
-    if let Err(e) = insert_message(DB_URL, &user_name, &message).await {
-        error!("Error inserting message to DB: {}", e);                     // note: probably good reason to exit program gracefully
+/// stores chat message sent to the room; returns id and time (ms since unix epoch) assigned to the message
+pub async fn store_message(db: &SqlitePool, user_name: &str, room: &str, message: &Message) -> Option<(u64, u64)> {
+    match insert_message(db, user_name, room, message).await {
+        Err(e) => {
+            error!("Error inserting message to DB: {}", e);                     // note: probably good reason to exit program gracefully
+            None
+        },
+        Ok(res) => Some(res)
     }
 }
 
-async fn insert_message(db_url: &str, client: &str, message: &Message) -> Result<()> {
+/// stores private message; it doesn't belong to any room
+pub async fn store_direct_message(db: &SqlitePool, user_name: &str, recipient: &str, message: &Message) -> Option<(u64, u64)> {
+    match insert_direct_message(db, user_name, recipient, message).await {
+        Err(e) => {
+            error!("Error inserting direct message to DB: {}", e);
+            None
+        },
+        Ok(res) => Some(res)
+    }
+}
+
+/// chunks of files are stored separately, the transfer appears in `Messages` once it's complete
+pub async fn store_file_chunk(db: &SqlitePool, transfer_id: u64, index: u64, data: &[u8]) {
+    if let Err(e) = insert_file_chunk(db, transfer_id, index, data).await {
+        error!("Error inserting chunk {} of transfer {} to DB: {}", index, transfer_id, e);
+    }
+}
+
+async fn insert_message(db: &SqlitePool, client: &str, room: &str, message: &Message) -> Result<(u64, u64)> {
+    let parent = match message {
+        Message::Reply { parent, .. } => Some(*parent as i64),
+        _ => None,
+    };
+    let message_blob = message.unwrap_reply().serialize()?;
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+
+    let mut tx = db.begin().await?;
+    let result = sqlx::query("INSERT INTO Messages (time, client, message, room, parent) VALUES (?, ?, ?, ?, ?);")
+        .bind(time)
+        .bind(client)
+        .bind(message_blob)
+        .bind(room)
+        .bind(parent)
+        .execute(&mut *tx).await?;
+    let id = result.last_insert_rowid();
+    index_message(&mut tx, id, searchable(message)).await?;
+    tx.commit().await?;
+    Ok((id as u64, time as u64))
+}
+
+async fn insert_direct_message(db: &SqlitePool, client: &str, recipient: &str, message: &Message) -> Result<(u64, u64)> {
     let message_blob = message.serialize()?;
     let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
 
-    let db = SqlitePool::connect(db_url).await?;
-    sqlx::query("INSERT INTO Messages (time, client, message) VALUES (?, ?, ?);")
+    let mut tx = db.begin().await?;
+    let result = sqlx::query("INSERT INTO Messages (time, client, message, room, recipient) VALUES (?, ?, ?, ?, ?);")
         .bind(time)
         .bind(client)
         .bind(message_blob)
-        .execute(&db).await?;
-    db.close().await;
+        .bind(DIRECT_MESSAGES_ROOM)
+        .bind(recipient)
+        .execute(&mut *tx).await?;
+    let id = result.last_insert_rowid();
+    index_message(&mut tx, id, searchable(message)).await?;
+    tx.commit().await?;
+    Ok((id as u64, time as u64))
+}
+
+/// what of the message can be found by `search_messages` - its text and file name
+fn searchable(message: &Message) -> (&str, &str) {
+    match message.unwrap_reply() {
+        Message::Text { content, .. } | Message::Direct { content, .. } => (content, ""),
+        Message::File { name, .. } | Message::FileOffer { name, .. } => ("", name),
+        _ => ("", ""),
+    }
+}
+
+/// every stored message has a row in the index (maybe empty), so the biggest indexed id tells where the index ends
+async fn index_message(db: &mut SqliteConnection, id: i64, (content, name): (&str, &str)) -> Result<()> {
+    sqlx::query("INSERT INTO MessagesSearch (rowid, content, name) VALUES (?, ?, ?);")
+        .bind(id)
+        .bind(content)
+        .bind(name)
+        .execute(db).await?;
+    Ok(())
+}
+
+/// indexes messages stored before the index existed
+async fn index_new_messages(db: &SqlitePool) -> Result<()> {
+    const BATCH: i64 = 500;
+    let (mut last,): (i64,) = sqlx::query_as("SELECT coalesce(max(rowid), 0) from MessagesSearch;").fetch_one(db).await?;
+    let mut indexed = 0;
+    loop {
+        let rows = sqlx::query_as::<_, (i64, Vec<u8>)>("SELECT id, message from Messages WHERE id > (?) order by id limit (?);")
+            .bind(last)
+            .bind(BATCH)
+            .fetch_all(db)
+            .await?;
+        let Some((id, _)) = rows.last() else {
+            break;
+        };
+        last = *id;
+        let mut tx = db.begin().await?;
+        for (id, message) in rows {
+            // note: broken message still gets (empty) row, otherwise it would be tried again on every start
+            let message = Message::deserialize(&message).inspect_err(|e| error!("Unable to index message {}: {}", id, e)).ok();
+            index_message(&mut tx, id, message.as_ref().map(searchable).unwrap_or_default()).await?;
+            indexed += 1;
+        }
+        tx.commit().await?;
+    }
+    if indexed > 0 {
+        info!("{} messages added to search index", indexed);
+    }
+    Ok(())
+}
+
+/// message of the room was written to the socket of the user - it's sent and the cursor of the user in the room moves past it
+///
+/// note: message ids are the sequence (`AUTOINCREMENT` never reuses nor decreases them), so the cursor is just the last id
+pub async fn mark_written(db: &SqlitePool, message_id: u64, user: &str, room: &str) {
+    if let Err(e) = mark_written_priv(db, message_id, user, room).await {
+        error!("Error when marking message {} as sent to {}: {}", message_id, user, e);
+    }
+}
+
+async fn mark_written_priv(db: &SqlitePool, message_id: u64, user: &str, room: &str) -> Result<()> {
+    mark_delivery_priv(db, message_id, &[user.to_string()], DELIVERY_SENT).await?;
+    // replayed room history may be older than messages written before
+    sqlx::query("INSERT INTO DeliveryCursors (client, room, last_id) VALUES (?, ?, ?) ON CONFLICT (client, room) DO UPDATE SET last_id = max(last_id, excluded.last_id);")
+        .bind(user)
+        .bind(room)
+        .bind(message_id as i64)
+        .execute(db).await?;
+    Ok(())
+}
+
+/// user confirmed the message was received
+pub async fn mark_received(db: &SqlitePool, message_id: u64, user: &str) {
+    if let Err(e) = mark_delivery_priv(db, message_id, &[user.to_string()], DELIVERY_RECEIVED).await {
+        error!("Error when marking message {} as received by {}: {}", message_id, user, e);
+    }
+}
+
+async fn mark_delivery_priv(db: &SqlitePool, message_id: u64, users: &[String], state: i64) -> Result<()> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+
+    for user in users {
+        // state never goes back (e.g. replayed message which was already received)
+        sqlx::query("INSERT INTO Deliveries (message_id, client, state, time) VALUES (?, ?, ?, ?) ON CONFLICT (message_id, client) DO UPDATE SET state = excluded.state, time = excluded.time WHERE excluded.state > Deliveries.state;")
+            .bind(message_id as i64)
+            .bind(user)
+            .bind(state)
+            .bind(time)
+            .execute(db).await?;
+    }
     Ok(())
 }
 
-async fn get_last_online_time(db_url: &str, client: &str) -> Result<Option<i64>> {
-    let db = SqlitePool::connect(db_url).await?;
+async fn insert_file_chunk(db: &SqlitePool, transfer_id: u64, index: u64, data: &[u8]) -> Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO FileChunks (transfer_id, idx, data) VALUES (?, ?, ?);")
+        .bind(transfer_id as i64)
+        .bind(index as i64)
+        .bind(data)
+        .execute(db).await?;
+    Ok(())
+}
+
+/// registers new transfer or finds unfinished transfer of the same content from the same client
+///
+/// returns id of the transfer and index of the first chunk that is not stored yet
+pub async fn start_file_transfer(db: &SqlitePool, client: &str, name: &str, kind: TransferKind, size: u64, checksum: &str) -> Option<(u64, u64)> {
+    match start_file_transfer_priv(db, client, name, kind, size, checksum).await {
+        Err(e) => {
+            error!("Error when starting file transfer {} for user {}: {}", name, client, e);
+            None
+        },
+        Ok(res) => Some(res)
+    }
+}
+
+async fn start_file_transfer_priv(db: &SqlitePool, client: &str, name: &str, kind: TransferKind, size: u64, checksum: &str) -> Result<(u64, u64)> {
+    let unfinished = 
+        sqlx::query_as::<_, DbFileTransfer>("SELECT * from FileTransfers WHERE client = (?) and checksum = (?) and size = (?) and completed = 0 order by id desc")
+        .bind(client)
+        .bind(checksum)
+        .bind(size as i64)
+        .fetch_optional(db)
+        .await?;
+    let res = match unfinished {
+        Some(transfer) => {
+            // note: not the count of stored chunks - a chunk in the middle may be missing (e.g. lost with the connection); 0 when the first one is missing
+            let (first_missing,): (i64,) = sqlx::query_as("SELECT coalesce(min(c.idx + 1), 0) * exists (SELECT 1 from FileChunks WHERE transfer_id = (?) and idx = 0) from FileChunks c \
+                                                           WHERE c.transfer_id = (?) and not exists (SELECT 1 from FileChunks n WHERE n.transfer_id = c.transfer_id and n.idx = c.idx + 1)")
+                .bind(transfer.id)
+                .bind(transfer.id)
+                .fetch_one(db)
+                .await?;
+            (transfer.id as u64, first_missing as u64)
+        },
+        None => {
+            let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+            let kind = match kind { TransferKind::File => KIND_FILE, TransferKind::Image => KIND_IMAGE };
+            let result = sqlx::query("INSERT INTO FileTransfers (time, client, name, kind, size, checksum) VALUES (?, ?, ?, ?, ?, ?);")
+                .bind(time)
+                .bind(client)
+                .bind(name)
+                .bind(kind)
+                .bind(size as i64)
+                .bind(checksum)
+                .execute(db).await?;
+            (result.last_insert_rowid() as u64, 0)
+        }
+    };
+    Ok(res)
+}
+
+/// checks that all chunks are stored and match the checksum announced by the client
+///
+/// on success the transfer is stored in `Messages` (as `Message::FileOffer`) so that it's part of the history of the room;
+/// returns id and time of the stored message
+pub async fn complete_file_transfer(db: &SqlitePool, client: &str, room: &str, transfer_id: u64) -> Result<(u64, u64), String> {
+    complete_file_transfer_priv(db, client, room, transfer_id).await
+        .map_err(|e| {
+            error!("Error when completing transfer {} of user {}: {}", transfer_id, client, e);
+            e.to_string()
+        })
+}
+
+async fn complete_file_transfer_priv(db: &SqlitePool, client: &str, room: &str, transfer_id: u64) -> Result<(u64, u64)> {
+    let Some(transfer) = get_file_transfer(db, transfer_id).await? else {
+        anyhow::bail!("Unknown transfer");
+    };
+    if transfer.client != client {
+        anyhow::bail!("Transfer belongs to another user");
+    }
+
+    let mut checksum = Checksum::new();
+    let mut size = 0u64;
+    for index in 0..transfer::chunk_count(transfer.size as u64) {
+        let chunk: Option<(Vec<u8>,)> = sqlx::query_as("SELECT data from FileChunks WHERE transfer_id = (?) and idx = (?)")
+            .bind(transfer.id)
+            .bind(index as i64)
+            .fetch_optional(db)
+            .await?;
+        let Some((data,)) = chunk else {
+            anyhow::bail!("Chunk {} is missing", index);
+        };
+        size += data.len() as u64;
+        checksum.update(&data);
+    }
+    if size != transfer.size as u64 || checksum.finish() != transfer.checksum {
+        anyhow::bail!("Checksum doesn't match");
+    }
+
+    sqlx::query("UPDATE FileTransfers set completed = 1 WHERE id = (?);")
+        .bind(transfer.id)
+        .execute(db).await?;
+
+    insert_message(db, client, room, &transfer_to_message(&transfer)).await
+}
+
+async fn get_file_transfer(db: &SqlitePool, transfer_id: u64) -> Result<Option<DbFileTransfer>> {
+    let res = 
+        sqlx::query_as::<_, DbFileTransfer>("SELECT * from FileTransfers WHERE id = (?)")
+        .bind(transfer_id as i64)
+        .fetch_optional(db)
+        .await?;
+    Ok(res)
+}
+
+fn transfer_to_message(transfer: &DbFileTransfer) -> Message {
+    Message::FileOffer { 
+        transfer_id: Some(transfer.id as u64), 
+        from: transfer.client.clone(), 
+        name: transfer.name.clone(), 
+        kind: transfer.kind(), 
+        size: transfer.size as u64, 
+        checksum: transfer.checksum.clone()
+    }
+}
+
+/// one stored chunk; used when replaying transfers to clients
+pub async fn get_file_chunk(db: &SqlitePool, transfer_id: u64, index: u64) -> Option<Vec<u8>> {
+    match get_file_chunk_priv(db, transfer_id, index).await {
+        Err(e) => {
+            error!("Error when getting chunk {} of transfer {}: {}", index, transfer_id, e);
+            None
+        },
+        Ok(res) => res
+    }
+}
+
+async fn get_file_chunk_priv(db: &SqlitePool, transfer_id: u64, index: u64) -> Result<Option<Vec<u8>>> {
+    let res: Option<(Vec<u8>,)> = sqlx::query_as("SELECT data from FileChunks WHERE transfer_id = (?) and idx = (?)")
+        .bind(transfer_id as i64)
+        .bind(index as i64)
+        .fetch_optional(db)
+        .await?;
+    Ok(res.map(|(data,)| data))
+}
+
+/// whole content of a completed transfer (name, kind, content)
+pub async fn get_file_content(db: &SqlitePool, transfer_id: u64) -> Option<(String, TransferKind, Vec<u8>)> {
+    match get_file_content_priv(db, transfer_id).await {
+        Err(e) => {
+            error!("Error when getting content of transfer {}: {}", transfer_id, e);
+            None
+        },
+        Ok(res) => res
+    }
+}
+
+async fn get_file_content_priv(db: &SqlitePool, transfer_id: u64) -> Result<Option<(String, TransferKind, Vec<u8>)>> {
+    let Some(transfer) = get_file_transfer(db, transfer_id).await? else {
+        return Ok(None);
+    };
+    if !transfer.completed {
+        return Ok(None);
+    }
+    let content = sqlx::query_as::<_, (Vec<u8>,)>("SELECT data from FileChunks WHERE transfer_id = (?) order by idx")
+        .bind(transfer.id)
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .flat_map(|(data,)| data)
+        .collect();
+    Ok(Some((transfer.name.clone(), transfer.kind(), content)))
+}
+
+/// password hash of registered user; `None` if the user is not registered
+pub async fn get_password_hash(db: &SqlitePool, user: &str) -> Result<Option<String>, String> {
+    get_password_hash_priv(db, user).await.map_err(|e| {
+        error!("Error getting user from DB: {}", e);
+        "Unable to verify user".to_string()
+    })
+}
+
+async fn get_password_hash_priv(db: &SqlitePool, user: &str) -> Result<Option<String>> {
+    let res: Option<(String,)> = 
+        sqlx::query_as("select password_hash from Users where name = (?)")
+        .bind(user)
+        .fetch_optional(db)
+        .await?;
+    Ok(res.map(|(hash,)| hash))
+}
+
+/// registers new user; fails if the name is already taken
+pub async fn create_user(db: &SqlitePool, user: &str, password_hash: &str) -> Result<(), String> {
+    match create_user_priv(db, user, password_hash).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("User {} is already registered", user)),
+        Err(e) => {
+            error!("Error creating user in DB: {}", e);
+            Err("Unable to register user".into())
+        }
+    }
+}
+
+/// returns false if the user already exists
+async fn create_user_priv(db: &SqlitePool, user: &str, password_hash: &str) -> Result<bool> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+    let res = sqlx::query("INSERT OR IGNORE INTO Users (name, password_hash, time) VALUES (?, ?, ?);")
+        .bind(user)
+        .bind(password_hash)
+        .bind(time)
+        .execute(db).await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// creates new room; fails if it already exists
+pub async fn create_room(db: &SqlitePool, room: &str, user: &str) -> Result<(), String> {
+    match create_room_priv(db, room, user).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Room {} already exists", room)),
+        Err(e) => {
+            error!("Error creating room in DB: {}", e);
+            Err("Unable to create room".into())
+        }
+    }
+}
+
+/// returns false if the room already exists
+async fn create_room_priv(db: &SqlitePool, room: &str, user: &str) -> Result<bool> {
+    if room == DEFAULT_ROOM {
+        return Ok(false);
+    }
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+    let res = sqlx::query("INSERT OR IGNORE INTO Rooms (name, client, time) VALUES (?, ?, ?);")
+        .bind(room)
+        .bind(user)
+        .bind(time)
+        .execute(db).await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// names of all rooms, the default one first
+pub async fn get_rooms(db: &SqlitePool) -> Vec<String> {
+    match get_rooms_priv(db).await {
+        Err(e) => {
+            error!("Error getting rooms from DB: {}", e);
+            vec![DEFAULT_ROOM.to_string()]
+        },
+        Ok(rooms) => rooms
+    }
+}
+
+async fn get_rooms_priv(db: &SqlitePool) -> Result<Vec<String>> {
+    let rooms = sqlx::query_as::<_, (String,)>("select name from Rooms order by name")
+        .fetch_all(db)
+        .await?;
+    Ok(std::iter::once(DEFAULT_ROOM.to_string()).chain(rooms.into_iter().map(|(name,)| name)).collect())
+}
+
+#[cfg(test)]
+async fn get_last_online_time(db: &SqlitePool, client: &str) -> Result<Option<i64>> {
     let res = 
         sqlx::query_as::<_, LastClientOnlinePresence>("select * from LastOnline where client = (?)")
         .bind(client)
-        .fetch_optional(&db)
+        .fetch_optional(db)
         .await?;
-    db.close().await;
     match res {
         Some(LastClientOnlinePresence{time, ..}) => Ok(Some(time)),
         None => Ok(None)
     }
 }
 
-pub async fn get_all_last_online_data() -> Vec<(String, std::time::SystemTime)> {
-    match get_all_last_online_data_priv(DB_URL).await {
+pub async fn get_all_last_online_data(db: &SqlitePool) -> Vec<(String, std::time::SystemTime)> {
+    match get_all_last_online_data_priv(db).await {
         Err(e) => { error!("Error getting users's last seen from DB: {}", e);
                     vec![]
         },
@@ -96,13 +715,11 @@ pub async fn get_all_last_online_data() -> Vec<(String, std::time::SystemTime)>
     }
 
 }
-async fn get_all_last_online_data_priv(db_url: &str) -> Result<Vec<(String, std::time::SystemTime)>> {
-    let db = SqlitePool::connect(db_url).await?;
+async fn get_all_last_online_data_priv(db: &SqlitePool) -> Result<Vec<(String, std::time::SystemTime)>> {
     let res = 
         sqlx::query_as::<_, LastClientOnlinePresence>("select * from LastOnline")
-        .fetch_all(&db)
+        .fetch_all(db)
         .await?;
-    db.close().await;
     let res = res.into_iter()
         .map(|row| (row.client, SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(row.time as u64)))
         .collect();
@@ -110,148 +727,565 @@ async fn get_all_last_online_data_priv(db_url: &str) -> Result<Vec<(String, std:
 
 }
 
-pub async fn update_online_users(users: &[String]) {
-    if let Err(e) = update_online_users_priv(DB_URL, users).await {
+pub async fn update_online_users(db: &SqlitePool, users: &[String]) {
+    if let Err(e) = update_online_users_priv(db, users).await {
         error!("Error updating online users in DB: {}", e);                     // note: probably good reason to exit program gracefully
     }
 }
 
-async fn update_online_users_priv(db_url: &str, users: &[String]) -> Result<()> {
+async fn update_online_users_priv(db: &SqlitePool, users: &[String]) -> Result<()> {
     // note: 
     // let result = sqlx::query("UPDATE LastOnline set time = (?) WHERE client in (?);")
     //     .bind(time)
     //     .bind(users.join(","))  // note: not very safe...
-    //     .execute(&db).await.unwrap();
+    //     .execute(db).await.unwrap();
     // doesn't work well - no user is updated; probably that join works differently than I expect (and AI as well)
 
     let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
 
-    let db = SqlitePool::connect(db_url).await?;
     for user in users {
         sqlx::query("INSERT OR REPLACE INTO LastOnline (time, client) VALUES (?, ?);")
             .bind(time)
             .bind(user)  // note: not very safe...
-            .execute(&db).await?;
-    }
-    db.close().await;   // how that .close().await works together with .await? ????
+            .execute(db).await?;
+    }   // how that .close().await works together with .await? ????
     Ok(())
 }
 
-pub async fn get_missing_messages(user: &str) -> Vec<Message> {
-    match get_missing_messages_priv(DB_URL, user).await {
-        Err(e) => { 
-            error!("Error when getting missing messages from DB for user {}: {}", user, e);
-            return vec![]
+/// messages (id, time, room, message) of the room after the cursor of the user (for `DIRECT_MESSAGES_ROOM` private messages for the user)
+///
+/// user without cursor in the room gets only the last `history` messages and the cursor starts at the newest message
+pub async fn get_unsent_messages(db: &SqlitePool, user: &str, room: &str, history: u32) -> Vec<(u64, u64, String, Message)> {
+    match get_unsent_messages_priv(db, user, room, history).await {
+        Err(e) => {
+            error!("Error when getting unsent messages of room '{}' for user {}: {}", room, user, e);
+            vec![]
         },
         Ok(messages) => messages
     }
 }
-async fn get_missing_messages_priv(db_url: &str, user: &str) -> Result<Vec<Message>> {
-    let user_last_online_time = get_last_online_time(db_url, user).await?;
-    
-    // user not registered yet, don't display him anything from the past...
-    if matches!(user_last_online_time, None) {
-        return Ok(vec![]);
+
+async fn get_unsent_messages_priv(db: &SqlitePool, user: &str, room: &str, history: u32) -> Result<Vec<(u64, u64, String, Message)>> {
+    let cursor = sqlx::query_as::<_, (i64,)>("SELECT last_id from DeliveryCursors WHERE client = (?) and room = (?);")
+        .bind(user)
+        .bind(room)
+        .fetch_optional(db)
+        .await?;
+    // note: private messages have the recipient set, messages of rooms don't
+    let rows = match cursor {
+        Some((last_id,)) => sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE id > (?) and room = (?) and client != (?) and (recipient is null or recipient = (?)) and deleted is null order by id;")
+            .bind(last_id)
+            .bind(room)
+            .bind(user)
+            .bind(user)
+            .fetch_all(db)
+            .await?,
+        None => {
+            sqlx::query("INSERT OR IGNORE INTO DeliveryCursors (client, room, last_id) SELECT ?, ?, coalesce(max(id), 0) from Messages;")
+                .bind(user)
+                .bind(room)
+                .execute(db).await?;
+            let mut rows = sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE room = (?) and client != (?) and (recipient is null or recipient = (?)) and deleted is null order by id desc limit (?);")
+                .bind(room)
+                .bind(user)
+                .bind(user)
+                .bind(history)
+                .fetch_all(db)
+                .await?;
+            rows.reverse();
+            rows
+        },
+    };
+    rows.into_iter()
+        .map(|row| Ok((row.id as u64, row.time as u64, row.room.clone(), row.to_message()?)))
+        .collect()
+}
+
+/// messages (id, time, room, message) of the room and private messages for the user stored after message `last_id`;
+/// replayed to reconnecting client that knows what it got last
+pub async fn get_messages_after(db: &SqlitePool, user: &str, room: &str, last_id: u64) -> Vec<(u64, u64, String, Message)> {
+    match get_messages_after_priv(db, user, room, last_id).await {
+        Err(e) => {
+            error!("Error when getting messages after {} for user {}: {}", last_id, user, e);
+            vec![]
+        },
+        Ok(messages) => messages
     }
-    let user_last_online_time = user_last_online_time.unwrap();
+}
 
-    let db = SqlitePool::connect(db_url).await?;
-    let result = sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE time > (?) and client != (?) order by time, client; ")
-            .bind(user_last_online_time)
+async fn get_messages_after_priv(db: &SqlitePool, user: &str, room: &str, last_id: u64) -> Result<Vec<(u64, u64, String, Message)>> {
+    sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE id > (?) and deleted is null and ((room = (?) and client != (?)) or recipient = (?)) order by id;")
+            .bind(last_id as i64)
+            .bind(room)
+            .bind(user)
             .bind(user)
-            .fetch_all(&db)
+            .fetch_all(db)
             .await?
-            .iter()
-            .map(|row| {
-                Message::deserialize(&row.message).unwrap()
-            })
-            .collect();
-    db.close().await;
-    Ok(result)
+            .into_iter()
+            .map(|row| Ok((row.id as u64, row.time as u64, row.room.clone(), row.to_message()?)))
+            .collect()
+}
+
+/// user is registered or was connected at least once
+pub async fn user_exists(db: &SqlitePool, user: &str) -> bool {
+    match user_exists_priv(db, user).await {
+        Err(e) => {
+            error!("Error when looking for user {}: {}", user, e);
+            false
+        },
+        Ok(exists) => exists
+    }
+}
+
+async fn user_exists_priv(db: &SqlitePool, user: &str) -> Result<bool> {
+    let (count,): (i64,) = sqlx::query_as("SELECT (SELECT count(*) from Users WHERE name = (?)) + (SELECT count(*) from LastOnline WHERE client = (?))")
+        .bind(user)
+        .bind(user)
+        .fetch_one(db)
+        .await?;
+    Ok(count > 0)
 }
 
-pub async fn forget_user(user: String)  {
-    if let Err(e) = forget_user_priv(DB_URL, user).await {
+pub async fn forget_user(db: &SqlitePool, user: String)  {
+    if let Err(e) = forget_user_priv(db, user).await {
         error!("Error fogetting user in DB: {}", e);                     // note: probably good reason to exit program gracefully
     }
 }
 
-async fn forget_user_priv(db_url: &str, user: String) -> Result<()> {
-    let db = SqlitePool::connect(db_url).await?;
-    sqlx::query("DELETE from LastOnline WHERE client = (?);").bind(&user).execute(&db).await?;
-    sqlx::query("DELETE from Messages WHERE client = (?);").bind(&user).execute(&db).await?;
-    db.close().await;
+async fn forget_user_priv(db: &SqlitePool, user: String) -> Result<()> {
+    sqlx::query("DELETE from LastOnline WHERE client = (?);").bind(&user).execute(db).await?;
+    sqlx::query("DELETE from Deliveries WHERE client = (?) or message_id in (SELECT id from Messages WHERE client = (?) or recipient = (?));").bind(&user).bind(&user).bind(&user).execute(db).await?;
+    sqlx::query("DELETE from MessageEdits WHERE message_id in (SELECT id from Messages WHERE client = (?) or recipient = (?));").bind(&user).bind(&user).execute(db).await?;
+    sqlx::query("DELETE from Reactions WHERE client = (?) or message_id in (SELECT id from Messages WHERE client = (?) or recipient = (?));").bind(&user).bind(&user).bind(&user).execute(db).await?;
+    sqlx::query("DELETE from MessagesSearch WHERE rowid in (SELECT id from Messages WHERE client = (?) or recipient = (?));").bind(&user).bind(&user).execute(db).await?;
+    sqlx::query("DELETE from Messages WHERE client = (?) or recipient = (?);").bind(&user).bind(&user).execute(db).await?;
+    sqlx::query("DELETE from FileChunks WHERE transfer_id in (SELECT id from FileTransfers WHERE client = (?));").bind(&user).execute(db).await?;
+    sqlx::query("DELETE from FileTransfers WHERE client = (?);").bind(&user).execute(db).await?;
+    sqlx::query("DELETE from Users WHERE name = (?);").bind(&user).execute(db).await?;
     Ok(())
 }
 
-pub async fn get_all_messages(user: Option<String>) -> Vec<(SystemTime, String, Message)> {
-    match get_all_messages_priv(DB_URL, &user).await {
+/// all stored messages (including deleted ones), optionally only of given user/room
+pub async fn get_all_messages(db: &SqlitePool, user: Option<String>, room: Option<String>) -> Vec<MessageRecord> {
+    match get_all_messages_priv(db, &user, &room, None, None, None).await {
         Err(e) => { 
-            error!("Error when getting messages from DB for user {:?}: {}", &user, e);
-            return vec![]
+            error!("Error when getting messages from DB for user {:?}, room {:?}: {}", &user, &room, e);
+            vec![]
+        },
+        Ok(messages) => messages
+    }
+}
+/// the whole thread the message belongs to (from the first message to the last reply), see `get_all_messages`
+pub async fn get_thread(db: &SqlitePool, id: u64) -> Vec<MessageRecord> {
+    match get_all_messages_priv(db, &None, &None, Some(id as i64), None, None).await {
+        Err(e) => {
+            error!("Error when getting thread of message {} from DB: {}", id, e);
+            vec![]
         },
         Ok(messages) => messages
     }
 }
-async fn get_all_messages_priv(db_url: &str, user: &Option<String>) -> Result<Vec<(SystemTime, String, Message)>> {
-    let query = match user {
-        Some(user) =>
-            sqlx::query_as::<_, DbMessage>("select * from Messages where client = (?) order by time asc").bind(user),
-        None => 
-            sqlx::query_as::<_, DbMessage>("select * from Messages order by time asc"),
+
+/// how many messages `search_messages` returns at most
+const SEARCH_LIMIT: u32 = 50;
+
+/// the best matches of the words typed by user (see `search_query`), in order of ids; see `get_all_messages`
+///
+/// `visible_to` leaves out deleted messages and private messages of other users (web sees everything); it applies only to the search
+pub async fn search_messages(db: &SqlitePool, terms: &str, visible_to: Option<&str>) -> Vec<MessageRecord> {
+    let Some(query) = search_query(terms) else {
+        return vec![];
     };
-    let db = SqlitePool::connect(db_url).await?;
+    match get_all_messages_priv(db, &None, &None, None, Some(&query), visible_to).await {
+        Err(e) => {
+            error!("Error when searching for {:?} in DB: {}", terms, e);
+            vec![]
+        },
+        Ok(messages) => messages
+    }
+}
+
+/// FTS5 query: every word has to be in the message (as a prefix of its word); quotes and operators typed by user are taken literally
+fn search_query(terms: &str) -> Option<String> {
+    let words = terms.split_whitespace().map(|word| format!("\"{}\"*", word.replace('"', "\"\""))).collect::<Vec<_>>();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+async fn get_all_messages_priv(db: &SqlitePool, user: &Option<String>, room: &Option<String>, thread: Option<i64>, search: Option<&str>, visible_to: Option<&str>) -> Result<Vec<MessageRecord>> {
+    #[derive(FromRow)]
+    struct Row {
+        id: i64,
+        time: i64, 
+        client: String,
+        room: String,
+        message: Vec<u8>,
+        edited: Option<i64>,
+        deleted: Option<i64>,
+        parent: Option<i64>,
+        received_by: Option<String>,
+    }
+
+    // note: missing filter matches everything ((?) is null)
+    // thread = the root (found by walking up the parents; its parent may be forgotten) and all replies below it
+    const SELECT: &str = "with recursive \
+                              up(id, parent) as (select id, parent from Messages where id = (?) union all select m.id, m.parent from Messages m join up on m.id = up.parent), \
+                              thread(id) as (select id from up where parent is null or parent not in (select id from Messages) \
+                                             union all select m.id from Messages m join thread t on m.parent = t.id) \
+                          select m.*, (select group_concat(d.client, ',') from Deliveries d where d.message_id = m.id and d.state = 2) as received_by from Messages m \
+                          where ((?) is null or m.client = (?)) and ((?) is null or m.room = (?)) and ((?) is null or m.id in (select id from thread)) \
+                          and ((?) is null or m.id in (select v.id from MessagesSearch join Messages v on v.id = MessagesSearch.rowid where MessagesSearch match (?) \
+                                                       and ((?) is null or (v.deleted is null and (v.recipient is null or v.recipient = (?) or v.client = (?)))) \
+                                                       order by rank limit (?))) \
+                          order by m.id asc";
+    // note: visibility is checked before the limit, hidden matches would take places of the visible ones otherwise
+    let query = sqlx::query_as::<_, Row>(SELECT).bind(thread).bind(user).bind(user).bind(room).bind(room).bind(thread)
+        .bind(search).bind(search).bind(visible_to).bind(visible_to).bind(visible_to).bind(SEARCH_LIMIT);
+    let mut reactions: HashMap<i64, ReactionCounts> = HashMap::new();
+    let counts = sqlx::query_as::<_, (i64, String, i64)>("select message_id, emoji, count(*) from Reactions group by message_id, emoji order by min(time), emoji")
+        .fetch_all(db)
+        .await?;
+    for (id, emoji, count) in counts {
+        reactions.entry(id).or_default().push((emoji, count as u32));
+    }
     let res = 
         query
-        .fetch_all(&db)
+        .fetch_all(db)
         .await?
         .into_iter()
-        .map(|row| {
-            (SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(row.time as u64), 
-            row.client, 
-            Message::deserialize(&row.message).unwrap())
-        })
-        .collect();
-    db.close().await;
+        .map(|row| Ok(MessageRecord {
+            id: row.id as u64,
+            time: SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(row.time as u64), 
+            author: row.client, 
+            room: row.room,
+            message: Message::deserialize(&row.message)?,
+            received_by: row.received_by.map(|users| users.split(',').map(String::from).collect()).unwrap_or_default(),
+            edited: row.edited.is_some(),
+            deleted: row.deleted.is_some(),
+            reactions: reactions.remove(&row.id).unwrap_or_default(),
+            parent: row.parent.map(|parent| parent as u64),
+        }))
+        .collect::<Result<_>>()?;
     Ok(res)
 }
 
+/// new content of text/direct message; only the author may edit it, the previous version goes to `MessageEdits`
+///
+/// returns reason of refusal if the user can't edit the message
+pub async fn edit_message(db: &SqlitePool, user: &str, id: u64, content: &str) -> Result<MessageChange, String> {
+    match edit_message_priv(db, user, id, content).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Error editing message {} in DB: {}", id, e);
+            Err("Unable to edit message".into())
+        }
+    }
+}
+
+/// outer error is db failure, inner one is the reason why the user can't edit the message
+async fn edit_message_priv(db: &SqlitePool, user: &str, id: u64, content: &str) -> Result<Result<MessageChange, String>> {
+    let row = get_own_message(db, user, id).await?;
+    let row = match row {
+        Ok(row) => row,
+        Err(reason) => return Ok(Err(reason)),
+    };
+    let edited = match Message::deserialize(&row.message)? {
+        Message::Text { from, .. } => Message::Text { from, content: content.into() },
+        Message::Direct { from, to, .. } => Message::Direct { from, to, content: content.into() },
+        _ => return Ok(Err(format!("Message #{} is not a text message", id))),
+    };
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+    // the history, the message and the search index change together, or not at all
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT INTO MessageEdits (message_id, time, message) VALUES (?, ?, ?);")
+        .bind(id as i64)
+        .bind(time)
+        .bind(&row.message)
+        .execute(&mut *tx).await?;
+    sqlx::query("UPDATE Messages set message = (?), edited = (?) WHERE id = (?);")
+        .bind(edited.serialize()?)
+        .bind(time)
+        .bind(id as i64)
+        .execute(&mut *tx).await?;
+    sqlx::query("UPDATE MessagesSearch set content = (?) WHERE rowid = (?);")
+        .bind(content)
+        .bind(id as i64)
+        .execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(Ok(MessageChange { time: time as u64, author: row.client, room: row.room, recipient: row.recipient }))
+}
+
+/// marks the message as deleted (tombstone stays in db); only the author may delete it
+///
+/// returns reason of refusal if the user can't delete the message
+pub async fn delete_message(db: &SqlitePool, user: &str, id: u64) -> Result<MessageChange, String> {
+    match delete_message_priv(db, user, id).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Error deleting message {} in DB: {}", id, e);
+            Err("Unable to delete message".into())
+        }
+    }
+}
+
+async fn delete_message_priv(db: &SqlitePool, user: &str, id: u64) -> Result<Result<MessageChange, String>> {
+    let row = match get_own_message(db, user, id).await? {
+        Ok(row) => row,
+        Err(reason) => return Ok(Err(reason)),
+    };
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+    let mut tx = db.begin().await?;
+    sqlx::query("UPDATE Messages set deleted = (?) WHERE id = (?);")
+        .bind(time)
+        .bind(id as i64)
+        .execute(&mut *tx).await?;
+    // note: the row stays (empty), see `index_message`
+    sqlx::query("UPDATE MessagesSearch set content = '', name = '' WHERE rowid = (?);")
+        .bind(id as i64)
+        .execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(Ok(MessageChange { time: time as u64, author: row.client, room: row.room, recipient: row.recipient }))
+}
+
+/// the message can be replied to from the room - it exists, isn't deleted and is in the same room
+pub async fn check_parent(db: &SqlitePool, parent: u64, room: &str) -> Result<(), String> {
+    match check_parent_priv(db, parent, room).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Message #{} doesn't exist in room {}", parent, room)),
+        Err(e) => {
+            error!("Error when looking for message {} in DB: {}", parent, e);
+            Err("Unable to store message".into())
+        }
+    }
+}
+
+async fn check_parent_priv(db: &SqlitePool, parent: u64, room: &str) -> Result<bool> {
+    let (count,): (i64,) = sqlx::query_as("SELECT count(*) from Messages WHERE id = (?) and room = (?) and deleted is null")
+        .bind(parent as i64)
+        .bind(room)
+        .fetch_one(db)
+        .await?;
+    Ok(count > 0)
+}
+
+/// the message if it exists, isn't deleted and belongs to the user
+async fn get_own_message(db: &SqlitePool, user: &str, id: u64) -> Result<Result<DbMessage, String>> {
+    let row = sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE id = (?)")
+        .bind(id as i64)
+        .fetch_optional(db)
+        .await?;
+    Ok(match row {
+        Some(row) if row.deleted.is_some() => Err(format!("Message #{} was deleted", id)),
+        Some(row) if row.client != user => Err(format!("Message #{} is not yours", id)),
+        Some(row) => Ok(row),
+        None => Err(format!("Message #{} doesn't exist", id)),
+    })
+}
+
+/// adds the user's reaction to the message, or removes it if it's already there
+///
+/// returns where the message belongs (the change has to be propagated) and current reactions of the message, or reason of refusal
+pub async fn toggle_reaction(db: &SqlitePool, user: &str, id: u64, emoji: &str) -> Result<(MessageChange, ReactionCounts), String> {
+    match toggle_reaction_priv(db, user, id, emoji).await {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Error storing reaction to message {} in DB: {}", id, e);
+            Err("Unable to store reaction".into())
+        }
+    }
+}
+
+async fn toggle_reaction_priv(db: &SqlitePool, user: &str, id: u64, emoji: &str) -> Result<Result<(MessageChange, ReactionCounts), String>> {
+    let row = sqlx::query_as::<_, DbMessage>("SELECT * from Messages WHERE id = (?)")
+        .bind(id as i64)
+        .fetch_optional(db)
+        .await?;
+    let row = match row {
+        // note: direct messages are visible only to the two users
+        Some(row) if row.deleted.is_none() && row.recipient.as_ref().is_none_or(|to| to == user || row.client == user) => row,
+        _ => return Ok(Err(format!("Message #{} doesn't exist", id))),
+    };
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+    // note: the counts are read in the same transaction, so they include this change and no other
+    let mut tx = db.begin().await?;
+    let removed = sqlx::query("DELETE from Reactions WHERE message_id = (?) and client = (?) and emoji = (?);")
+        .bind(id as i64)
+        .bind(user)
+        .bind(emoji)
+        .execute(&mut *tx).await?;
+    if removed.rows_affected() == 0 {
+        sqlx::query("INSERT INTO Reactions (message_id, client, emoji, time) VALUES (?, ?, ?, ?);")
+            .bind(id as i64)
+            .bind(user)
+            .bind(emoji)
+            .bind(time)
+            .execute(&mut *tx).await?;
+    }
+    let reactions = sqlx::query_as::<_, (String, i64)>("SELECT emoji, count(*) from Reactions WHERE message_id = (?) group by emoji order by min(time), emoji")
+        .bind(id as i64)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|(emoji, count)| (emoji, count as u32))
+        .collect();
+    tx.commit().await?;
+    Ok(Ok((MessageChange { time: time as u64, author: row.client, room: row.room, recipient: row.recipient }, reactions)))
+}
+
+/// previous versions (time of the edit, message) of the message, oldest first
+#[cfg(test)]
+async fn get_message_edits(db: &SqlitePool, id: u64) -> Result<Vec<(u64, Message)>> {
+    let edits = sqlx::query_as::<_, (i64, Vec<u8>)>("SELECT time, message from MessageEdits WHERE message_id = (?) order by time, rowid")
+        .bind(id as i64)
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|(time, message)| Ok((time as u64, Message::deserialize(&message)?)))
+        .collect::<Result<Vec<_>>>();
+    edits
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    const DB_URL_TESTING: &str = "sqlite://testing_sqlite_a3b094/sqlite_test.db";
-    const DB_URL_DIR: &str = "testing_sqlite_a3b094"; // matches the db_url_testing
+    const DB_DIR: &str = "testing_sqlite_a3b094";
 
-    fn raw_query(db_url: &str, query: &str) {
-        let db = tokio_test::block_on(SqlitePool::connect(db_url)).unwrap();
-        tokio_test::block_on(sqlx::query(query).execute(&db)).unwrap();
-        tokio_test::block_on(db.close());
+    /// path for database of one test, nothing is there yet; tests run in parallel, so each of them has its own file
+    fn test_db_path(name: &str) -> std::path::PathBuf {
+        std::fs::create_dir_all(DB_DIR).unwrap();
+        let path = Path::new(DB_DIR).join(format!("{}.db", name));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        path
+    }
+
+    fn test_db(name: &str) -> SqlitePool {
+        tokio_test::block_on(open(&test_db_path(name))).unwrap()
+    }
+
+    fn raw_query(db: &SqlitePool, query: &str) {
+        tokio_test::block_on(sqlx::query(query).execute(db)).unwrap();
     }
 
     #[test]
     fn test_create_db() {
-        let file_path = DB_URL_TESTING.replace("sqlite://", "");
-        let path = std::path::Path::new(&file_path);
-        if path.exists() {
-            std::fs::remove_dir_all(DB_URL_DIR).unwrap();
+        let path = test_db_path("create_db");
+        let db = tokio_test::block_on(open(&path)).unwrap();
+        assert!(path.exists());
+        let (mode,): (String,) = tokio_test::block_on(sqlx::query_as("PRAGMA journal_mode").fetch_one(&db)).unwrap();
+        assert_eq!(mode, "wal");
+
+        // opening existing database keeps its content
+        let msg = Message::Text { from: "test user".into(), content: "message".into() };
+        tokio_test::block_on(insert_message(&db, "test user", DEFAULT_ROOM, &msg)).unwrap();
+        tokio_test::block_on(db.close());
+        let db = tokio_test::block_on(open(&path)).unwrap();
+        let messages = tokio_test::block_on(get_all_messages_priv(&db, &None, &None, None, None, None)).unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+
+    /// tables exactly as the first server version (`create_tables`) created them
+    async fn create_unversioned_tables(db: &SqlitePool) {
+        for table in [
+            "CREATE TABLE Messages (time INTEGER, client VARCHAR(250) NOT NULL, message blob NOT NULL);",
+            "CREATE TABLE LastOnline (time INTEGER, client VARCHAR(250) NOT NULL PRIMARY KEY);",
+        ] {
+            sqlx::query(table).execute(db).await.unwrap();
         }
-        std::fs::create_dir(DB_URL_DIR).unwrap();
+    }
 
-        tokio_test::block_on(create_if_needed(DB_URL_TESTING)).unwrap();
+    #[test]
+    fn test_unversioned_database_is_upgraded_without_data_loss() {
+        // setup - database of the first server version with some data
+        let path = test_db_path("upgrade");
+        let old = tokio_test::block_on(SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path).create_if_missing(true))).unwrap();
+        tokio_test::block_on(create_unversioned_tables(&old));
+        let msg = |content: &str| Message::Text { from: "test upgrade user".into(), content: content.into() };
+        let insert = |content: &str, time: i64| {
+            let query = sqlx::query("INSERT INTO Messages (time, client, message) VALUES (?, 'test upgrade user', ?);").bind(time).bind(msg(content).serialize().unwrap());
+            tokio_test::block_on(query.execute(&old)).unwrap().last_insert_rowid() as u64
+        };
+        let seen_time = 1000;
+        let seen = insert("seen", seen_time);
+        let removed = insert("removed", seen_time);
+        let missed = insert("missed", seen_time + 1000);
+        // a gap in rowids, the ids must not be renumbered
+        raw_query(&old, &format!("DELETE from Messages WHERE rowid = {};", removed));
+        // user last seen before the cursors existed
+        raw_query(&old, &format!("INSERT INTO LastOnline (time, client) VALUES ({}, 'test upgrade user2');", seen_time));
+        tokio_test::block_on(old.close());
 
-        assert!(path.exists());
+        // act
+        let db = tokio_test::block_on(open(&path)).unwrap();
+
+        // verify
+        let (version,): (i64,) = tokio_test::block_on(sqlx::query_as("SELECT max(version) from SchemaVersion").fetch_one(&db)).unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        let indexes: Vec<(String,)> = tokio_test::block_on(sqlx::query_as("SELECT name from sqlite_master WHERE type = 'index' and tbl_name = 'Messages' and sql is not null order by name").fetch_all(&db)).unwrap();
+        assert_eq!(indexes, vec![("Messages_client".to_string(),), ("Messages_time".to_string(),)]);
+
+        // rows keep their rowids as ids, in the same order, all in the default room
+        let messages = tokio_test::block_on(get_all_messages_priv(&db, &None, &None, None, None, None)).unwrap();
+        assert_eq!(messages.iter().map(|m| (m.id, m.message.clone())).collect::<Vec<_>>(), vec![(seen, msg("seen")), (missed, msg("missed"))]);
+        assert!(messages.iter().all(|m| m.room == DEFAULT_ROOM && !m.edited && !m.deleted && m.parent.is_none()));
+        let unsent = |user: &str| tokio_test::block_on(get_unsent_messages_priv(&db, user, DEFAULT_ROOM, 0)).unwrap().into_iter().map(|(id, ..)| id).collect::<Vec<_>>();
+        assert_eq!(unsent("test upgrade user2"), vec![missed]);
+        // messages stored before are in the search index
+        let found = tokio_test::block_on(search_messages(&db, "missed", None));
+        assert_eq!(found.iter().map(|m| m.id).collect::<Vec<_>>(), vec![missed]);
+        // new messages continue after the old ones, the other tables are there
+        let (id, _) = tokio_test::block_on(insert_message(&db, "test upgrade user", DEFAULT_ROOM, &msg("new"))).unwrap();
+        assert!(id > missed);
+        tokio_test::block_on(mark_written_priv(&db, id, "test upgrade user2", DEFAULT_ROOM)).unwrap();
+        assert!(tokio_test::block_on(create_user_priv(&db, "test upgrade user", "hash")).unwrap());
+        assert!(tokio_test::block_on(create_room_priv(&db, "test upgrade room", "test upgrade user")).unwrap());
+
+        // nothing is applied twice
+        tokio_test::block_on(db.close());
+        let db = tokio_test::block_on(open(&path)).unwrap();
+        let (count,): (i64,) = tokio_test::block_on(sqlx::query_as("SELECT count(*) from SchemaVersion").fetch_one(&db)).unwrap();
+        assert_eq!(count as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_database_of_any_unversioned_server_is_upgraded() {
+        let msg = Message::Text { from: "test upgrade user".into(), content: "old".into() };
+        // schemas of the server versions before versioning are exactly the first migrations
+        for applied in 1..=10 {
+            let path = test_db_path(&format!("upgrade_from_{}", applied));
+            let old = tokio_test::block_on(SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path).create_if_missing(true))).unwrap();
+            for statement in MIGRATIONS[..applied].iter().flat_map(|statements| statements.iter()) {
+                raw_query(&old, statement);
+            }
+            let query = sqlx::query("INSERT INTO Messages (time, client, message) VALUES (1000, 'test upgrade user', ?);").bind(msg.serialize().unwrap());
+            let id = tokio_test::block_on(query.execute(&old)).unwrap().last_insert_rowid() as u64;
+            tokio_test::block_on(old.close());
+
+            let db = tokio_test::block_on(open(&path)).unwrap_or_else(|e| panic!("schema of version {} was not upgraded: {:?}", applied, e));
+            let (count,): (i64,) = tokio_test::block_on(sqlx::query_as("SELECT count(*) from SchemaVersion").fetch_one(&db)).unwrap();
+            assert_eq!(count as usize, MIGRATIONS.len());
+            let messages = tokio_test::block_on(get_all_messages_priv(&db, &None, &None, None, None, None)).unwrap();
+            assert_eq!(messages.iter().map(|m| (m.id, m.message.clone())).collect::<Vec<_>>(), vec![(id, msg.clone())]);
+            tokio_test::block_on(insert_direct_message(&db, "test upgrade user", "test upgrade user2", &msg)).unwrap();
+            tokio_test::block_on(db.close());
+        }
+    }
 
-        println!("DB created at {}", file_path);
+    #[test]
+    fn test_newer_database_is_refused() {
+        let db = test_db("newer_schema");
+        raw_query(&db, &format!("INSERT INTO SchemaVersion (version, time) VALUES ({}, 0);", MIGRATIONS.len() + 1));
+        assert!(tokio_test::block_on(migrate(&db)).is_err());
     }
 
     #[test]
     fn test_insert_message() {
-        test_create_db();
+        let db = test_db("insert_message");
         let msg = Message::Text { from: "".into(), content: "message".into() };
         let msg2 = Message::File{ from: "".into(), name: "file".into(), content: "content".into()};
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user", &msg)).unwrap();
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user2", &msg2)).unwrap();
+        tokio_test::block_on(insert_message(&db, "test user", DEFAULT_ROOM, &msg)).unwrap();
+        tokio_test::block_on(insert_message(&db, "test user2", DEFAULT_ROOM, &msg2)).unwrap();
 
         println!("user inserted: test_user");
     }
@@ -259,18 +1293,18 @@ mod test {
     #[test]
     fn test_user_presence_update_for_some_users() {
         // setup
-        test_create_db();
-        raw_query(DB_URL_TESTING, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user');");
-        raw_query(DB_URL_TESTING, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user2');");
-        raw_query(DB_URL_TESTING, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user3');");
+        let db = test_db("user_presence_update_for_some_users");
+        raw_query(&db, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user');");
+        raw_query(&db, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user2');");
+        raw_query(&db, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user3');");
 
         // act
-        tokio_test::block_on(update_online_users_priv(DB_URL_TESTING, &["test user".into(), "test user3".into()])).unwrap();
+        tokio_test::block_on(update_online_users_priv(&db, &["test user".into(), "test user3".into()])).unwrap();
 
         // verify
-        let u1 = tokio_test::block_on(get_last_online_time(DB_URL_TESTING, "test user")).unwrap().unwrap();
-        let u2 = tokio_test::block_on(get_last_online_time(DB_URL_TESTING, "test user2")).unwrap().unwrap();
-        let u3 = tokio_test::block_on(get_last_online_time(DB_URL_TESTING, "test user3")).unwrap().unwrap();
+        let u1 = tokio_test::block_on(get_last_online_time(&db, "test user")).unwrap().unwrap();
+        let u2 = tokio_test::block_on(get_last_online_time(&db, "test user2")).unwrap().unwrap();
+        let u3 = tokio_test::block_on(get_last_online_time(&db, "test user3")).unwrap().unwrap();
         println!("Times: {} {} {}", u1, u2, u3);
         let expected_time_at_least = (SystemTime::now() - std::time::Duration::from_secs(10)).duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
 
@@ -282,15 +1316,15 @@ mod test {
     #[test]
     fn test_user_presence_update_works_even_for_nonexisting_user() {
         // setup
-        test_create_db();
-        raw_query(DB_URL_TESTING, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user');");
+        let db = test_db("user_presence_update_works_even_for_nonexisting_user");
+        raw_query(&db, "INSERT INTO LastOnline (time, client) VALUES (10, 'test user');");
 
         // act
-        tokio_test::block_on(update_online_users_priv(DB_URL_TESTING, &["test user2".into()])).unwrap();
+        tokio_test::block_on(update_online_users_priv(&db, &["test user2".into()])).unwrap();
 
         // verify
-        let u1 = tokio_test::block_on(get_last_online_time(DB_URL_TESTING, "test user")).unwrap().unwrap();
-        let u2 = tokio_test::block_on(get_last_online_time(DB_URL_TESTING, "test user2")).unwrap().unwrap();
+        let u1 = tokio_test::block_on(get_last_online_time(&db, "test user")).unwrap().unwrap();
+        let u2 = tokio_test::block_on(get_last_online_time(&db, "test user2")).unwrap().unwrap();
         println!("Times: {} {}", u1, u2);
         let expected_time_at_least = (SystemTime::now() - std::time::Duration::from_secs(10)).duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as i64;
         
@@ -299,49 +1333,381 @@ mod test {
     }
 
     #[test]
-    fn test_get_only_missing_messages_for_given_user() {
-        // setup - insert messages
-        test_create_db();
-        let msg_u1_t = Message::Text { from: "test user".into(), content: "message".into() };
-        let msg_u2_f = Message::File{ from: "test user2".into(), name: "file".into(), content: "content".into()};
-        let msg_u2_t1 = Message::Text { from: "test user2".into(), content: "another message".into()};
-        let msg_u2_t2 = Message::Text { from: "test user2".into(), content: "last message".into()};
-        let msg_u3_t1 = Message::Text { from: "test user3".into(), content: "user3 message".into()};
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user", &msg_u1_t)).unwrap();
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user2", &msg_u2_f)).unwrap();
-        // setup - update users
-        tokio_test::block_on(update_online_users_priv(DB_URL_TESTING, &["test user".into(), "test user2".into()])).unwrap();
-        // setup - wait and add another messages
-        std::thread::sleep(std::time::Duration::from_millis(50));
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user2", &msg_u2_t1)).unwrap();
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user2", &msg_u2_t2)).unwrap();
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user3", &msg_u3_t1)).unwrap();
+    fn test_unsent_messages_follow_cursor() {
+        let db = test_db("unsent_messages_follow_cursor");
+        let room = "test cursor room";
+        let msg = |user: &str, content: &str| Message::Text { from: user.into(), content: content.into() };
+        tokio_test::block_on(insert_message(&db, "test cursor user2", room, &msg("test cursor user2", "before"))).unwrap();
+        // the first look creates the cursor
+        assert!(tokio_test::block_on(get_unsent_messages_priv(&db, "test cursor user", room, 0)).unwrap().is_empty());
 
-        // act
-        let missing_messages = tokio_test::block_on(get_missing_messages_priv(DB_URL_TESTING, "test user")).unwrap();
+        let (first, _) = tokio_test::block_on(insert_message(&db, "test cursor user2", room, &msg("test cursor user2", "first"))).unwrap();
+        tokio_test::block_on(insert_message(&db, "test cursor user", room, &msg("test cursor user", "own"))).unwrap();
+        let (second, _) = tokio_test::block_on(insert_message(&db, "test cursor user3", room, &msg("test cursor user3", "second"))).unwrap();
+        tokio_test::block_on(insert_message(&db, "test cursor user2", DEFAULT_ROOM, &msg("test cursor user2", "other room"))).unwrap();
 
-        // verify
-        assert_eq!(missing_messages.len(), 3);
-        assert_eq!(&missing_messages[0], &msg_u2_t1);
-        assert_eq!(&missing_messages[1], &msg_u2_t2);
-        assert_eq!(&missing_messages[2], &msg_u3_t1);
+        let unsent = tokio_test::block_on(get_unsent_messages_priv(&db, "test cursor user", room, 0)).unwrap();
+        assert_eq!(unsent.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), vec![first, second]);
+        assert_eq!(unsent[0].2, room);
+        assert_eq!(unsent[1].3, msg("test cursor user3", "second"));
+
+        // only writing moves the cursor, and never back
+        tokio_test::block_on(mark_written_priv(&db, first, "test cursor user", room)).unwrap();
+        let unsent = tokio_test::block_on(get_unsent_messages_priv(&db, "test cursor user", room, 0)).unwrap();
+        assert_eq!(unsent.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), vec![second]);
+        tokio_test::block_on(mark_written_priv(&db, second, "test cursor user", room)).unwrap();
+        tokio_test::block_on(mark_written_priv(&db, first, "test cursor user", room)).unwrap();
+        assert!(tokio_test::block_on(get_unsent_messages_priv(&db, "test cursor user", room, 0)).unwrap().is_empty());
     }
 
     #[test]
-    fn test_missing_messages_are_empty_for_unknown_user() {
-        // setup - insert messages
-        test_create_db();
-        let msg_u1_t = Message::Text { from: "test user".into(), content: "message".into() };
-        let msg_u2_f = Message::File{ from: "test user2".into(), name: "file".into(), content: "content".into()};
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user", &msg_u1_t)).unwrap();
-        tokio_test::block_on(insert_message(DB_URL_TESTING, "test user2", &msg_u2_f)).unwrap();
-        // setup - update users
-        tokio_test::block_on(update_online_users_priv(DB_URL_TESTING, &["test user".into(), "test user2".into()])).unwrap();
+    fn test_unsent_messages_are_empty_for_new_user() {
+        let db = test_db("unsent_messages_are_empty_for_new_user");
+        let msg = Message::Text { from: "test user".into(), content: "message".into() };
+        tokio_test::block_on(insert_message(&db, "test user", DEFAULT_ROOM, &msg)).unwrap();
 
-        // act
-        let missing_messages = tokio_test::block_on(get_missing_messages_priv(DB_URL_TESTING, "test user3")).unwrap();
+        let unsent = tokio_test::block_on(get_unsent_messages_priv(&db, "test new user", DEFAULT_ROOM, 0)).unwrap();
+        assert!(unsent.is_empty());
+    }
+
+    #[test]
+    fn test_file_transfer_can_be_resumed_and_completed() {
+        let db = test_db("file_transfer_can_be_resumed_and_completed");
+        let content = vec![7u8; transfer::CHUNK_SIZE + 10];
+        let mut checksum = Checksum::new();
+        checksum.update(&content);
+        let checksum = checksum.finish();
+
+        // first attempt - only first chunk arrives
+        let (id, next_chunk) = tokio_test::block_on(start_file_transfer_priv(&db, "test transfer user", "file.bin", TransferKind::File, content.len() as u64, &checksum)).unwrap();
+        assert_eq!(next_chunk, 0);
+        tokio_test::block_on(insert_file_chunk(&db, id, 0, &content[..transfer::CHUNK_SIZE])).unwrap();
+        assert!(tokio_test::block_on(complete_file_transfer_priv(&db, "test transfer user", DEFAULT_ROOM, id)).is_err());
+
+        // second attempt continues where the first one ended
+        let (resumed_id, next_chunk) = tokio_test::block_on(start_file_transfer_priv(&db, "test transfer user", "file.bin", TransferKind::File, content.len() as u64, &checksum)).unwrap();
+        assert_eq!(resumed_id, id);
+        assert_eq!(next_chunk, 1);
+        tokio_test::block_on(insert_file_chunk(&db, id, 1, &content[transfer::CHUNK_SIZE..])).unwrap();
 
         // verify
-        assert!(missing_messages.is_empty());
+        assert!(tokio_test::block_on(complete_file_transfer_priv(&db, "test transfer user2", DEFAULT_ROOM, id)).is_err());
+        tokio_test::block_on(complete_file_transfer_priv(&db, "test transfer user", DEFAULT_ROOM, id)).unwrap();
+        let (name, kind, stored) = tokio_test::block_on(get_file_content_priv(&db, id)).unwrap().unwrap();
+        assert_eq!(name, "file.bin");
+        assert_eq!(kind, TransferKind::File);
+        assert_eq!(stored, content);
+        let messages = tokio_test::block_on(get_all_messages_priv(&db, &Some("test transfer user".into()), &None, None, None, None)).unwrap();
+        assert!(messages.iter().any(|record| matches!(&record.message, Message::FileOffer { transfer_id: Some(stored_id), .. } if *stored_id == id)));
+    }
+
+    #[test]
+    fn test_file_transfer_resumes_at_first_missing_chunk() {
+        let db = test_db("file_transfer_resumes_at_first_missing_chunk");
+        let size = 4 * transfer::CHUNK_SIZE as u64;
+        let (id, _) = tokio_test::block_on(start_file_transfer_priv(&db, "test transfer user", "file.bin", TransferKind::File, size, "checksum")).unwrap();
+        let next_chunk = || tokio_test::block_on(start_file_transfer_priv(&db, "test transfer user", "file.bin", TransferKind::File, size, "checksum")).unwrap().1;
+        tokio_test::block_on(insert_file_chunk(&db, id, 1, b"1")).unwrap();
+        assert_eq!(next_chunk(), 0);
+        tokio_test::block_on(insert_file_chunk(&db, id, 0, b"0")).unwrap();
+        tokio_test::block_on(insert_file_chunk(&db, id, 3, b"3")).unwrap();
+        // chunk 2 was lost, it has to be sent again
+        assert_eq!(next_chunk(), 2);
+        tokio_test::block_on(insert_file_chunk(&db, id, 2, b"2")).unwrap();
+        assert_eq!(next_chunk(), 4);
+    }
+
+    #[test]
+    fn test_file_transfer_with_wrong_checksum_is_not_completed() {
+        let db = test_db("file_transfer_with_wrong_checksum_is_not_completed");
+        let (id, _) = tokio_test::block_on(start_file_transfer_priv(&db, "test user", "file.bin", TransferKind::Image, 3, "bad checksum")).unwrap();
+        tokio_test::block_on(insert_file_chunk(&db, id, 0, b"abc")).unwrap();
+
+        assert!(tokio_test::block_on(complete_file_transfer_priv(&db, "test user", DEFAULT_ROOM, id)).is_err());
+        assert!(tokio_test::block_on(get_file_content_priv(&db, id)).unwrap().is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_delivery_state_is_tracked_per_recipient() {
+        let db = test_db("delivery_state_is_tracked_per_recipient");
+        let msg = Message::Text { from: "test delivery user".into(), content: "delivered?".into() };
+        let (id, _) = tokio_test::block_on(insert_message(&db, "test delivery user", DEFAULT_ROOM, &msg)).unwrap();
+        let (next_id, _) = tokio_test::block_on(insert_message(&db, "test delivery user", DEFAULT_ROOM, &msg)).unwrap();
+        assert!(next_id > id);
+
+        let recipients = vec!["test delivery user2".to_string(), "test delivery user3".to_string()];
+        tokio_test::block_on(mark_delivery_priv(&db, id, &recipients, DELIVERY_SENT)).unwrap();
+        tokio_test::block_on(mark_delivery_priv(&db, id, &recipients[..1], DELIVERY_RECEIVED)).unwrap();
+        // late "sent" doesn't downgrade the state
+        tokio_test::block_on(mark_delivery_priv(&db, id, &recipients[..1], DELIVERY_SENT)).unwrap();
+
+        let messages = tokio_test::block_on(get_all_messages_priv(&db, &Some("test delivery user".into()), &None, None, None, None)).unwrap();
+        let record = messages.iter().find(|record| record.id == id).unwrap();
+        assert_eq!(record.received_by, vec!["test delivery user2".to_string()]);
+    }
+
+    #[test]
+    fn test_user_can_be_registered_only_once() {
+        let db = test_db("user_can_be_registered_only_once");
+        tokio_test::block_on(forget_user_priv(&db, "test registered user".into())).unwrap();
+        assert_eq!(tokio_test::block_on(get_password_hash_priv(&db, "test registered user")).unwrap(), None);
+
+        assert!(tokio_test::block_on(create_user_priv(&db, "test registered user", "hash")).unwrap());
+        assert!(!tokio_test::block_on(create_user_priv(&db, "test registered user", "other hash")).unwrap());
+
+        let hash = tokio_test::block_on(get_password_hash_priv(&db, "test registered user")).unwrap();
+        assert_eq!(hash, Some("hash".to_string()));
+    }
+
+    #[test]
+    fn test_room_can_be_created_only_once() {
+        let db = test_db("room_can_be_created_only_once");
+        raw_query(&db, "DELETE from Rooms WHERE name = 'test room';");
+
+        assert!(tokio_test::block_on(create_room_priv(&db, "test room", "test user")).unwrap());
+        assert!(!tokio_test::block_on(create_room_priv(&db, "test room", "test user2")).unwrap());
+        assert!(!tokio_test::block_on(create_room_priv(&db, DEFAULT_ROOM, "test user")).unwrap());
+
+        let rooms = tokio_test::block_on(get_rooms_priv(&db)).unwrap();
+        assert_eq!(rooms[0], DEFAULT_ROOM);
+        assert!(rooms.contains(&"test room".to_string()));
+    }
+
+    #[test]
+    fn test_room_history_without_cursor_is_limited() {
+        let db = test_db("room_history_without_cursor_is_limited");
+        let room = "test history room";
+        let msg = |content: &str| Message::Text { from: "test history user".into(), content: content.into() };
+        tokio_test::block_on(insert_message(&db, "test history user", room, &msg("oldest"))).unwrap();
+        let (first, _) = tokio_test::block_on(insert_message(&db, "test history user", room, &msg("first"))).unwrap();
+        tokio_test::block_on(insert_message(&db, "test history user", DEFAULT_ROOM, &msg("other room"))).unwrap();
+        tokio_test::block_on(insert_message(&db, "test history reader", room, &msg("own"))).unwrap();
+        let (second, _) = tokio_test::block_on(insert_message(&db, "test history user", room, &msg("second"))).unwrap();
+
+        let history = tokio_test::block_on(get_unsent_messages_priv(&db, "test history reader", room, 2)).unwrap();
+        let ids: Vec<_> = history.iter().map(|(id, ..)| *id).collect();
+        assert_eq!(ids, vec![first, second]);
+        // the cursor starts after the history
+        assert!(tokio_test::block_on(get_unsent_messages_priv(&db, "test history reader", room, 2)).unwrap().is_empty());
+
+        let messages = tokio_test::block_on(get_all_messages_priv(&db, &None, &Some(room.into()), None, None, None)).unwrap();
+        assert!(messages.iter().all(|record| record.room == room));
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn test_direct_message_waits_until_written() {
+        let db = test_db("direct_message_waits_until_written");
+        // cursors exist, the users were connected before
+        tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user2", DIRECT_MESSAGES_ROOM, 0)).unwrap();
+        tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user3", DIRECT_MESSAGES_ROOM, 0)).unwrap();
+        let msg = Message::Direct { from: "test dm user".into(), to: "test dm user2".into(), content: "psst".into() };
+        let (id, _) = tokio_test::block_on(insert_direct_message(&db, "test dm user", "test dm user2", &msg)).unwrap();
+        tokio_test::block_on(insert_message(&db, "test dm user", DEFAULT_ROOM, &Message::Text { from: "test dm user".into(), content: "public".into() })).unwrap();
+
+        let pending = tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user2", DIRECT_MESSAGES_ROOM, 0)).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].0, &pending[0].3), (id, &msg));
+        assert!(tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user3", DIRECT_MESSAGES_ROOM, 0)).unwrap().is_empty());
+        // not part of any room
+        let history = tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user2", DEFAULT_ROOM, 100)).unwrap();
+        assert!(history.iter().all(|(history_id, ..)| *history_id != id));
+
+        tokio_test::block_on(mark_written_priv(&db, id, "test dm user2", DIRECT_MESSAGES_ROOM)).unwrap();
+        assert!(tokio_test::block_on(get_unsent_messages_priv(&db, "test dm user2", DIRECT_MESSAGES_ROOM, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_messages_after_last_received() {
+        let db = test_db("messages_after_last_received");
+        let room = "test resume room";
+        let msg = |content: &str| Message::Text { from: "test resume user".into(), content: content.into() };
+        let (received, _) = tokio_test::block_on(insert_message(&db, "test resume user", room, &msg("received"))).unwrap();
+        // sent, but lost with the connection
+        let (lost, _) = tokio_test::block_on(insert_message(&db, "test resume user", room, &msg("lost"))).unwrap();
+        tokio_test::block_on(mark_written_priv(&db, lost, "test resume reader", room)).unwrap();
+        tokio_test::block_on(insert_message(&db, "test resume user", DEFAULT_ROOM, &msg("other room"))).unwrap();
+        tokio_test::block_on(insert_message(&db, "test resume reader", room, &msg("own"))).unwrap();
+        let direct = Message::Direct { from: "test resume user".into(), to: "test resume reader".into(), content: "psst".into() };
+        let (direct_id, _) = tokio_test::block_on(insert_direct_message(&db, "test resume user", "test resume reader", &direct)).unwrap();
+        tokio_test::block_on(insert_direct_message(&db, "test resume user", "test resume other", &direct)).unwrap();
+
+        let messages = tokio_test::block_on(get_messages_after_priv(&db, "test resume reader", room, received)).unwrap();
+        let ids: Vec<_> = messages.iter().map(|(id, ..)| *id).collect();
+        assert_eq!(ids, vec![lost, direct_id]);
+        assert_eq!(messages[0].2, room);
+        assert_eq!(messages[1].3, direct);
+
+        // broken message is an error, not a panic of the db actor
+        raw_query(&db, &format!("INSERT INTO Messages (time, client, message, room) VALUES (0, 'test resume user', x'ff', '{}');", room));
+        assert!(tokio_test::block_on(get_messages_after_priv(&db, "test resume reader", room, received)).is_err());
+    }
+
+    #[test]
+    fn test_broken_message_in_listing_is_error() {
+        let db = test_db("broken_message_in_listing_is_error");
+        let room = "test broken listing room";
+        let msg = Message::Text { from: "test broken listing user".into(), content: "fine".into() };
+        tokio_test::block_on(insert_message(&db, "test broken listing user", room, &msg)).unwrap();
+        let room_filter = Some(room.to_string());
+        assert_eq!(tokio_test::block_on(get_all_messages_priv(&db, &None, &room_filter, None, None, None)).unwrap().len(), 1);
+
+        // broken message is an error, not a panic of the web handler
+        raw_query(&db, &format!("INSERT INTO Messages (time, client, message, room) VALUES (0, 'test broken listing user', x'ff', '{}');", room));
+        assert!(tokio_test::block_on(get_all_messages_priv(&db, &None, &room_filter, None, None, None)).is_err());
+        assert!(tokio_test::block_on(get_all_messages(&db, None, room_filter)).is_empty());
+    }
+
+    #[test]
+    fn test_direct_messages_for_user_without_cursor() {
+        let db = test_db("direct_messages_for_user_without_cursor");
+        // registered user that never connected gets all private messages
+        let msg = Message::Direct { from: "test dm sender".into(), to: "test dm newbie".into(), content: "welcome".into() };
+        let (id, _) = tokio_test::block_on(insert_direct_message(&db, "test dm sender", "test dm newbie", &msg)).unwrap();
+        let pending = tokio_test::block_on(get_unsent_messages_priv(&db, "test dm newbie", DIRECT_MESSAGES_ROOM, u32::MAX)).unwrap();
+        assert_eq!(pending.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), vec![id]);
+    }
+
+    #[test]
+    fn test_only_author_can_edit_and_delete_message() {
+        let db = test_db("only_author_can_edit_and_delete_message");
+        let msg = Message::Text { from: "test edit user".into(), content: "helo".into() };
+        let (id, _) = tokio_test::block_on(insert_message(&db, "test edit user", DEFAULT_ROOM, &msg)).unwrap();
+
+        assert!(tokio_test::block_on(edit_message_priv(&db, "test edit user2", id, "hacked")).unwrap().is_err());
+        assert!(tokio_test::block_on(delete_message_priv(&db, "test edit user2", id)).unwrap().is_err());
+        assert!(tokio_test::block_on(edit_message_priv(&db, "test edit user", id + 1000, "hello")).unwrap().is_err());
+
+        let change = tokio_test::block_on(edit_message_priv(&db, "test edit user", id, "hello")).unwrap().unwrap();
+        assert_eq!((change.room.as_str(), change.recipient), (DEFAULT_ROOM, None));
+        let edits = tokio_test::block_on(get_message_edits(&db, id)).unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].1, msg);
+
+        let all = tokio_test::block_on(get_all_messages_priv(&db, &Some("test edit user".into()), &None, None, None, None)).unwrap();
+        assert_eq!(all[0].message, Message::Text { from: "test edit user".into(), content: "hello".into() });
+        assert!(all[0].edited && !all[0].deleted);
+
+        tokio_test::block_on(delete_message_priv(&db, "test edit user", id)).unwrap().unwrap();
+        assert!(tokio_test::block_on(edit_message_priv(&db, "test edit user", id, "again")).unwrap().is_err());
+        let all = tokio_test::block_on(get_all_messages_priv(&db, &Some("test edit user".into()), &None, None, None, None)).unwrap();
+        assert!(all[0].deleted);
+        // deleted message is not replayed
+        let history = tokio_test::block_on(get_unsent_messages_priv(&db, "test edit user2", DEFAULT_ROOM, 100)).unwrap();
+        assert!(history.iter().all(|(history_id, ..)| *history_id != id));
+    }
+
+    #[test]
+    fn test_failed_edit_or_delete_changes_nothing() {
+        let db = test_db("failed_edit_or_delete_changes_nothing");
+        let msg = Message::Text { from: "test edit user".into(), content: "helo".into() };
+        let (id, _) = tokio_test::block_on(insert_message(&db, "test edit user", DEFAULT_ROOM, &msg)).unwrap();
+        // the search index is updated last
+        raw_query(&db, "DROP TABLE MessagesSearch;");
+
+        assert!(tokio_test::block_on(edit_message_priv(&db, "test edit user", id, "hello")).is_err());
+        assert!(tokio_test::block_on(get_message_edits(&db, id)).unwrap().is_empty());
+        assert!(tokio_test::block_on(delete_message_priv(&db, "test edit user", id)).is_err());
+        let row = tokio_test::block_on(get_own_message(&db, "test edit user", id)).unwrap().unwrap();
+        assert_eq!(Message::deserialize(&row.message).unwrap(), msg);
+        assert!(row.edited.is_none() && row.deleted.is_none());
+    }
+
+    #[test]
+    fn test_reaction_is_toggled_and_counted() {
+        let db = test_db("reaction_is_toggled_and_counted");
+        let msg = Message::Text { from: "test reaction user".into(), content: "lunch?".into() };
+        let (id, _) = tokio_test::block_on(insert_message(&db, "test reaction user", DEFAULT_ROOM, &msg)).unwrap();
+
+        tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user2", id, "👍")).unwrap().unwrap();
+        tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user3", id, "👍")).unwrap().unwrap();
+        let (change, reactions) = tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user3", id, "🍕")).unwrap().unwrap();
+        assert_eq!(change.room, DEFAULT_ROOM);
+        assert_eq!(reactions, vec![("👍".to_string(), 2), ("🍕".to_string(), 1)]);
+
+        // second time the reaction is removed
+        let (_, reactions) = tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user2", id, "👍")).unwrap().unwrap();
+        assert_eq!(reactions, vec![("👍".to_string(), 1), ("🍕".to_string(), 1)]);
+
+        let all = tokio_test::block_on(get_all_messages_priv(&db, &Some("test reaction user".into()), &None, None, None, None)).unwrap();
+        assert_eq!(all[0].reactions, reactions);
+
+        assert!(tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user2", id + 1000, "👍")).unwrap().is_err());
+        let dm = Message::Direct { from: "test reaction user".into(), to: "test reaction user2".into(), content: "psst".into() };
+        let (dm_id, _) = tokio_test::block_on(insert_direct_message(&db, "test reaction user", "test reaction user2", &dm)).unwrap();
+        assert!(tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user3", dm_id, "👀")).unwrap().is_err());
+        assert!(tokio_test::block_on(toggle_reaction_priv(&db, "test reaction user2", dm_id, "👀")).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_thread_contains_root_and_all_replies() {
+        let db = test_db("thread_contains_root_and_all_replies");
+        let text = |content: &str| Message::Text { from: "test thread user".into(), content: content.into() };
+        let room = "test thread room";
+        let (root, _) = tokio_test::block_on(insert_message(&db, "test thread user", room, &text("root"))).unwrap();
+        let (other, _) = tokio_test::block_on(insert_message(&db, "test thread user", room, &text("unrelated"))).unwrap();
+        let reply = Message::Reply { parent: root, message: Box::new(text("reply")) };
+        let (reply_id, _) = tokio_test::block_on(insert_message(&db, "test thread user", room, &reply)).unwrap();
+        let nested = Message::Reply { parent: reply_id, message: Box::new(text("nested")) };
+        let (nested_id, _) = tokio_test::block_on(insert_message(&db, "test thread user", room, &nested)).unwrap();
+
+        assert!(tokio_test::block_on(check_parent_priv(&db, root, room)).unwrap());
+        assert!(!tokio_test::block_on(check_parent_priv(&db, root, DEFAULT_ROOM)).unwrap());
+        assert!(!tokio_test::block_on(check_parent_priv(&db, nested_id + 1000, room)).unwrap());
+
+        // any message of the thread gives the whole thread
+        for id in [root, reply_id, nested_id] {
+            let thread = tokio_test::block_on(get_all_messages_priv(&db, &None, &None, Some(id as i64), None, None)).unwrap();
+            assert_eq!(thread.iter().map(|m| m.id).collect::<Vec<_>>(), vec![root, reply_id, nested_id]);
+            assert_eq!(thread[2].parent, Some(reply_id));
+            assert_eq!(thread[2].message, text("nested"));
+        }
+        let thread = tokio_test::block_on(get_all_messages_priv(&db, &None, &None, Some(other as i64), None, None)).unwrap();
+        assert_eq!(thread.len(), 1);
+
+        // replays get the reply back
+        let history = tokio_test::block_on(get_unsent_messages_priv(&db, "test thread reader", room, 100)).unwrap();
+        assert_eq!(history.last().unwrap().3, nested);
+    }
+
+    #[test]
+    fn test_search_finds_words_in_texts_and_file_names() {
+        let db = test_db("search");
+        let text = |content: &str| Message::Text { from: "test search user".into(), content: content.into() };
+        let (build, _) = tokio_test::block_on(insert_message(&db, "test search user", DEFAULT_ROOM, &text("Vyčistit build cache před vydáním"))).unwrap();
+        let (other, _) = tokio_test::block_on(insert_message(&db, "test search user", DEFAULT_ROOM, &text("nothing to see here"))).unwrap();
+        let file = Message::File { from: "test search user".into(), name: "build-log.txt".into(), content: vec![1] };
+        let (file, _) = tokio_test::block_on(insert_message(&db, "test search user", DEFAULT_ROOM, &file)).unwrap();
+        let direct = Message::Direct { from: "test search user".into(), to: "test search user2".into(), content: "private build notes".into() };
+        let (direct, _) = tokio_test::block_on(insert_direct_message(&db, "test search user", "test search user2", &direct)).unwrap();
+        let ids = |terms: &str, user: Option<&str>| tokio_test::block_on(search_messages(&db, terms, user)).into_iter().map(|m| m.id).collect::<Vec<_>>();
+
+        // every word as a prefix, case and diacritics don't matter
+        assert_eq!(ids("BUILD cach", None), vec![build]);
+        assert_eq!(ids("vycistit", None), vec![build]);
+        assert_eq!(ids("build", None), vec![build, file, direct]);
+        // private messages only for the two users
+        assert_eq!(ids("build", Some("test search user3")), vec![build, file]);
+        assert_eq!(ids("build", Some("test search user2")), vec![build, file, direct]);
+        // operators and quotes typed by user are just words
+        assert!(ids("build OR nothing", None).is_empty());
+        assert_eq!(ids("\"cache", None), vec![build]);
+        assert!(ids("  ", None).is_empty());
+
+        // matches the user can't see don't take places of the visible ones
+        let public = Message::Text { from: "test search user".into(), content: "secret recipe, shared with everyone in the whole room".into() };
+        let (public, _) = tokio_test::block_on(insert_message(&db, "test search user", DEFAULT_ROOM, &public)).unwrap();
+        let hidden = Message::Direct { from: "test search user".into(), to: "test search user2".into(), content: "secret secret".into() };
+        for _ in 0..SEARCH_LIMIT {
+            tokio_test::block_on(insert_direct_message(&db, "test search user", "test search user2", &hidden)).unwrap();
+        }
+        assert_eq!(ids("secret", Some("test search user3")), vec![public]);
+        assert_eq!(ids("secret", None).len(), SEARCH_LIMIT as usize);
+
+        // edits and deletes are reflected
+        tokio_test::block_on(edit_message_priv(&db, "test search user", other, "now about cache")).unwrap().unwrap();
+        assert_eq!(ids("cache", None), vec![build, other]);
+        tokio_test::block_on(delete_message_priv(&db, "test search user", build)).unwrap().unwrap();
+        assert_eq!(ids("cache", None), vec![other]);
+    }
+}
@@ -2,20 +2,32 @@
 #[macro_use] extern crate rocket_include_static_resources;
 
 mod metrics;
+mod auth;
 mod db;
 mod actor_connected_clients;
 mod actor_db;
+mod outbound;
 mod web;
 
 use clap::Parser;
-use shared::{Message, chaos};
-use tokio::net::tcp::{OwnedWriteHalf, OwnedReadHalf};
+use shared::{Message, Capabilities, handshake, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use shared::fault::FaultInjector;
+use shared::frame::WireFormat;
+use shared::framed::{self, MessageReader, MessageWriter};
+use shared::tls::{self, StreamReader, StreamWriter, TlsAcceptor};
 use log::{info, warn, error};
 use shared::ReceiveMessageError::*;
 use anyhow::{Result, Context};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use ractor::{Actor, ActorRef};
-use actor_connected_clients::ConnectedClientsActorMessage;
+use actor_connected_clients::{ConnectedClientsActorMessage, NameReservation};
+use actor_db::DbMessage;
+use outbound::SlowClientPolicy;
+use tokio_util::sync::CancellationToken;
 
 // looks like common code for client and server, but this is not typical dry sample
 #[derive(Parser)]
@@ -24,56 +36,118 @@ struct ListenerArgs {
     port: u16,
     #[arg(short = 's', long, default_value = "localhost")]
     host: String,
+    /// biggest message (in bytes) accepted from clients; clients sending bigger ones are disconnected
+    #[arg(long, default_value_t = shared::DEFAULT_MAX_FRAME_SIZE)]
+    max_frame_size: usize,
+    /// certificate chain (PEM); clients are accepted only over TLS when set
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// private key (PEM) of the certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// generates self-signed certificate for localhost to `--tls-cert`/`--tls-key` if they don't exist (development only)
+    #[arg(long, requires = "tls_cert")]
+    generate_dev_cert: bool,
+    /// clients may connect without password, unless they use name of a registered user
+    #[arg(long)]
+    allow_anonymous: bool,
+    /// seconds a client (supporting heartbeats) may be silent before it's disconnected
+    #[arg(long, default_value_t = shared::DEFAULT_IDLE_TIMEOUT_SECS)]
+    idle_timeout: u64,
+    /// seconds a new connection has for TLS, hello and login; it's closed afterwards
+    #[arg(long, default_value_t = 10)]
+    handshake_timeout: u64,
+    /// how many messages may wait to be written to one client
+    #[arg(long, default_value_t = 256)]
+    outbound_queue_size: usize,
+    /// what to do with a client whose queue is full
+    #[arg(long, value_enum, default_value_t = SlowClientPolicy::Disconnect)]
+    slow_client_policy: SlowClientPolicy,
+    /// told to clients when the server is stopped (e.g. "back in 5 minutes")
+    #[arg(long)]
+    shutdown_reason: Option<String>,
+    /// sqlite database file; created if it doesn't exist
+    #[arg(long, default_value = "sqlite.db")]
+    db: PathBuf,
+}
+
+/// returns `None` when TLS is not configured
+fn tls_acceptor(args: &ListenerArgs) -> Result<Option<TlsAcceptor>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+    if args.generate_dev_cert && !cert.exists() && !key.exists() {
+        warn!("Generating self-signed development certificate {}", cert.display());
+        tls::write_dev_certificate(cert, key)?;
+    }
+    let acceptor = tls::acceptor(cert, key).context("Unable to load TLS certificate")?;
+    info!("TLS enabled, certificate {}", cert.display());
+    Ok(Some(acceptor))
 }
 
 #[rocket::main]
 async fn main() -> Result<()> {
     shared::logging::init();
-    if chaos::enabled() {
-        warn!("Chaos monkey is enabled");
-    }
+    let faults = FaultInjector::from_env().map_err(|e| anyhow::anyhow!(e))?;
 
     metrics::init();
 
-    db::ensure_db_exists().await?;
-
     let args = ListenerArgs::parse();
+    let tls_acceptor = tls_acceptor(&args)?;
     info!("Listening on {}:{}", args.host, args.port);
 
     let listener = TcpListener::bind(format!("{}:{}", args.host, args.port))
                             .await
                             .context("Unable to create listener. Is there any other instance running?")?;
 
-    let (db_actor, _db_actor_handle) = 
-        Actor::spawn(Some("actor_db".to_string()), actor_db::DbAccessActor, ())
+    let (db_actor, db_actor_handle) = 
+        Actor::spawn(Some("actor_db".to_string()), actor_db::DbAccessActor, args.db.clone())
             .await
-            .expect("Failed to start actor with access to db");
+            .context("Failed to start actor with access to db")?;
 
     let (connected_cli_actor, _connected_cli_actor_handle) = 
-        Actor::spawn(Some("actor_clients".to_string()), actor_connected_clients::ConnectedClientsActor{db: db_actor.clone()}, ())
+        Actor::spawn(Some("actor_clients".to_string()), actor_connected_clients::ConnectedClientsActor{db: db_actor.clone(), queue_size: args.outbound_queue_size, slow_client_policy: args.slow_client_policy}, ())
             .await
             .expect("Failed to start actor with connected clients");
 
-    tokio::spawn(async move {
-        web::rocket(db_actor.clone()).launch().await.unwrap();
+    let web_db_actor = db_actor.clone();
+    let web_clients_actor = connected_cli_actor.clone();
+    let web = web::rocket(web_db_actor, web_clients_actor).ignite().await?;
+    let web_shutdown = web.shutdown();
+    let web_handle = tokio::spawn(async move {
+        if let Err(e) = web.launch().await {
+            error!("Web server failed: {}", e);
+        }
         info!("Web server has exited..")
     });
-                                                            
+
+    let mut handshakes = Handshake {
+        tasks: JoinSet::new(),
+        tls_acceptor,
+        faults,
+        clients: connected_cli_actor.clone(),
+        db: db_actor.clone(),
+        max_frame_size: args.max_frame_size,
+        allow_anonymous: args.allow_anonymous,
+        timeout: Duration::from_secs(args.handshake_timeout),
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+    };
+
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            signal = &mut signal => {
+                info!("Received {}, shutting down", signal);
+                break;
+            },
+            accepted = listener.accept() => accepted,
+            _ = handshakes.finished() => continue,
+        };
+        match accepted {
             Ok((stream, addr)) => {
                 info!("New connection from {}", addr);
-
-                let Some((user_name, stream_reader, stream_writer)) = try_process_new_user(stream, &connected_cli_actor).await else {
-                    continue;
-                };
-
-                metrics::users_up();
-                
-                // register new client; it's stored with other clients so that it's possible to broadcast the incomming message
-                connected_cli_actor.cast(ConnectedClientsActorMessage::NewClient{user_name: user_name.to_string(), stream_writer}).unwrap();
-                
-                spawn_new_task_handling_one_client(user_name, stream_reader, connected_cli_actor.clone());
+                handshakes.spawn(stream, addr);
             }
             Err(e) => { 
                 error!("Encountered IO error: {}. Skipping the new connection attempt.", e);
@@ -81,44 +155,288 @@ async fn main() -> Result<()> {
             }
         }
     }
+
+    // no new clients from now on
+    drop(listener);
+    // note: clients in handshake wouldn't be told about the shutdown, or would connect to stopped actor
+    handshakes.cancel().await;
+    // the web uses both actors, so it goes first; db actor is the last one, the others write to it
+    web_shutdown.notify();
+    if let Err(e) = web_handle.await {
+        error!("Web server task failed: {}", e);
+    }
+    ractor::call!(connected_cli_actor, ConnectedClientsActorMessage::Shutdown, args.shutdown_reason.clone())
+        .context("Unable to disconnect clients")?;
+    connected_cli_actor.stop_and_wait(None, None).await?;
+    // note: stopping drops messages waiting in the mailbox, e.g. last seen of the clients
+    ractor::call!(db_actor, DbMessage::Flush).context("Unable to finish db writes")?;
+    db_actor.stop_and_wait(None, None).await?;
+    // note: stop_and_wait returns before post_stop, which closes the db
+    db_actor_handle.await?;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// waits for ctrl+c (SIGINT) or SIGTERM; returns name of the signal
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Unable to listen for ctrl+c: {}", e);
+            std::future::pending::<()>().await;
+        }
+        "ctrl+c"
+    }
+}
+
+/// everything needed to connect a new client
+struct Handshake {
+    /// running handshakes
+    tasks: JoinSet<()>,
+    tls_acceptor: Option<TlsAcceptor>,
+    faults: Option<FaultInjector>,
+    clients: ActorRef<ConnectedClientsActorMessage>,
+    db: ActorRef<DbMessage>,
+    max_frame_size: usize,
+    allow_anonymous: bool,
+    /// the whole handshake has to be done in this time
+    timeout: Duration,
+    /// see `spawn_new_task_handling_one_client`
+    idle_timeout: Duration,
+}
+
+impl Handshake {
+    /// connects the client in its own task, so that slow (or silent) client doesn't hold up the others
+    fn spawn(&mut self, stream: TcpStream, addr: SocketAddr) {
+        let (tls_acceptor, faults) = (self.tls_acceptor.clone(), self.faults.clone());
+        let (clients, db) = (self.clients.clone(), self.db.clone());
+        let (max_frame_size, allow_anonymous, timeout, idle_timeout) = (self.max_frame_size, self.allow_anonymous, self.timeout, self.idle_timeout);
+        self.tasks.spawn(async move {
+            let handshake = try_process_new_user(stream, tls_acceptor.as_ref(), faults.as_ref(), &clients, &db, max_frame_size, allow_anonymous);
+            let (client, reservation, stream_reader, stream_writer) = match tokio::time::timeout(timeout, handshake).await {
+                Ok(Some(connected)) => connected,
+                Ok(None) => return,
+                Err(_) => {
+                    // note: dropping the handshake releases the name it has reserved
+                    error!("Handshake with {} not finished in {:?}, closing connection", addr, timeout);
+                    return;
+                },
+            };
+
+            metrics::users_up();
+
+            // register new client; it's stored with other clients so that it's possible to broadcast the incomming message
+            let reader = CancellationToken::new();
+            reservation.connected();
+            if let Err(e) = clients.cast(ConnectedClientsActorMessage::NewClient{user_name: client.user_name.to_string(), capabilities: client.capabilities, resume_after: client.resume_after, stream_writer, reader: reader.clone()}) {
+                error!("Unable to register client {}: {}", client.user_name, e);
+                return;
+            }
+
+            // note: nothing is awaited after NewClient, so the cancelled handshake either registered the client or not at all
+            spawn_new_task_handling_one_client(client, stream_reader, reader, clients, idle_timeout);
+        });
+    }
+
+    /// waits until some handshake ends (forever if none is running); finished tasks have to be collected, otherwise they're kept
+    async fn finished(&mut self) {
+        match self.tasks.join_next().await {
+            Some(Err(e)) if e.is_panic() => error!("Handshake task failed: {}", e),
+            Some(_) => {},
+            None => std::future::pending().await,
+        }
+    }
+
+    /// stops all running handshakes (their connections are closed, reserved names released) and waits for them
+    async fn cancel(&mut self) {
+        if !self.tasks.is_empty() {
+            info!("Cancelling {} handshakes", self.tasks.len());
+        }
+        self.tasks.shutdown().await;
+    }
+}
+
+/// what we know about the client after successful handshake
+pub struct ClientInfo {
+    pub user_name: String,
+    pub version: u16,
+    /// features supported by both the client and the server
+    pub capabilities: Capabilities,
+    /// the last stored message the client received (reconnecting client), see `Message::Resume`
+    pub resume_after: Option<u64>,
 }
 
 // makes first contact with client and checks whether the client can be connected
 //
-// the client can not be connected if there is any other already connected client with the same name
-async fn try_process_new_user(stream: TcpStream, actor: &ActorRef<ConnectedClientsActorMessage>) -> Option<(String, OwnedReadHalf, OwnedWriteHalf)> {
+// the client can not be connected if 
+// - there is any other already connected client (or client in handshake) with the same name
+// - it speaks incompatible version of the protocol
+// - TLS is required and the client fails the TLS handshake
+// - it fails to log in (or register), see `authenticate`
+async fn try_process_new_user(stream: TcpStream, tls_acceptor: Option<&TlsAcceptor>, faults: Option<&FaultInjector>, actor: &ActorRef<ConnectedClientsActorMessage>, db: &ActorRef<DbMessage>, max_frame_size: usize, allow_anonymous: bool) -> Option<(ClientInfo, NameReservation, MessageReader, MessageWriter)> {
+
+    async fn refuse<T>(stream_writer: &mut StreamWriter, reason: String) -> Result<Option<T>> {
+        error!("Refusing client: {}", reason);
+        if let Err(e) = (Message::ServerRefused { reason }).send(stream_writer, WireFormat::HELLO).await {
+            error!("Error when sending refusal: {}", e);
+        }
+        Ok(None)
+    }
 
     // checks whether the user that is trying to register on server, can be connected
-    async fn try_user_handshake(stream_reader: &mut OwnedReadHalf, stream_writer: &mut OwnedWriteHalf, actor: &ActorRef<ConnectedClientsActorMessage>) -> Result<Option<String>>  {
-        let hello_message = Message::receive(stream_reader).await;
-        let Ok(Message::ClientHello {from: user }) =  hello_message else  {
+    async fn try_user_handshake(stream_reader: &mut StreamReader, stream_writer: &mut StreamWriter, actor: &ActorRef<ConnectedClientsActorMessage>, db: &ActorRef<DbMessage>, max_frame_size: usize, allow_anonymous: bool) -> Result<Option<(ClientInfo, NameReservation)>>  {
+        let frame = match Message::receive_frame(stream_reader, max_frame_size).await {
+            Ok(frame) => frame,
+            Err(e @ FrameTooLarge { .. }) => {
+                metrics::oversized_frames_up();
+                return refuse(stream_writer, e.to_string()).await
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        // version is checked first; hello from incompatible client can't be deserialized
+        let Some(version) = handshake::client_hello_version(&frame) else {
+            return refuse(stream_writer, "First message has to be hello".into()).await
+        };
+        if version == handshake::LEGACY_PROTOCOL_VERSION {
+            error!("Refusing legacy client without protocol version");
+            let refusal = handshake::legacy_refusal(format!("Client is too old, protocol version {} is required", MIN_SUPPORTED_PROTOCOL_VERSION));
+            if let Err(e) = refusal.send(stream_writer, WireFormat::HELLO).await {
+                error!("Error when sending refusal: {}", e);
+            }
+            return Ok(None);
+        }
+        if let Err(reason) = handshake::check_version(version) {
+            return refuse(stream_writer, reason).await
+        }
+
+        let hello_message = Message::deserialize(&frame);
+        let Ok(Message::ClientHello { from: user, capabilities, .. }) = hello_message else {
             error!("Unexpected message from client: {:?}", hello_message);
-            return Ok(None)
+            return refuse(stream_writer, "Malformed hello".into()).await
         };
-        let can_connect = ractor::call!(actor, ConnectedClientsActorMessage::CheckUserCanConnect, user.to_string()).expect("Failed to check whether user can connect");
+        let can_connect = ractor::call!(actor, ConnectedClientsActorMessage::CheckUserCanConnect, user.to_string()).context("Failed to check whether user can connect")?;
         if !can_connect {
-            error!("User {} already connected", user);
-            return Ok(None)
+            return refuse(stream_writer, format!("User {} already connected", user)).await
+        }
+        // released on any failure from now on
+        let reservation = NameReservation::new(actor.clone(), user.clone());
+        let hello = Message::ServerHello { version: PROTOCOL_VERSION, capabilities: Capabilities::all() };
+        if let Err(e) = hello.send(stream_writer, WireFormat::HELLO).await {
+            error!("Error when sending server hello: {}", e); // convert to anyhow??
+            return Ok(None);
         }
-        match Message::ServerHello.send(stream_writer).await {
-            Ok(()) => Ok(Some(user)),
-            Err(e) => { error!("Error when sending server hello: {}", e); Ok(None)}, // convert to anyhow??
+
+        let capabilities = capabilities.intersection(Capabilities::all());
+        // old clients don't know anything about passwords, they may connect only anonymously
+        let credentials = if capabilities.contains(Capabilities::AUTH) {
+            Message::receive(stream_reader, max_frame_size, WireFormat::HELLO).await?
+        } else {
+            Message::Login { password: None }
+        };
+        if let Err(reason) = authenticate(db, &user, credentials, allow_anonymous).await {
+            metrics::auth_failures_up();
+            error!("Authentication of {} failed: {}", user, reason);
+            let refusal = if capabilities.contains(Capabilities::AUTH) {
+                Message::AuthFailed { reason }
+            } else {
+                Message::ServerRefused { reason }
+            };
+            if let Err(e) = refusal.send(stream_writer, WireFormat::HELLO).await {
+                error!("Error when sending refusal: {}", e);
+            }
+            return Ok(None);
+        }
+        if capabilities.contains(Capabilities::AUTH) {
+            if let Err(e) = Message::AuthOk.send(stream_writer, WireFormat::HELLO).await {
+                error!("Error when confirming login: {}", e);
+                return Ok(None);
+            }
         }
+        let resume_after = if capabilities.contains(Capabilities::RESUME) {
+            match Message::receive(stream_reader, max_frame_size, WireFormat::HELLO).await? {
+                Message::Resume { last_id } => last_id,
+                other => return refuse(stream_writer, format!("Expected resume, got {:?}", other)).await,
+            }
+        } else {
+            None
+        };
+        Ok(Some((ClientInfo { user_name: user, version, capabilities, resume_after }, reservation)))
     }
 
-    let (mut stream_reader, mut stream_writer) = stream.into_split();
-    match try_user_handshake(&mut stream_reader, &mut stream_writer, actor).await {
-        Ok(Some(user_name)) => Some((user_name, stream_reader, stream_writer)),
+    let (stream_reader, stream_writer) = match tls_acceptor {
+        Some(acceptor) => match tls::accept(acceptor, stream).await {
+            Ok(halves) => halves,
+            Err(e) => { error!("TLS handshake failed: {}", e); return None; },
+        },
+        None => tls::plain(stream),
+    };
+    // faults are injected into the decrypted stream, TLS would just drop the connection otherwise
+    let (mut stream_reader, mut stream_writer) = match faults {
+        Some(faults) => faults.wrap(stream_reader, stream_writer),
+        None => (stream_reader, stream_writer),
+    };
+    // note: the handshake reads exactly one frame at a time, nothing is lost when the stream is framed afterwards
+    match try_user_handshake(&mut stream_reader, &mut stream_writer, actor, db, max_frame_size, allow_anonymous).await {
+        Ok(Some((client, reservation))) => {
+            let format = WireFormat::negotiate(client.capabilities);
+            Some((client, reservation, MessageReader::new(stream_reader, format, max_frame_size), framed::writer(stream_writer, format, max_frame_size)))
+        },
         _ => None,
     }
 }
 
+/// checks the password of registered user or registers a new one; returns reason when the user can't connect
+///
+/// anonymous users (no password) are allowed only with `--allow-anonymous` and can't use names of registered users
+///
+/// note: hashing is slow on purpose, so it's done outside of the async runtime
+async fn authenticate(db: &ActorRef<DbMessage>, user: &str, credentials: Message, allow_anonymous: bool) -> Result<(), String> {
+    let stored_hash = ractor::call!(db, DbMessage::GetPasswordHash, user.to_string()).map_err(|e| e.to_string())??;
+    match (credentials, stored_hash) {
+        (Message::Login { password: Some(password) }, Some(hash)) => {
+            let valid = tokio::task::spawn_blocking(move || auth::verify_password(&password, &hash)).await.map_err(|e| e.to_string())?;
+            if valid { Ok(()) } else { Err("Wrong password".into()) }
+        },
+        (Message::Login { password: Some(_) }, None) => Err(format!("User {} is not registered, register first", user)),
+        (Message::Login { password: None }, Some(_)) => Err(format!("User {} is registered, password is required", user)),
+        (Message::Login { password: None }, None) if allow_anonymous => Ok(()),
+        (Message::Login { password: None }, None) => Err("Anonymous users are not allowed, register first".into()),
+        (Message::Register { .. }, Some(_)) => Err(format!("User {} is already registered", user)),
+        (Message::Register { password }, None) => {
+            let hash = tokio::task::spawn_blocking(move || auth::hash_password(&password)).await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+            ractor::call!(db, DbMessage::CreateUser, user.to_string(), hash).map_err(|e| e.to_string())?
+        },
+        (other, _) => Err(format!("Expected login or registration, got {:?}", other)),
+    }
+}
+
 /// task that handles one client
 /// 
 /// the task is using read part of the TCP stream to receive messages from the client
 /// the message is decoded and sent to the channel `tx_msg` to be broadcasted to other clients
-fn spawn_new_task_handling_one_client(user_name: String, mut stream: OwnedReadHalf, actor: ActorRef<ConnectedClientsActorMessage>)  {
+/// 
+/// client sending frame bigger than the limit of the reader is disconnected
+///
+/// client supporting heartbeats that doesn't send anything for `idle_timeout` is considered dead and disconnected
+/// (others can't be told from idle ones)
+///
+/// the task ends without announcing anything when `stop` is cancelled, the client was disconnected by the server
+fn spawn_new_task_handling_one_client(client: ClientInfo, mut stream: MessageReader, stop: CancellationToken, actor: ActorRef<ConnectedClientsActorMessage>, idle_timeout: Duration)  {
     tokio::spawn(async move {
+        let ClientInfo { user_name, version, capabilities, .. } = client;
+        let heartbeat = capabilities.contains(Capabilities::HEARTBEAT);
 
         fn send(actor: &ActorRef<ConnectedClientsActorMessage>, user_name: &str, message: Message) {
             let msg = ConnectedClientsActorMessage::IncommingChatMessage { user_name: user_name.to_string(), message };
@@ -128,11 +446,31 @@ fn spawn_new_task_handling_one_client(user_name: String, mut stream: OwnedReadHa
         }
 
         // send "hello" message to other clients
-        send(&actor, &user_name, Message::ClientHello{ from: user_name.to_string() });
+        send(&actor, &user_name, Message::ClientHello{ version, from: user_name.to_string(), capabilities });
 
         // process other incomming messages
         loop {
-            match Message::receive(&mut stream).await {
+            let receive = async {
+                if heartbeat {
+                    tokio::time::timeout(idle_timeout, stream.receive()).await.ok()
+                } else {
+                    Some(stream.receive().await)
+                }
+            };
+            let received = tokio::select! {
+                _ = stop.cancelled() => {
+                    info!("Client {} was disconnected by server", user_name);
+                    break;
+                },
+                received = receive => received,
+            };
+            let Some(received) = received else {
+                error!("Client {} was silent for {:?}. Disconnecting...", user_name, idle_timeout);
+                metrics::idle_timeouts_up();
+                send(&actor, &user_name, Message::ClientQuit{from: user_name.to_string()});
+                break;
+            };
+            match received {
                 Ok(message) => send(&actor, &user_name, message),
                 Err(GeneralStreamError(e)) => { 
                     error!("Client {} stream problems. Error: {}. Exitting...", user_name, e);
@@ -147,6 +485,12 @@ fn spawn_new_task_handling_one_client(user_name: String, mut stream: OwnedReadHa
                 Err(DeserializationError(e)) => { 
                     error!("Client {} sent malformed message. Error: {}", user_name, e);
                 },
+                Err(e @ FrameTooLarge { .. }) => {
+                    error!("Client {} sent too big message. Error: {}. Disconnecting...", user_name, e);
+                    metrics::oversized_frames_up();
+                    send(&actor, &user_name, Message::ClientQuit{from: user_name.to_string()});
+                    break;
+                },
             }
         }
     });
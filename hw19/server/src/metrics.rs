@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
-use prometheus::{IntCounter, Gauge};
+use prometheus::{IntCounter, IntGauge, Gauge};
+use shared::framed::CompressedBytes;
 
 lazy_static! {
     pub static ref METRICS_MESSAGES_COUNT_COUNTER: IntCounter = IntCounter::new(
@@ -10,6 +11,38 @@ lazy_static! {
         "chatapp_connected_users_count",
         "Count of users currently connected to server."
     ).unwrap();
+    pub static ref METRICS_OVERSIZED_FRAMES_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_oversized_frames_count",
+        "Count of clients disconnected because of a frame exceeding the size limit."
+    ).unwrap();
+    pub static ref METRICS_BYTES_BEFORE_COMPRESSION_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_bytes_before_compression",
+        "Size of frames sent to clients compressed, before compression."
+    ).unwrap();
+    pub static ref METRICS_BYTES_AFTER_COMPRESSION_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_bytes_after_compression",
+        "Size of frames sent to clients compressed, after compression."
+    ).unwrap();
+    pub static ref METRICS_AUTH_FAILURES_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_auth_failures_count",
+        "Count of clients refused because of failed login or registration."
+    ).unwrap();
+    pub static ref METRICS_IDLE_TIMEOUTS_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_idle_timeouts_count",
+        "Count of clients disconnected because they were silent for too long."
+    ).unwrap();
+    pub static ref METRICS_OUTBOUND_QUEUED_GAUGE: IntGauge = IntGauge::new(
+        "chatapp_outbound_queued_messages",
+        "Count of messages waiting in outbound queues of all clients."
+    ).unwrap();
+    pub static ref METRICS_OUTBOUND_DROPPED_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_outbound_dropped_count",
+        "Count of messages not sent because the queue of the client was full."
+    ).unwrap();
+    pub static ref METRICS_SLOW_CLIENT_EVICTIONS_COUNTER: IntCounter = IntCounter::new(
+        "chatapp_slow_client_evictions_count",
+        "Count of clients disconnected because their queue was full."
+    ).unwrap();
 }
 
 pub fn messages_up() {
@@ -21,6 +54,31 @@ pub fn users_up() {
 pub fn users_down() {
     METRICS_CONNECTED_USERS_GAUGE.dec();
 }
+pub fn oversized_frames_up() {
+    METRICS_OVERSIZED_FRAMES_COUNTER.inc();
+}
+pub fn compressed_bytes(CompressedBytes { before, after }: CompressedBytes) {
+    METRICS_BYTES_BEFORE_COMPRESSION_COUNTER.inc_by(before);
+    METRICS_BYTES_AFTER_COMPRESSION_COUNTER.inc_by(after);
+}
+pub fn auth_failures_up() {
+    METRICS_AUTH_FAILURES_COUNTER.inc();
+}
+pub fn idle_timeouts_up() {
+    METRICS_IDLE_TIMEOUTS_COUNTER.inc();
+}
+pub fn outbound_queued_up() {
+    METRICS_OUTBOUND_QUEUED_GAUGE.inc();
+}
+pub fn outbound_queued_down() {
+    METRICS_OUTBOUND_QUEUED_GAUGE.dec();
+}
+pub fn outbound_dropped_up() {
+    METRICS_OUTBOUND_DROPPED_COUNTER.inc();
+}
+pub fn slow_client_evictions_up() {
+    METRICS_SLOW_CLIENT_EVICTIONS_COUNTER.inc();
+}
 
 pub fn init() {
     prometheus::default_registry()
@@ -29,4 +87,28 @@ pub fn init() {
     prometheus::default_registry()
         .register(Box::new(METRICS_MESSAGES_COUNT_COUNTER.clone()))
         .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_OVERSIZED_FRAMES_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_BYTES_BEFORE_COMPRESSION_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_BYTES_AFTER_COMPRESSION_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_AUTH_FAILURES_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_IDLE_TIMEOUTS_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_OUTBOUND_QUEUED_GAUGE.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_OUTBOUND_DROPPED_COUNTER.clone()))
+        .unwrap();
+    prometheus::default_registry()
+        .register(Box::new(METRICS_SLOW_CLIENT_EVICTIONS_COUNTER.clone()))
+        .unwrap();
 }
\ No newline at end of file
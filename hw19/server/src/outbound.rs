@@ -0,0 +1,300 @@
+//! outbound queue of one client
+//!
+//! messages are written to the socket by a separate task per client, so the actor only puts them into a bounded queue
+//! and one client with full TCP buffer doesn't stall delivery to everyone else
+//!
+//! stored messages are reported back once they are written (see `Delivery`), the delivery cursor of the client moves only then
+//!
+//! replay of missed messages is one item of the queue (see `Replay`) - the actor doesn't wait until the client takes it all,
+//! and messages queued after it are written after it
+
+use crate::metrics;
+use futures::future::Either;
+use futures::stream::{self, BoxStream};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error};
+use shared::framed::{MessageWriter, SendMessageError};
+use shared::Message;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+/// what to do when the queue of a client is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SlowClientPolicy {
+    /// the message is not sent to the client
+    Drop,
+    /// the client is disconnected (and gets the messages replayed when it connects again)
+    Disconnect,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum QueueError {
+    #[error("Queue is full")]
+    Full,
+    /// the writer has failed, the client is going to be disconnected
+    #[error("Connection is closed")]
+    Closed,
+}
+
+/// stored message that was written to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    pub message_id: u64,
+    /// room of the message, `db::DIRECT_MESSAGES_ROOM` for private messages
+    pub room: String,
+}
+
+/// messages written one by one as they come (e.g. chunks of stored transfers are read from db only when it's their turn)
+pub type Replay = BoxStream<'static, (Arc<Message>, Option<Delivery>)>;
+
+enum Item {
+    /// message and what to report once it's written
+    Message(Arc<Message>, Option<Delivery>),
+    Replay(Replay),
+}
+
+/// messages are shared, broadcast doesn't copy them for every client
+pub struct OutboundQueue {
+    sender: mpsc::Sender<Item>,
+    /// `None` only while the queue is being drained
+    writer: Option<JoinHandle<()>>,
+}
+
+/// messages left in the queue are not going to be sent, they are removed from the metric
+struct Queue(mpsc::Receiver<Item>);
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        self.0.close();
+        while self.0.try_recv().is_ok() {
+            metrics::outbound_queued_down();
+        }
+    }
+}
+
+impl OutboundQueue {
+    /// `written` is called for every delivery whose message was written to the socket
+    pub fn spawn(user_name: String, mut stream_writer: MessageWriter, capacity: usize, written: impl Fn(Delivery) + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let writer = tokio::spawn(async move {
+            let mut queue = Queue(receiver);
+            while let Some(item) = queue.0.recv().await {
+                metrics::outbound_queued_down();
+                let mut messages = match item {
+                    Item::Message(message, delivery) => Either::Left(stream::iter(Some((message, delivery)))),
+                    Item::Replay(replay) => Either::Right(replay),
+                };
+                while let Some((message, delivery)) = messages.next().await {
+                    match stream_writer.send(&message).await {
+                        Ok(()) => {},
+                        // note: the frame is refused before anything is written, the connection can go on; the message
+                        // would never fit, so it counts as delivered, otherwise it would be replayed again and again
+                        Err(SendMessageError::GeneralStreamError(e)) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                            error!("Message for {} skipped: {}", user_name, e);
+                        },
+                        Err(e) => {
+                            error!("Error sending message to {}: {}", user_name, e);
+                            return;
+                        },
+                    }
+                    metrics::compressed_bytes(stream_writer.encoder_mut().take_compressed());
+                    if let Some(delivery) = delivery {
+                        written(delivery);
+                    }
+                }
+            }
+            debug!("Queue of {} closed", user_name);
+            if let Err(e) = stream_writer.close().await {
+                debug!("Error closing connection of {}: {}", user_name, e);
+            }
+        });
+        OutboundQueue { sender, writer: Some(writer) }
+    }
+
+    /// queues the message without waiting
+    pub fn push(&self, message: Arc<Message>, delivery: Option<Delivery>) -> Result<(), QueueError> {
+        self.push_item(Item::Message(message, delivery))
+    }
+
+    /// queues the whole replay without waiting, it takes one place in the queue
+    pub fn push_replay(&self, replay: Replay) -> Result<(), QueueError> {
+        self.push_item(Item::Replay(replay))
+    }
+
+    fn push_item(&self, item: Item) -> Result<(), QueueError> {
+        match self.sender.try_send(item) {
+            Ok(()) => { metrics::outbound_queued_up(); Ok(()) },
+            Err(TrySendError::Full(_)) => Err(QueueError::Full),
+            Err(TrySendError::Closed(_)) => Err(QueueError::Closed),
+        }
+    }
+
+    /// closes the queue and waits until the rest of it is written and the connection is closed;
+    /// returns false if it didn't make it in `timeout` (the connection is then closed right away)
+    pub async fn drain(mut self, timeout: Duration) -> bool {
+        let Some(mut writer) = self.writer.take() else {
+            return true;
+        };
+        // dropping the sender ends the writer loop once the queue is empty
+        drop(self);
+        if tokio::time::timeout(timeout, &mut writer).await.is_ok() {
+            return true;
+        }
+        writer.abort();
+        false
+    }
+}
+
+/// dropping the queue closes the connection right away, the rest of the queue is not sent (see `drain`)
+impl Drop for OutboundQueue {
+    fn drop(&mut self) {
+        if let Some(writer) = &self.writer {
+            writer.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use shared::frame::WireFormat;
+    use shared::framed::{self, MessageReader};
+    use std::sync::Mutex;
+
+    fn text(i: usize) -> Arc<Message> {
+        Arc::new(Message::Text { from: "hugo".into(), content: format!("message {}", i) })
+    }
+
+    fn delivery(i: usize) -> Option<Delivery> {
+        Some(Delivery { message_id: i as u64, room: "general".into() })
+    }
+
+    /// queue writing to `stream`; ids of written deliveries are collected
+    fn spawn_queue(stream: tokio::io::DuplexStream, capacity: usize) -> (OutboundQueue, Arc<Mutex<Vec<u64>>>) {
+        let written = Arc::new(Mutex::new(vec![]));
+        let collected = written.clone();
+        let queue = OutboundQueue::spawn("hugo".into(), framed::writer(Box::new(stream), WireFormat::HELLO, shared::DEFAULT_MAX_FRAME_SIZE), capacity, move |delivery| {
+            collected.lock().unwrap().push(delivery.message_id);
+        });
+        (queue, written)
+    }
+
+    #[test]
+    fn test_full_queue() {
+        tokio_test::block_on(async {
+            // tiny buffer that nobody reads, the writer gets stuck on the first message
+            let (client, server) = tokio::io::duplex(8);
+            let (queue, written) = spawn_queue(server, 2);
+            let mut pushed = 0;
+            while queue.push(text(pushed), delivery(pushed)).is_ok() {
+                pushed += 1;
+                assert!(pushed < 10, "queue is not bounded");
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(queue.push(text(pushed), None), Err(QueueError::Full));
+            // nothing is delivered until the client reads
+            assert!(written.lock().unwrap().is_empty());
+
+            // queued messages are sent in order once the client reads
+            let mut reader = MessageReader::new(Box::new(client), WireFormat::HELLO, 1024);
+            for i in 0..pushed {
+                assert_eq!(reader.receive().await.unwrap(), *text(i));
+            }
+            // the writer reports the last message after it's flushed
+            for _ in 0..10 {
+                if written.lock().unwrap().len() == pushed {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(*written.lock().unwrap(), (0..pushed as u64).collect::<Vec<_>>());
+            // dropping the queue closes the connection
+            drop(queue);
+            assert!(reader.receive().await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_too_large_message_is_skipped() {
+        tokio_test::block_on(async {
+            let (client, server) = tokio::io::duplex(1024);
+            let written = Arc::new(Mutex::new(vec![]));
+            let collected = written.clone();
+            let queue = OutboundQueue::spawn("hugo".into(), framed::writer(Box::new(server), WireFormat::HELLO, 100), 4, move |delivery| {
+                collected.lock().unwrap().push(delivery.message_id);
+            });
+            let large = Arc::new(Message::Text { from: "hugo".into(), content: "x".repeat(200) });
+            queue.push(large, delivery(0)).unwrap();
+            queue.push(text(1), delivery(1)).unwrap();
+
+            // the connection goes on with the next message
+            let mut reader = MessageReader::new(Box::new(client), WireFormat::HELLO, 1024);
+            assert_eq!(reader.receive().await.unwrap(), *text(1));
+            for _ in 0..10 {
+                if written.lock().unwrap().len() == 2 {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(*written.lock().unwrap(), vec![0, 1]);
+        });
+    }
+
+    #[test]
+    fn test_replay_is_written_before_later_messages() {
+        tokio_test::block_on(async {
+            let (client, server) = tokio::io::duplex(8);
+            let (queue, written) = spawn_queue(server, 2);
+            // much longer than the queue, but it doesn't wait for the client
+            let replay = futures::stream::iter((0..10).map(|i| (text(i), delivery(i)))).boxed();
+            queue.push_replay(replay).unwrap();
+            queue.push(text(10), delivery(10)).unwrap();
+
+            let mut reader = MessageReader::new(Box::new(client), WireFormat::HELLO, 1024);
+            for i in 0..=10 {
+                assert_eq!(reader.receive().await.unwrap(), *text(i));
+            }
+            for _ in 0..10 {
+                if written.lock().unwrap().len() == 11 {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(*written.lock().unwrap(), (0..=10).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_drain() {
+        tokio_test::block_on(async {
+            let (client, server) = tokio::io::duplex(8);
+            let (queue, _) = spawn_queue(server, 4);
+            for i in 0..3 {
+                queue.push(text(i), None).unwrap();
+            }
+            // nobody reads, so the queue can't be written out in time
+            assert!(!queue.drain(Duration::from_millis(50)).await);
+
+            let (client2, server) = tokio::io::duplex(8);
+            let (queue, written) = spawn_queue(server, 4);
+            for i in 0..3 {
+                queue.push(text(i), delivery(i)).unwrap();
+            }
+            let reading = tokio::spawn(async move {
+                let mut reader = MessageReader::new(Box::new(client2), WireFormat::HELLO, 1024);
+                let mut received = vec![];
+                // the connection is closed after the last message
+                while let Ok(message) = reader.receive().await {
+                    received.push(message);
+                }
+                received
+            });
+            assert!(queue.drain(Duration::from_secs(5)).await);
+            assert_eq!(reading.await.unwrap(), (0..3).map(|i| (*text(i)).clone()).collect::<Vec<_>>());
+            assert_eq!(*written.lock().unwrap(), vec![0, 1, 2]);
+            drop(client);
+        });
+    }
+}
@@ -1,8 +1,9 @@
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::response::{content, status, Redirect};
 use rocket::{Rocket, Request,Build, State, serde};
 
 use crate::actor_db;
+use crate::actor_connected_clients::ConnectedClientsActorMessage;
 use ractor::ActorRef;
 use chrono::{DateTime, Utc};
 use std::time::SystemTime;
@@ -11,6 +12,7 @@ use rocket_dyn_templates::Template;
 use std::collections::HashMap;
 
 use base64::{engine::general_purpose, Engine as _};
+use shared::transfer::TransferKind;
 
 fn  format_time(time: SystemTime) -> String {
     let datetime: DateTime<Utc> = time.into();
@@ -35,19 +37,38 @@ fn default_catcher(status: Status, req: &Request<'_>) -> status::Custom<String>
 
 use serde::Serialize;
 #[get("/users")]
-async fn users(state: &State<ActorRef<actor_db::DbMessage>>) -> Template {
+async fn users(state: &State<ActorRef<actor_db::DbMessage>>, clients: &State<ActorRef<ConnectedClientsActorMessage>>) -> Template {
 
     let Ok(cli) = ractor::call!(state, actor_db::DbMessage::GetAllUsersLastSeen) else {
-        return Template::render("error", &HashMap::from([("error", "Unable to get users")]));
+        return Template::render("error", HashMap::from([("error", "Unable to get users")]));
     };
+    // note: presence is not in db, only the clients actor knows it
+    let presence = ractor::call!(clients, ConnectedClientsActorMessage::GetPresence).unwrap_or_else(|e| {
+        error!("Unable to get presence: {}", e);
+        HashMap::new()
+    });
+
+    #[derive(Serialize, Debug)]
+    struct TemplateUser {
+        name: String,
+        last_seen: String,
+        presence: String,
+        status: String,
+    }
     let data = cli.into_iter()
-                        .map(|r| (r.user_name, format_time(r.last_seen)))
+                        .map(|r| {
+                            let (presence, status) = match presence.get(&r.user_name) {
+                                Some((state, status)) => (state.to_string(), status.clone().unwrap_or_default()),
+                                None => ("offline".to_string(), String::new()),
+                            };
+                            TemplateUser { name: r.user_name, last_seen: format_time(r.last_seen), presence, status }
+                        })
                         .collect::<Vec<_>>();
     info!("Returning users: {:?}", data);
 
     #[derive(Serialize)]
     struct Data {
-        users: Vec<(String, String)>,
+        users: Vec<TemplateUser>,
         rendered: String
     }
     let datax = Data { users: data, rendered: format_time(std::time::SystemTime::now()) };
@@ -64,41 +85,115 @@ async fn delete_user(user: &str, state: &State<ActorRef<actor_db::DbMessage>>) -
     rocket::response::Redirect::to(uri!(users))
 }
 
-#[get("/messages?<user>")]
-async fn messages(user: Option<String>, state: &State<ActorRef<actor_db::DbMessage>>) -> Template {
-    let Ok(messages) = ractor::call!(state, actor_db::DbMessage::ListAllMessages, user) else {
-        return Template::render("error", &HashMap::from([("error", "Unable to get messages")]));
+#[get("/messages?<user>&<room>")]
+async fn messages(user: Option<String>, room: Option<String>, state: &State<ActorRef<actor_db::DbMessage>>) -> Template {
+    let Ok(messages) = ractor::call!(state, actor_db::DbMessage::ListAllMessages, user, room.clone()) else {
+        return Template::render("error", HashMap::from([("error", "Unable to get messages")]));
+    };
+    render_messages(messages, room, None, None)
+}
+
+/// full-text search in texts and file names of all messages (including private ones)
+#[get("/search?<q>")]
+async fn search(q: String, state: &State<ActorRef<actor_db::DbMessage>>) -> Template {
+    let Ok(messages) = ractor::call!(state, actor_db::DbMessage::Search, q.clone(), None) else {
+        return Template::render("error", HashMap::from([("error", "Unable to search messages")]));
+    };
+    render_messages(messages, None, None, Some(q))
+}
+
+/// replies to the message and the message it replies to etc.
+#[get("/thread/<id>")]
+async fn thread(id: u64, state: &State<ActorRef<actor_db::DbMessage>>) -> Template {
+    let Ok(messages) = ractor::call!(state, actor_db::DbMessage::GetThread, id) else {
+        return Template::render("error", HashMap::from([("error", "Unable to get thread")]));
     };
-    
+    render_messages(messages, None, Some(id), None)
+}
+
+fn render_messages(messages: Vec<actor_db::StoredMessage>, room: Option<String>, thread: Option<u64>, search: Option<String>) -> Template {
     #[derive(Serialize)]
     struct TemplateMessage {
+        id: u64,
         user: String,
         time: String,
+        room: String,
         kind: String,
         data: String,
+        /// where content of chunked transfers can be downloaded
+        url: Option<String>,
+        received_by: String,
+        edited: bool,
+        /// e.g. "👍 2  🍕 1"
+        reactions: String,
+        /// the message replies to this one
+        parent: Option<u64>,
     }
     #[derive(Serialize)]
     struct Data {
         messages: Vec<TemplateMessage>,
+        /// shown when the messages are filtered by room
+        room: Option<String>,
+        /// shown on the thread page
+        thread: Option<u64>,
+        /// what was searched for, on the search page
+        search: Option<String>,
         rendered: String,
     }
 
     let messages = 
         messages.into_iter()
         .map(|row| {
-            let (kind, data) = match row.message {
-                shared::Message::Text { content, .. } => ("t".to_string(), content.to_string()),
-                shared::Message::Image { content, .. } => ("i".to_string(), general_purpose::STANDARD.encode(&content)),
-                shared::Message::File { name, .. } => ("f".to_string(), name.to_string()),
-                _ => ("".to_string(),"".to_string()),
+            let (kind, data, url) = match row.message {
+                // tombstone, content of deleted message is not shown
+                _ if row.deleted => ("d".to_string(), "".to_string(), None),
+                shared::Message::Text { content, .. } => ("t".to_string(), content.to_string(), None),
+                shared::Message::Direct { to, content, .. } => ("t".to_string(), format!("(private to {}) {}", to, content), None),
+                shared::Message::Image { content, .. } => ("i".to_string(), general_purpose::STANDARD.encode(&content), None),
+                shared::Message::File { name, .. } => ("f".to_string(), name.to_string(), None),
+                shared::Message::FileOffer { transfer_id: Some(id), name, kind: TransferKind::Image, .. } => ("i".to_string(), name, Some(uri!(file(id)).to_string())),
+                shared::Message::FileOffer { transfer_id: Some(id), name, kind: TransferKind::File, .. } => ("f".to_string(), name, Some(uri!(file(id)).to_string())),
+                _ => ("".to_string(),"".to_string(), None),
             };
-            TemplateMessage { user: row.user_name,  time: format_time(row.time), kind, data }
+            TemplateMessage { id: row.id, user: row.user_name,  time: format_time(row.time), room: row.room, kind, data, url, received_by: row.received_by.join(", "), edited: row.edited, reactions: format_reactions(&row.reactions), parent: row.parent }
         })
         .collect();
-    let data = Data { rendered: format_time(std::time::SystemTime::now()), messages };
+    let data = Data { rendered: format_time(std::time::SystemTime::now()), room, thread, search, messages };
     Template::render("messages", &data)
 }
 
+fn format_reactions(reactions: &[(String, u32)]) -> String {
+    reactions.iter().map(|(emoji, count)| format!("{} {}", emoji, count)).collect::<Vec<_>>().join("  ")
+}
+
+#[get("/files/<id>")]
+async fn file(id: u64, state: &State<ActorRef<actor_db::DbMessage>>) -> Option<(ContentType, Vec<u8>)> {
+    let Ok(Some((name, kind, content))) = ractor::call!(state, actor_db::DbMessage::GetFileContent, id) else {
+        return None;
+    };
+    Some((content_type(&name, kind, &content), content))
+}
+
+/// images can be in any format the client sent, so the type is taken from the content first; otherwise from the name
+fn content_type(name: &str, kind: TransferKind, content: &[u8]) -> ContentType {
+    const SIGNATURES: &[(&[u8], ContentType)] = &[
+        (b"\x89PNG\r\n\x1a\n", ContentType::PNG),
+        (b"\xff\xd8\xff", ContentType::JPEG),
+        (b"GIF87a", ContentType::GIF),
+        (b"GIF89a", ContentType::GIF),
+        (b"BM", ContentType::BMP),
+    ];
+    let detected = match kind {
+        // note: webp is RIFF container with the type after the length
+        TransferKind::Image if content.starts_with(b"RIFF") && content.get(8..12) == Some(b"WEBP") => Some(ContentType::WEBP),
+        TransferKind::Image => SIGNATURES.iter().find(|(signature, _)| content.starts_with(signature)).map(|(_, content_type)| content_type.clone()),
+        TransferKind::File => None,
+    };
+    detected
+        .or_else(|| std::path::Path::new(name).extension().and_then(|ext| ContentType::from_extension(&ext.to_string_lossy())))
+        .unwrap_or(ContentType::Binary)
+}
+
 #[get("/")]
 async fn index() -> Redirect {
     rocket::response::Redirect::to(uri!(users))
@@ -122,11 +217,16 @@ static_response_handler! {
     "/images/textbubble.png" => textbubble_png => "tbubble",
 }
 
-pub fn rocket(db_actor: ActorRef<actor_db::DbMessage>) -> Rocket<Build> {
+/// note: rocket doesn't handle ctrl+c and SIGTERM on its own, the server shuts it down after the clients are disconnected
+pub fn rocket(db_actor: ActorRef<actor_db::DbMessage>, clients_actor: ActorRef<ConnectedClientsActorMessage>) -> Rocket<Build> {
+    let config = rocket::Config::figment()
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
 
-    rocket::build()
-        .mount("/", routes![index, users, delete_user, messages, forced_error, metrics])
+    rocket::custom(config)
+        .mount("/", routes![index, users, delete_user, messages, thread, search, file, forced_error, metrics])
         .manage(db_actor)
+        .manage(clients_actor)
         .attach(Template::custom(|_engines| {
             //engines.handlebars.register_helper("simple-helper", Box::new(web_handlebars_ext::SimpleHelper));
         }))
@@ -137,4 +237,22 @@ pub fn rocket(db_actor: ActorRef<actor_db::DbMessage>) -> Rocket<Build> {
         ))
         .mount("/", routes![favicon, disk_png, textbubble_png])
         .register("/", catchers![general_not_found, default_catcher])
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_content_type_of_files() {
+        assert_eq!(content_type("cat.jpg", TransferKind::Image, b"\x89PNG\r\n\x1a\n...."), ContentType::PNG);
+        assert_eq!(content_type("cat.png", TransferKind::Image, b"\xff\xd8\xff\xe0...."), ContentType::JPEG);
+        assert_eq!(content_type("cat", TransferKind::Image, b"RIFF\0\0\0\0WEBPVP8 "), ContentType::WEBP);
+        // unknown content falls back to the name
+        assert_eq!(content_type("cat.gif", TransferKind::Image, b"????"), ContentType::GIF);
+        assert_eq!(content_type("cat", TransferKind::Image, b"????"), ContentType::Binary);
+        // files are never sniffed, text file may start with anything
+        assert_eq!(content_type("notes.txt", TransferKind::File, b"BMW"), ContentType::Text);
+        assert_eq!(content_type("notes", TransferKind::File, b"\x89PNG\r\n\x1a\n"), ContentType::Binary);
+    }
+}
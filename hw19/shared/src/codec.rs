@@ -0,0 +1,170 @@
+//! wire formats of `Message`
+//!
+//! hello messages (`ClientHello`, `ServerHello`, `ServerRefused`) are always bincode, so that the sides are able
+//! to understand each other before anything is negotiated. The codec for the rest of the connection is chosen
+//! from the codec capabilities both sides support (see `CodecKind::negotiate`).
+//!
+//! note: the database always stores bincode, codecs are only about the network
+
+use crate::{Capabilities, Message};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error("bincode: {0}")]
+    Bincode(#[from] bincode::Error),
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("MessagePack: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    /// compressed frame is corrupted (see `frame`)
+    #[error("deflate: {0}")]
+    Deflate(std::io::Error),
+}
+
+/// converts messages from/to bytes of one frame
+pub trait Codec: Send + Sync {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError>;
+}
+
+pub struct Bincode;
+pub struct Json;
+/// fields are serialized with names so that the messages are readable by generic MessagePack tools
+pub struct MessagePack;
+
+impl Codec for Bincode {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::serialize(message)?)
+    }
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+impl Codec for Json {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(message)?)
+    }
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+impl Codec for MessagePack {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(message)?)
+    }
+    fn decode(&self, data: &[u8]) -> Result<Message, CodecError> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// codec used on one connection
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Json,
+    MessagePack,
+}
+
+impl CodecKind {
+    pub fn codec(&self) -> &'static dyn Codec {
+        match self {
+            CodecKind::Bincode => &Bincode,
+            CodecKind::Json => &Json,
+            CodecKind::MessagePack => &MessagePack,
+        }
+    }
+
+    /// capability advertising support of the codec; bincode is supported by everybody
+    pub fn capability(&self) -> Capabilities {
+        match self {
+            CodecKind::Bincode => Capabilities::NONE,
+            CodecKind::Json => Capabilities::JSON_CODEC,
+            CodecKind::MessagePack => Capabilities::MSGPACK_CODEC,
+        }
+    }
+
+    /// picks codec from negotiated capabilities; MessagePack is preferred to JSON, bincode is the fallback
+    pub fn negotiate(capabilities: Capabilities) -> CodecKind {
+        if capabilities.contains(Capabilities::MSGPACK_CODEC) {
+            CodecKind::MessagePack
+        } else if capabilities.contains(Capabilities::JSON_CODEC) {
+            CodecKind::Json
+        } else {
+            CodecKind::Bincode
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bincode" => Ok(CodecKind::Bincode),
+            "json" => Ok(CodecKind::Json),
+            "msgpack" | "messagepack" => Ok(CodecKind::MessagePack),
+            _ => Err(format!("Unknown codec {}, use one of bincode, json, msgpack", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecKind::Bincode => write!(f, "bincode"),
+            CodecKind::Json => write!(f, "json"),
+            CodecKind::MessagePack => write!(f, "msgpack"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transfer::TransferKind;
+
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::Text { from: "hugo".into(), content: "hello, ěščř".into() },
+            Message::Image { from: "hugo".into(), content: vec![0, 1, 255] },
+            Message::ClientHello { version: 1, from: "hugo".into(), capabilities: Capabilities::all() },
+            Message::FileOffer { transfer_id: None, from: "hugo".into(), name: "a.txt".into(), kind: TransferKind::Image, size: 3, checksum: "abc".into() },
+            Message::Stored { id: 7, time: 42, message: Box::new(Message::File { from: "hugo".into(), name: "a.txt".into(), content: vec![1, 2] }) },
+            Message::SearchResults { query: "a".into(), results: vec![crate::SearchHit { id: 7, time: 42, from: "hugo".into(), room: "".into(), text: "a.txt".into() }] },
+        ]
+    }
+
+    #[test]
+    fn test_all_codecs_roundtrip() {
+        for kind in [CodecKind::Bincode, CodecKind::Json, CodecKind::MessagePack] {
+            for message in samples() {
+                let data = kind.codec().encode(&message).unwrap();
+                assert_eq!(kind.codec().decode(&data).unwrap(), message, "codec {}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let data = Json.encode(&Message::Text { from: "hugo".into(), content: "hello".into() }).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), r#"{"Text":{"from":"hugo","content":"hello"}}"#);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(CodecKind::negotiate(Capabilities::NONE), CodecKind::Bincode);
+        assert_eq!(CodecKind::negotiate(Capabilities::JSON_CODEC), CodecKind::Json);
+        assert_eq!(CodecKind::negotiate(Capabilities::all()), CodecKind::MessagePack);
+        for kind in [CodecKind::Bincode, CodecKind::Json, CodecKind::MessagePack] {
+            assert_eq!(CodecKind::negotiate(kind.capability()), kind);
+            assert_eq!(kind.to_string().parse::<CodecKind>().unwrap(), kind);
+        }
+    }
+}
@@ -0,0 +1,583 @@
+//! fault injection for resilience testing (replaces the old chaos monkey)
+//!
+//! `FaultInjector::wrap` puts `FaultyReader`/`FaultyWriter` around the halves of a connection. They split the byte
+//! stream to frames (see `frame`) and for every frame decide whether to inject one fault:
+//! - `drop` - the frame disappears
+//! - `delay` - the frame is held back for random time up to `delay_ms`
+//! - `truncate` - the end of the payload is cut off (header is fixed, so the stream stays in sync)
+//! - `duplicate` - the frame is passed twice
+//! - `reorder` - the frame is held back and passed after the next one
+//! - `corrupt` - one byte of the payload is changed
+//! - `close` - the connection is closed (reader returns EOF, writer fails)
+//!
+//! Configured by `CHAOS_MONKEY` environment variable, e.g. `CHAOS_MONKEY=drop=0.05,corrupt=0.1,seed=42`;
+//! the values are probabilities (0..1). Any other value (e.g. `CHAOS_MONKEY=1`) means `corrupt=0.12` - the old behaviour.
+//!
+//! Every connection (and direction) gets its own random generator derived from the seed, so the same seed
+//! gives the same faults for the same sequence of frames. Injected faults are logged and counted (`FaultInjector::stats`).
+//!
+//! note: frames are buffered whole and their size is not checked, it's a testing tool, not for production
+
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::frame;
+use crate::tls::{StreamReader, StreamWriter};
+
+pub const ENV_VARIABLE: &str = "CHAOS_MONKEY";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FaultKind {
+    Drop,
+    Delay,
+    Truncate,
+    Duplicate,
+    Reorder,
+    Corrupt,
+    Close,
+}
+
+impl FaultKind {
+    /// order in which the faults are rolled; only the first hit is injected
+    pub const ALL: [FaultKind; 7] = [FaultKind::Close, FaultKind::Drop, FaultKind::Truncate, FaultKind::Corrupt, FaultKind::Duplicate, FaultKind::Reorder, FaultKind::Delay];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultKind::Drop => "drop",
+            FaultKind::Delay => "delay",
+            FaultKind::Truncate => "truncate",
+            FaultKind::Duplicate => "duplicate",
+            FaultKind::Reorder => "reorder",
+            FaultKind::Corrupt => "corrupt",
+            FaultKind::Close => "close",
+        }
+    }
+}
+
+impl std::fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// probabilities (0..1) of the faults per frame
+#[derive(Debug, PartialEq, Clone)]
+pub struct FaultConfig {
+    /// random seed is used (and logged) if not set
+    pub seed: Option<u64>,
+    pub drop: f64,
+    pub delay: f64,
+    pub truncate: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub corrupt: f64,
+    pub close: f64,
+    /// the longest delay
+    pub delay_max: Duration,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            seed: None,
+            drop: 0.0,
+            delay: 0.0,
+            truncate: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            close: 0.0,
+            delay_max: Duration::from_millis(500),
+        }
+    }
+}
+
+impl FaultConfig {
+    /// what `CHAOS_MONKEY=1` used to do
+    pub fn legacy() -> Self {
+        FaultConfig { corrupt: 0.12, ..Default::default() }
+    }
+
+    pub fn probability(&self, kind: FaultKind) -> f64 {
+        match kind {
+            FaultKind::Drop => self.drop,
+            FaultKind::Delay => self.delay,
+            FaultKind::Truncate => self.truncate,
+            FaultKind::Duplicate => self.duplicate,
+            FaultKind::Reorder => self.reorder,
+            FaultKind::Corrupt => self.corrupt,
+            FaultKind::Close => self.close,
+        }
+    }
+}
+
+/// `key=value` pairs separated by commas; keys are fault names, `seed` and `delay_ms`
+impl FromStr for FaultConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = FaultConfig::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("Expected key=value, got {}", pair));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = |e: &dyn std::fmt::Display| format!("Invalid value of {}: {}", key, e);
+            if key == "seed" {
+                config.seed = Some(value.parse().map_err(|e| invalid(&e))?);
+                continue;
+            }
+            if key == "delay_ms" {
+                config.delay_max = Duration::from_millis(value.parse().map_err(|e| invalid(&e))?);
+                continue;
+            }
+            let probability: f64 = value.parse().map_err(|e| invalid(&e))?;
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(invalid(&"probability has to be between 0 and 1"));
+            }
+            let target = match key {
+                "drop" => &mut config.drop,
+                "delay" => &mut config.delay,
+                "truncate" => &mut config.truncate,
+                "duplicate" => &mut config.duplicate,
+                "reorder" => &mut config.reorder,
+                "corrupt" => &mut config.corrupt,
+                "close" => &mut config.close,
+                _ => return Err(format!("Unknown fault {}", key)),
+            };
+            *target = probability;
+        }
+        Ok(config)
+    }
+}
+
+/// how many faults of each kind were injected
+#[derive(Default, Debug)]
+pub struct FaultStats {
+    counts: [AtomicU64; FaultKind::ALL.len()],
+}
+
+impl FaultStats {
+    fn index(kind: FaultKind) -> usize {
+        FaultKind::ALL.iter().position(|k| *k == kind).unwrap()
+    }
+
+    fn record(&self, kind: FaultKind) {
+        self.counts[Self::index(kind)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self, kind: FaultKind) -> u64 {
+        self.counts[Self::index(kind)].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        FaultKind::ALL.iter().map(|kind| self.count(*kind)).sum()
+    }
+}
+
+impl std::fmt::Display for FaultStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let counts: Vec<_> = FaultKind::ALL.iter()
+            .filter(|kind| self.count(**kind) > 0)
+            .map(|kind| format!("{}: {}", kind, self.count(*kind)))
+            .collect();
+        if counts.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", counts.join(", "))
+        }
+    }
+}
+
+/// shared by all connections of the process; cheap to clone
+#[derive(Clone)]
+pub struct FaultInjector {
+    config: Arc<FaultConfig>,
+    seed: u64,
+    connections: Arc<AtomicU64>,
+    stats: Arc<FaultStats>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        FaultInjector { config: Arc::new(config), seed, connections: Arc::default(), stats: Arc::default() }
+    }
+
+    /// `None` if `CHAOS_MONKEY` is not set
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(value) = std::env::var(ENV_VARIABLE) else {
+            return Ok(None);
+        };
+        let config = if value.contains('=') { value.parse()? } else { FaultConfig::legacy() };
+        let injector = FaultInjector::new(config);
+        warn!("Fault injection is enabled: {:?}, seed {}", injector.config, injector.seed);
+        Ok(Some(injector))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn stats(&self) -> &FaultStats {
+        &self.stats
+    }
+
+    pub fn wrap(&self, reader: StreamReader, writer: StreamWriter) -> (StreamReader, StreamWriter) {
+        let connection = self.connections.fetch_add(1, Ordering::Relaxed);
+        let seed = self.seed.wrapping_add(connection.wrapping_mul(2));
+        let reader = FaultyReader::new(reader, self.faults("read", seed));
+        let writer = FaultyWriter::new(writer, self.faults("write", seed.wrapping_add(1)));
+        (Box::new(reader), Box::new(writer))
+    }
+
+    fn faults(&self, direction: &'static str, seed: u64) -> FrameFaults {
+        FrameFaults {
+            config: self.config.clone(),
+            stats: self.stats.clone(),
+            rng: StdRng::seed_from_u64(seed),
+            direction,
+            held: None,
+        }
+    }
+}
+
+/// what happens with one frame
+enum Outcome {
+    /// bytes to pass on (may be empty, or more frames)
+    Pass(Vec<u8>),
+    Delay(Duration, Vec<u8>),
+    Close,
+}
+
+/// decides about faults of one direction of one connection
+struct FrameFaults {
+    config: Arc<FaultConfig>,
+    stats: Arc<FaultStats>,
+    rng: StdRng,
+    direction: &'static str,
+    /// frame waiting for the next one (reorder)
+    held: Option<Vec<u8>>,
+}
+
+impl FrameFaults {
+    fn pick(&mut self) -> Option<FaultKind> {
+        let config = &self.config;
+        let rng = &mut self.rng;
+        // every kind is rolled so that the sequence of random numbers doesn't depend on the hits
+        let hits: Vec<_> = FaultKind::ALL.iter().filter(|kind| rng.gen_bool(config.probability(**kind))).collect();
+        let kind = **hits.first()?;
+        self.stats.record(kind);
+        warn!("Injecting fault ({}): {}", self.direction, kind);
+        Some(kind)
+    }
+
+    /// `frame` is complete frame including header
+    fn process(&mut self, mut frame: Vec<u8>) -> Outcome {
+        let payload_len = frame.len() - 4;
+        match self.pick() {
+            Some(FaultKind::Close) => return Outcome::Close,
+            Some(FaultKind::Drop) => return Outcome::Pass(Vec::new()),
+            Some(FaultKind::Truncate) if payload_len > 0 => {
+                let len = self.rng.gen_range(0..payload_len);
+                let header = u32::from_be_bytes(frame[..4].try_into().unwrap());
+                let header = len as u32 | (header & frame::COMPRESSED_FLAG);
+                frame.truncate(4 + len);
+                frame[..4].copy_from_slice(&header.to_be_bytes());
+            },
+            Some(FaultKind::Corrupt) if payload_len > 0 => {
+                let index = 4 + self.rng.gen_range(0..payload_len);
+                frame[index] ^= self.rng.gen_range(1..=255u8);
+            },
+            Some(FaultKind::Duplicate) => frame.extend_from_within(..),
+            Some(FaultKind::Reorder) if self.held.is_none() => {
+                self.held = Some(frame);
+                return Outcome::Pass(Vec::new());
+            },
+            Some(FaultKind::Delay) => {
+                let delay = self.rng.gen_range(Duration::ZERO..=self.config.delay_max);
+                return Outcome::Delay(delay, self.release_held(frame));
+            },
+            _ => {},
+        }
+        Outcome::Pass(self.release_held(frame))
+    }
+
+    fn release_held(&mut self, mut frame: Vec<u8>) -> Vec<u8> {
+        if let Some(held) = self.held.take() {
+            frame.extend(held);
+        }
+        frame
+    }
+}
+
+/// collects bytes until there is complete frame
+#[derive(Default)]
+struct FrameSplitter {
+    buffer: Vec<u8>,
+}
+
+impl FrameSplitter {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let header = u32::from_be_bytes(self.buffer.get(..4)?.try_into().unwrap());
+        let (len, _) = frame::parse_header(header);
+        if self.buffer.len() < 4 + len {
+            return None;
+        }
+        let rest = self.buffer.split_off(4 + len);
+        Some(std::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+pub struct FaultyReader<R> {
+    inner: R,
+    faults: FrameFaults,
+    incoming: FrameSplitter,
+    ready: VecDeque<u8>,
+    delayed: Option<(Pin<Box<Sleep>>, Vec<u8>)>,
+    closed: bool,
+}
+
+impl<R> FaultyReader<R> {
+    fn new(inner: R, faults: FrameFaults) -> Self {
+        FaultyReader { inner, faults, incoming: FrameSplitter::default(), ready: VecDeque::new(), delayed: None, closed: false }
+    }
+
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            info!("Faults injected so far: {}", self.faults.stats);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FaultyReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.ready.is_empty() {
+                let len = this.ready.len().min(buf.remaining());
+                let data: Vec<u8> = this.ready.drain(..len).collect();
+                buf.put_slice(&data);
+                return Poll::Ready(Ok(()));
+            }
+            if this.closed {
+                // EOF
+                return Poll::Ready(Ok(()));
+            }
+            if let Some((sleep, _)) = &mut this.delayed {
+                ready!(sleep.as_mut().poll(cx));
+                let (_, data) = this.delayed.take().unwrap();
+                this.ready.extend(data);
+                continue;
+            }
+            if let Some(frame) = this.incoming.next_frame() {
+                match this.faults.process(frame) {
+                    Outcome::Pass(data) => this.ready.extend(data),
+                    Outcome::Delay(delay, data) => this.delayed = Some((Box::pin(tokio::time::sleep(delay)), data)),
+                    Outcome::Close => this.close(),
+                }
+                continue;
+            }
+
+            let mut data = [0u8; 8192];
+            let mut data_buf = ReadBuf::new(&mut data);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut data_buf))?;
+            if data_buf.filled().is_empty() {
+                this.close();
+                continue;
+            }
+            this.incoming.push(data_buf.filled());
+        }
+    }
+}
+
+/// frames are passed to the inner writer on flush (`Message::send` always flushes)
+pub struct FaultyWriter<W> {
+    inner: W,
+    faults: FrameFaults,
+    outgoing: FrameSplitter,
+    pending: Vec<u8>,
+    written: usize,
+    delayed: Option<(Pin<Box<Sleep>>, Vec<u8>)>,
+    closed: bool,
+}
+
+impl<W> FaultyWriter<W> {
+    fn new(inner: W, faults: FrameFaults) -> Self {
+        FaultyWriter { inner, faults, outgoing: FrameSplitter::default(), pending: Vec::new(), written: 0, delayed: None, closed: false }
+    }
+}
+
+fn closed_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "connection closed by fault injection")
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FaultyWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(Err(closed_error()));
+        }
+        this.outgoing.push(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(Err(closed_error()));
+            }
+            if this.written < this.pending.len() {
+                let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.pending[this.written..]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                }
+                this.written += written;
+                continue;
+            }
+            this.pending.clear();
+            this.written = 0;
+            if let Some((sleep, _)) = &mut this.delayed {
+                ready!(sleep.as_mut().poll(cx));
+                let (_, data) = this.delayed.take().unwrap();
+                this.pending = data;
+                continue;
+            }
+            if let Some(frame) = this.outgoing.next_frame() {
+                match this.faults.process(frame) {
+                    Outcome::Pass(data) => this.pending = data,
+                    Outcome::Delay(delay, data) => this.delayed = Some((Box::pin(tokio::time::sleep(delay)), data)),
+                    Outcome::Close => {
+                        this.closed = true;
+                        info!("Faults injected so far: {}", this.faults.stats);
+                    },
+                }
+                continue;
+            }
+            return Pin::new(&mut this.inner).poll_flush(cx);
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Message, ReceiveMessageError};
+    use crate::frame::WireFormat;
+
+    fn text(i: usize) -> Message {
+        Message::Text { from: "hugo".into(), content: format!("message {}", i) }
+    }
+
+    /// sends `count` messages through faulty writer and returns what arrived (`None` for undecodable message)
+    async fn send_through(config: &str, count: usize) -> (Vec<Option<Message>>, FaultInjector) {
+        let injector = FaultInjector::new(config.parse().unwrap());
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let (_, mut writer) = injector.wrap(Box::new(client_reader), Box::new(client_writer));
+        for i in 0..count {
+            if text(i).send(&mut writer, WireFormat::HELLO).await.is_err() {
+                break;
+            }
+        }
+        drop(writer);
+
+        let (mut reader, _) = tokio::io::split(server);
+        let mut received = Vec::new();
+        loop {
+            match Message::receive(&mut reader, 1024, WireFormat::HELLO).await {
+                Ok(message) => received.push(Some(message)),
+                Err(ReceiveMessageError::DeserializationError(_)) => received.push(None),
+                Err(_) => break,
+            }
+        }
+        (received, injector)
+    }
+
+    #[test]
+    fn test_config_is_parsed() {
+        let config: FaultConfig = "drop=0.5, corrupt=0.1,seed=42,delay_ms=10".parse().unwrap();
+        assert_eq!(config, FaultConfig { seed: Some(42), drop: 0.5, corrupt: 0.1, delay_max: Duration::from_millis(10), ..Default::default() });
+        assert!("drop=2".parse::<FaultConfig>().is_err());
+        assert!("explode=0.1".parse::<FaultConfig>().is_err());
+        assert!("drop".parse::<FaultConfig>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_same_seed_gives_same_faults() {
+        let config = "drop=0.2,truncate=0.1,corrupt=0.1,duplicate=0.1,reorder=0.1,seed=7";
+        let (first, injector) = send_through(config, 100).await;
+        let (second, _) = send_through(config, 100).await;
+        assert_eq!(first, second);
+        assert!(injector.stats().total() > 0);
+        assert!(injector.stats().count(FaultKind::Drop) > 0);
+    }
+
+    #[tokio::test]
+    async fn test_without_faults_everything_arrives() {
+        let (received, injector) = send_through("", 10).await;
+        assert_eq!(received, (0..10).map(|i| Some(text(i))).collect::<Vec<_>>());
+        assert_eq!(injector.stats().total(), 0);
+        assert_eq!(injector.stats().to_string(), "none");
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_duplicated_and_reordered() {
+        let (received, _) = send_through("duplicate=1", 2).await;
+        assert_eq!(received, vec![Some(text(0)), Some(text(0)), Some(text(1)), Some(text(1))]);
+
+        let (received, _) = send_through("reorder=1", 4).await;
+        assert_eq!(received, vec![Some(text(1)), Some(text(0)), Some(text(3)), Some(text(2))]);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frames_keep_stream_in_sync() {
+        let (received, injector) = send_through("truncate=1", 5).await;
+        assert_eq!(received, vec![None; 5]);
+        assert_eq!(injector.stats().count(FaultKind::Truncate), 5);
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection() {
+        let (received, _) = send_through("close=1", 5).await;
+        assert!(received.is_empty());
+
+        // reader side sees EOF
+        let injector = FaultInjector::new("close=1".parse().unwrap());
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (mut reader, _) = injector.wrap(Box::new(server_reader), Box::new(server_writer));
+        let (_, mut writer) = tokio::io::split(client);
+        text(0).send(&mut writer, WireFormat::HELLO).await.unwrap();
+        assert!(matches!(Message::receive(&mut reader, 1024, WireFormat::HELLO).await, Err(ReceiveMessageError::RemoteDisconnected(_))));
+    }
+
+    #[tokio::test]
+    async fn test_delayed_frame_arrives() {
+        let (received, injector) = send_through("delay=1,delay_ms=10", 3).await;
+        assert_eq!(received, (0..3).map(|i| Some(text(i))).collect::<Vec<_>>());
+        assert_eq!(injector.stats().count(FaultKind::Delay), 3);
+    }
+}
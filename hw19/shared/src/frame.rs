@@ -0,0 +1,149 @@
+//! how messages are put into frames
+//!
+//! frame = 4 bytes header (big endian) + payload; the highest bit of the header tells whether the payload
+//! is compressed (deflate), the rest is length of the payload. Compressed and uncompressed frames may be mixed,
+//! so only frames above `COMPRESSION_THRESHOLD` are compressed (and only when it actually saves something).
+//!
+//! Compressed frames are sent only if both sides support `Capabilities::COMPRESSION`, but they are always understood.
+
+use crate::Capabilities;
+use crate::codec::CodecKind;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+/// set in the frame header when the payload is compressed
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+/// smaller payloads are not worth compressing
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// how messages are sent over one connection
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct WireFormat {
+    pub codec: CodecKind,
+    pub compression: bool,
+}
+
+impl WireFormat {
+    /// hello messages are sent before anything is negotiated
+    pub const HELLO: WireFormat = WireFormat { codec: CodecKind::Bincode, compression: false };
+
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        WireFormat {
+            codec: CodecKind::negotiate(capabilities),
+            compression: capabilities.contains(Capabilities::COMPRESSION),
+        }
+    }
+}
+
+impl std::fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.codec, if self.compression { "+deflate" } else { "" })
+    }
+}
+
+/// returns header and payload of the frame; the payload is compressed if the header says so (see `parse_header`)
+///
+/// data longer than `max_frame_size` are refused, see `frame_len`
+pub fn encode(data: Vec<u8>, compression: bool, max_frame_size: usize) -> std::io::Result<(u32, Vec<u8>)> {
+    let len = frame_len(data.len(), max_frame_size)?;
+    if !compression {
+        return Ok((len, data));
+    }
+    // note: compressed payload is smaller than the data, so its length fits too
+    Ok(match compress(&data)? {
+        Some(compressed) => (compressed.len() as u32 | COMPRESSED_FLAG, compressed),
+        None => (len, data),
+    })
+}
+
+/// length of the data for the header; refused when the other side wouldn't accept it (even compressed, the limit
+/// applies to the decompressed data too) or when it doesn't fit into the header next to `COMPRESSED_FLAG`
+fn frame_len(len: usize, max_frame_size: usize) -> std::io::Result<u32> {
+    let max = max_frame_size.min(!COMPRESSED_FLAG as usize);
+    if len > max {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Frame of {} bytes is larger than maximum {} bytes", len, max)));
+    }
+    Ok(len as u32)
+}
+
+/// returns `None` if the data are too small or don't compress
+fn compress(data: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+    if data.len() < COMPRESSION_THRESHOLD {
+        return Ok(None);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), Compression::fast());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    Ok((compressed.len() < data.len()).then_some(compressed))
+}
+
+/// splits frame header to payload length and compression flag
+pub fn parse_header(header: u32) -> (usize, bool) {
+    ((header & !COMPRESSED_FLAG) as usize, header & COMPRESSED_FLAG != 0)
+}
+
+/// inflates the payload; returns `None` if it would be bigger than `max_size`
+pub fn decompress(payload: &[u8], max_size: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::with_capacity(payload.len() * 2);
+    // read one byte more to find out whether the limit is exceeded
+    DeflateDecoder::new(payload).take(max_size as u64 + 1).read_to_end(&mut data)?;
+    Ok((data.len() <= max_size).then_some(data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_small_frames_are_not_compressed() {
+        let (header, payload) = encode(vec![1, 2, 3], true, usize::MAX).unwrap();
+        assert_eq!(parse_header(header), (3, false));
+        assert_eq!(payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_compressed_frame_roundtrip() {
+        let data = "hello ".repeat(1000).into_bytes();
+        let (header, payload) = encode(data.clone(), true, usize::MAX).unwrap();
+        let (len, compressed) = parse_header(header);
+        assert!(compressed);
+        assert_eq!(len, payload.len());
+        assert!(len < data.len());
+        assert_eq!(decompress(&payload, data.len()).unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn test_decompression_is_limited() {
+        let data = vec![0u8; 100_000];
+        let (_, payload) = encode(data, true, usize::MAX).unwrap();
+        assert!(decompress(&payload, 1000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_without_compression_frame_is_untouched() {
+        let data = vec![0u8; 100_000];
+        let (header, payload) = encode(data.clone(), false, usize::MAX).unwrap();
+        assert_eq!(parse_header(header), (data.len(), false));
+        assert_eq!(payload, data);
+    }
+
+    #[test]
+    fn test_frame_larger_than_maximum_is_refused() {
+        let (header, _) = encode(vec![0u8; 1000], false, 1000).unwrap();
+        assert_eq!(parse_header(header), (1000, false));
+        let error = encode(vec![0u8; 1001], false, 1000).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        // compressible data are refused as well, the other side checks the decompressed size
+        assert!(encode(vec![0u8; 2000], true, 1999).is_err());
+    }
+
+    #[test]
+    fn test_frame_length_has_to_fit_into_header() {
+        let max = !COMPRESSED_FLAG as usize;
+        assert_eq!(frame_len(max, usize::MAX).unwrap(), !COMPRESSED_FLAG);
+        assert!(frame_len(max + 1, usize::MAX).is_err());
+        assert!(frame_len(u32::MAX as usize, usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! `tokio_util` codec for `Message` frames (see `frame` for the wire layout)
+//!
+//! `Message::receive` reads the header and the payload by separate reads, so when it is cancelled
+//! (by `select!` or `timeout`) in between, the already read part of the frame is lost and the stream is out of sync.
+//! `MessageReader` keeps partially received frames in its buffer, so `next()` can be cancelled safely.
+
+use crate::codec::CodecError;
+use crate::frame::{self, WireFormat};
+use crate::tls::{StreamReader, StreamWriter};
+use crate::{Message, ReceiveMessageError};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+/// frame header size
+const HEADER_LEN: usize = 4;
+
+/// sink for messages sent to the other side
+pub type MessageWriter = FramedWrite<StreamWriter, MessageCodec>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SendMessageError {
+    #[error("General stream error")]
+    GeneralStreamError(#[from] std::io::Error),
+    #[error("Unable to serialize message")]
+    SerializationError(#[from] CodecError),
+}
+
+/// sizes of the sent frames that were compressed - before and after compression (payload only, without header)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedBytes {
+    pub before: u64,
+    pub after: u64,
+}
+
+/// encodes and decodes messages in given wire format
+///
+/// the format can be changed when the connection negotiates another one, already buffered data are kept
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    pub format: WireFormat,
+    pub max_frame_size: usize,
+    /// counted since the last `take_compressed`
+    compressed: CompressedBytes,
+}
+
+impl MessageCodec {
+    pub fn new(format: WireFormat, max_frame_size: usize) -> Self {
+        MessageCodec { format, max_frame_size, compressed: CompressedBytes::default() }
+    }
+
+    /// sizes of frames compressed since the last call (e.g. for metrics); frames too small or not worth compressing are not counted
+    pub fn take_compressed(&mut self) -> CompressedBytes {
+        std::mem::take(&mut self.compressed)
+    }
+}
+
+/// decoded item is a result on its own: malformed message was still read whole, so the stream can go on
+///
+/// note: errors of the decoder end `FramedRead`, so only the broken stream is reported that way
+impl Decoder for MessageCodec {
+    type Item = Result<Message, CodecError>;
+    type Error = ReceiveMessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, ReceiveMessageError> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let header = u32::from_be_bytes([src[0], src[1], src[2], src[3]]);
+        let (data_len, compressed) = frame::parse_header(header);
+        if data_len > self.max_frame_size {
+            return Err(ReceiveMessageError::FrameTooLarge { size: data_len, max: self.max_frame_size });
+        }
+        if src.len() < HEADER_LEN + data_len {
+            // not complete yet, make room for the rest so that it's not reallocated on every read
+            src.reserve(HEADER_LEN + data_len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_LEN);
+        let payload = src.split_to(data_len);
+        if !compressed {
+            return Ok(Some(Message::decode(self.format.codec, &payload)));
+        }
+        match frame::decompress(&payload, self.max_frame_size) {
+            Ok(Some(data)) => Ok(Some(Message::decode(self.format.codec, &data))),
+            Ok(None) => Err(ReceiveMessageError::FrameTooLarge { size: self.max_frame_size + 1, max: self.max_frame_size }),
+            Err(e) => Ok(Some(Err(CodecError::Deflate(e)))),
+        }
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = SendMessageError;
+
+    fn encode(&mut self, message: &Message, dst: &mut BytesMut) -> Result<(), SendMessageError> {
+        let data = message.encode(self.format.codec)?;
+        let before = data.len();
+        let (header, payload) = frame::encode(data, self.format.compression, self.max_frame_size)?;
+        if frame::parse_header(header).1 {
+            self.compressed.before += before as u64;
+            self.compressed.after += payload.len() as u64;
+        }
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_u32(header);
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+/// stream of messages received from the other side
+///
+/// ends (`None`) when the other side closes the connection between frames
+pub struct MessageReader {
+    inner: FramedRead<StreamReader, MessageCodec>,
+}
+
+impl MessageReader {
+    pub fn new(stream: StreamReader, format: WireFormat, max_frame_size: usize) -> Self {
+        MessageReader { inner: FramedRead::new(stream, MessageCodec::new(format, max_frame_size)) }
+    }
+
+    /// switches to the negotiated format
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.inner.decoder_mut().format = format;
+    }
+
+    /// waits for the next message; the end of the stream is reported as `RemoteDisconnected`
+    ///
+    /// cancellation safe, a partially received frame stays in the buffer
+    pub async fn receive(&mut self) -> Result<Message, ReceiveMessageError> {
+        match self.next().await {
+            Some(result) => result,
+            None => Err(ReceiveMessageError::RemoteDisconnected(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+}
+
+impl Stream for MessageReader {
+    type Item = Result<Message, ReceiveMessageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx).map(|item| item.map(|result| Ok(result??)))
+    }
+}
+
+/// messages bigger than `max_frame_size` are not sent, the other side (with the same limit) would refuse them
+pub fn writer(stream: StreamWriter, format: WireFormat, max_frame_size: usize) -> MessageWriter {
+    FramedWrite::new(stream, MessageCodec::new(format, max_frame_size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::CodecKind;
+    use futures::SinkExt;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    fn text(i: usize) -> Message {
+        Message::Text { from: "hugo".into(), content: format!("message {}", i) }
+    }
+
+    fn frame_bytes(message: &Message, format: WireFormat) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        MessageCodec::new(format, usize::MAX).encode(message, &mut buffer).unwrap();
+        buffer.to_vec()
+    }
+
+    // raw writing side and message reader of one in-memory connection
+    fn connection(buffer: usize, format: WireFormat, max_frame_size: usize) -> (tokio::io::DuplexStream, MessageReader) {
+        let (client, server) = tokio::io::duplex(buffer);
+        (client, MessageReader::new(Box::new(server), format, max_frame_size))
+    }
+
+    #[test]
+    fn test_decoder_waits_for_whole_frame() {
+        let bytes = frame_bytes(&text(1), WireFormat::HELLO);
+        let mut codec = MessageCodec::new(WireFormat::HELLO, 1024);
+        let mut buffer = BytesMut::new();
+        // feeding the frame byte by byte, the message appears only after the last one
+        for (i, byte) in bytes.iter().enumerate() {
+            buffer.put_u8(*byte);
+            let decoded = codec.decode(&mut buffer).unwrap();
+            if i + 1 < bytes.len() {
+                assert!(decoded.is_none());
+            } else {
+                assert_eq!(decoded.unwrap().unwrap(), text(1));
+            }
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decoder_refuses_large_frame_from_header() {
+        let bytes = frame_bytes(&Message::Text { from: "hugo".into(), content: "x".repeat(2000) }, WireFormat::HELLO);
+        let mut codec = MessageCodec::new(WireFormat::HELLO, 1024);
+        // only the header is needed to refuse it
+        let mut buffer = BytesMut::from(&bytes[..HEADER_LEN]);
+        assert!(matches!(codec.decode(&mut buffer), Err(ReceiveMessageError::FrameTooLarge { max: 1024, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_partial_reads() {
+        let format = WireFormat { codec: CodecKind::Json, compression: true };
+        let messages = vec![text(1), Message::Text { from: "hugo".into(), content: "hello ".repeat(1000) }, text(2)];
+        let bytes: Vec<u8> = messages.iter().flat_map(|m| frame_bytes(m, format)).collect();
+        let (mut stream, mut reader) = connection(16, format, 100_000);
+        tokio::spawn(async move {
+            // odd sized pieces so that frame boundaries are in the middle of reads
+            for chunk in bytes.chunks(7) {
+                stream.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        for message in messages {
+            assert_eq!(reader.next().await.unwrap().unwrap(), message);
+        }
+        // clean end of stream after the last frame
+        assert!(reader.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_receive_keeps_partial_frame() {
+        let bytes = frame_bytes(&text(1), WireFormat::HELLO);
+        let (mut stream, mut reader) = connection(1024, WireFormat::HELLO, 1024);
+
+        // half of the frame arrives and then the receive is cancelled by timeout
+        stream.write_all(&bytes[..bytes.len() / 2]).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), reader.receive()).await.is_err());
+        stream.write_all(&bytes[bytes.len() / 2..]).await.unwrap();
+        assert_eq!(reader.receive().await.unwrap(), text(1));
+        drop(stream);
+        assert!(matches!(reader.receive().await, Err(ReceiveMessageError::RemoteDisconnected(_))));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_message_does_not_end_stream() {
+        let (mut stream, mut reader) = connection(1024, WireFormat::HELLO, 1024);
+        stream.write_all(&[0, 0, 0, 3, 255, 255, 255]).await.unwrap();
+        stream.write_all(&frame_bytes(&text(1), WireFormat::HELLO)).await.unwrap();
+        assert!(matches!(reader.receive().await, Err(ReceiveMessageError::DeserializationError(_))));
+        assert_eq!(reader.receive().await.unwrap(), text(1));
+    }
+
+    #[test]
+    fn test_encoder_refuses_frame_the_other_side_would_refuse() {
+        let message = Message::Text { from: "hugo".into(), content: "x".repeat(2000) };
+        let len = message.encode(CodecKind::Bincode).unwrap().len();
+        let mut buffer = BytesMut::new();
+        MessageCodec::new(WireFormat::HELLO, len).encode(&message, &mut buffer).unwrap();
+        assert_eq!(buffer.len(), HEADER_LEN + len);
+
+        let mut buffer = BytesMut::new();
+        let result = MessageCodec::new(WireFormat::HELLO, len - 1).encode(&message, &mut buffer);
+        assert!(matches!(result, Err(SendMessageError::GeneralStreamError(_))));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_only_compressed_frames_are_counted() {
+        let format = WireFormat { codec: CodecKind::Bincode, compression: true };
+        let mut codec = MessageCodec::new(format, 100_000);
+        let mut buffer = BytesMut::new();
+        codec.encode(&text(1), &mut buffer).unwrap();
+        assert_eq!(codec.take_compressed(), CompressedBytes::default());
+
+        let big = Message::Text { from: "hugo".into(), content: "hello ".repeat(1000) };
+        codec.encode(&big, &mut buffer).unwrap();
+        codec.encode(&big, &mut buffer).unwrap();
+        let compressed = codec.take_compressed();
+        let before = big.encode(CodecKind::Bincode).unwrap().len() as u64;
+        assert_eq!(compressed.before, 2 * before);
+        assert!(compressed.after > 0 && compressed.after < compressed.before);
+        // taken, counting starts again
+        assert_eq!(codec.take_compressed(), CompressedBytes::default());
+
+        // nothing is compressed without the negotiated compression
+        let mut codec = MessageCodec::new(WireFormat::HELLO, 100_000);
+        codec.encode(&big, &mut buffer).unwrap();
+        assert_eq!(codec.take_compressed(), CompressedBytes::default());
+    }
+
+    #[tokio::test]
+    async fn test_sink_and_format_change() {
+        let (stream, mut reader) = connection(1024, WireFormat::HELLO, 1024);
+        let mut writer = writer(Box::new(stream), WireFormat::HELLO, usize::MAX);
+        let negotiated = WireFormat { codec: CodecKind::MessagePack, compression: false };
+
+        writer.send(&text(1)).await.unwrap();
+        writer.encoder_mut().format = negotiated;
+        writer.send(&text(2)).await.unwrap();
+        assert_eq!(reader.receive().await.unwrap(), text(1));
+        reader.set_format(negotiated);
+        assert_eq!(reader.receive().await.unwrap(), text(2));
+    }
+}
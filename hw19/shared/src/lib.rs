@@ -1,11 +1,100 @@
-use bincode::Error as BincodeError;
-use log::{warn,debug};
+use log::debug;
 use serde::{Deserialize, Serialize};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use std::error::Error;
 use std::time::Duration;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub mod codec;
+pub mod fault;
+pub mod frame;
+pub mod framed;
+pub mod tls;
+pub mod transfer;
+use codec::{CodecError, CodecKind};
+use frame::WireFormat;
+use transfer::TransferKind;
+
+/// version of the protocol spoken by this build
+///
+/// bump it whenever layout of any message changes (bincode is not able to skip unknown fields)
+pub const PROTOCOL_VERSION: u16 = 1;
+/// the oldest version of the protocol the other side may speak so that we are still able to talk to it
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: u16 = 1;
+
+/// optional features of the protocol
+///
+/// both sides advertise what they support in the hello messages and only features present on both sides
+/// (see `Capabilities::intersection`) can be used afterwards
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// `Message::File` can be sent/received
+    pub const FILES: Capabilities = Capabilities(1 << 0);
+    /// `Message::Image` can be sent/received
+    pub const IMAGES: Capabilities = Capabilities(1 << 1);
+    /// files and images can be sent in chunks, see `transfer`
+    pub const FILE_TRANSFER: Capabilities = Capabilities(1 << 2);
+    /// chat messages get id from server (`Message::Stored`), their delivery is acknowledged (`Message::Accepted`, `Message::Received`)
+    pub const MESSAGE_IDS: Capabilities = Capabilities(1 << 3);
+    /// messages after the handshake may be encoded as JSON, see `codec`
+    pub const JSON_CODEC: Capabilities = Capabilities(1 << 4);
+    /// messages after the handshake may be encoded as MessagePack, see `codec`
+    pub const MSGPACK_CODEC: Capabilities = Capabilities(1 << 5);
+    /// big frames may be compressed, see `frame`
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 6);
+    /// the client authenticates itself after the hello messages (`Message::Login`, `Message::Register`)
+    pub const AUTH: Capabilities = Capabilities(1 << 7);
+    /// the client sends `Message::Ping` regularly, the server answers `Message::Pong` and disconnects silent clients
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 8);
+    /// the client can switch rooms (`Message::JoinRoom` etc.); clients without it stay in `DEFAULT_ROOM`
+    pub const ROOMS: Capabilities = Capabilities(1 << 9);
+    /// `Message::Direct` can be sent/received
+    pub const DIRECT_MESSAGES: Capabilities = Capabilities(1 << 10);
+    /// `Message::Presence` and `Message::Typing` can be sent/received
+    pub const PRESENCE: Capabilities = Capabilities(1 << 11);
+    /// authors can edit/delete their stored messages (`Message::Edit`, `Message::Delete`)
+    pub const EDITS: Capabilities = Capabilities(1 << 12);
+    /// emoji reactions to stored messages (`Message::React`, `Message::Reactions`)
+    pub const REACTIONS: Capabilities = Capabilities(1 << 13);
+    /// text/image/file messages may reply to stored message (`Message::Reply`); other clients get replies as plain messages
+    pub const THREADS: Capabilities = Capabilities(1 << 14);
+    /// server announces it's going down (`Message::ServerShutdown`) before closing the connection
+    pub const SHUTDOWN_NOTICE: Capabilities = Capabilities(1 << 15);
+    /// client tells which stored message it got last (`Message::Resume`), the server replays everything after it
+    pub const RESUME: Capabilities = Capabilities(1 << 16);
+    /// full-text search in stored messages (`Message::Search`, `Message::SearchResults`)
+    pub const SEARCH: Capabilities = Capabilities(1 << 17);
+
+    /// everything this build is able to handle
+    pub fn all() -> Self {
+        Self::FILES.union(Self::IMAGES).union(Self::FILE_TRANSFER).union(Self::MESSAGE_IDS)
+            .union(Self::JSON_CODEC).union(Self::MSGPACK_CODEC).union(Self::COMPRESSION)
+            .union(Self::AUTH).union(Self::HEARTBEAT).union(Self::ROOMS)
+            .union(Self::DIRECT_MESSAGES).union(Self::PRESENCE)
+            .union(Self::EDITS).union(Self::REACTIONS)
+            .union(Self::THREADS).union(Self::SHUTDOWN_NOTICE)
+            .union(Self::RESUME).union(Self::SEARCH)
+    }
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(&self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn intersection(&self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn without(&self, other: Capabilities) -> Self {
+        Capabilities(self.0 & !other.0)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 
@@ -13,12 +102,130 @@ pub enum Message {
     Text { from: String, content: String },
     Image { from: String, content: Vec<u8> },
     File { from: String, name: String, content: Vec<u8> },
-    ClientHello { from: String },
-    ServerHello,
+    // note: `version` has to stay the first field and the variant has to stay on the same position,
+    // otherwise it's not possible to tell the version of incompatible client (see `handshake::client_hello_version`)
+    ClientHello { version: u16, from: String, capabilities: Capabilities },
+    ServerHello { version: u16, capabilities: Capabilities },
     ClientQuit { from: String },
+    ServerRefused { reason: String },
+    // chunked transfer, see `transfer` module
+    /// `transfer_id` is `None` when sent by the uploading client, the server assigns it
+    FileOffer { transfer_id: Option<u64>, from: String, name: String, kind: TransferKind, size: u64, checksum: String },
+    FileAccept { transfer_id: u64, checksum: String, next_chunk: u64 },
+    FileChunk { transfer_id: u64, index: u64, data: Vec<u8> },
+    FileComplete { transfer_id: u64 },
+    FileFailed { transfer_id: u64, reason: String },
+    /// chat message stored by the server; `id` grows monotonically, `time` is in ms since unix epoch
+    Stored { id: u64, time: u64, message: Box<Message> },
+    /// server -> sender: the chat message was stored under given id (acks come in the same order as the messages were sent)
+    Accepted { id: u64, time: u64 },
+    /// server -> sender: the chat message was not stored
+    Rejected { reason: String },
+    /// client -> server: the stored message was received
+    Received { id: u64 },
+    // authentication, sent right after hello messages (in the same format); the name is taken from `ClientHello`
+    /// `password` is `None` for anonymous user
+    Login { password: Option<String> },
+    Register { password: String },
+    AuthOk,
+    AuthFailed { reason: String },
+    /// client -> server every `HEARTBEAT_INTERVAL`; any message proves the other side is alive, ping makes sure there is one
+    Ping,
+    Pong,
+    // rooms; every client is in exactly one room, chat messages go only to the clients in the same room
+    /// client -> server: creates the room and joins it
+    CreateRoom { name: String },
+    /// client -> server: leaves the current room and joins given one
+    JoinRoom { name: String },
+    /// client -> server: goes back to `DEFAULT_ROOM`
+    LeaveRoom,
+    ListRooms,
+    /// server -> client: room names with count of connected members
+    RoomList { rooms: Vec<(String, u32)> },
+    /// server -> client: the client is in the room now (reply to create/join/leave)
+    RoomJoined { name: String },
+    RoomFailed { reason: String },
+    /// private text message, delivered only to `to` (later, if `to` is offline); acknowledged like other chat messages
+    Direct { from: String, to: String, content: String },
+    /// the user changed its presence; sent to everybody, the server keeps only the current one (not stored in db)
+    Presence { from: String, state: PresenceState, status: Option<String> },
+    /// the user is writing a message; sent to the room, never stored
+    Typing { from: String },
+    /// new content of stored text (or direct) message `id`; only its author may send it, acknowledged like chat messages
+    Edit { id: u64, from: String, content: String },
+    /// stored message `id` was retracted by its author
+    Delete { id: u64, from: String },
+    /// client -> server: adds the reaction to stored message `id`, or removes it if the user already reacted so; acknowledged like chat messages
+    React { id: u64, emoji: String },
+    /// server -> client: reactions of message `id` changed; emoji with count of users, in order of the first reaction
+    Reactions { id: u64, reactions: Vec<(String, u32)> },
+    /// text, image or file message replying to stored message `parent` (in the same room)
+    Reply { parent: u64, message: Box<Message> },
+    /// server -> client: the server is stopping, the connection is closed after the messages queued before this one
+    ServerShutdown { reason: Option<String> },
+    /// client -> server right after authentication (in the hello format): id of the last stored message the client received,
+    /// `None` if it hasn't received any (the server then replays what was stored since the user was last online)
+    Resume { last_id: Option<u64> },
+    /// client -> server: finds stored messages containing all the words (their prefixes), in texts and file names
+    Search { query: String },
+    /// server -> client: the best matches of the query the user can see, the oldest first
+    SearchResults { query: String, results: Vec<SearchHit> },
+}
+
+/// stored message found by `Message::Search`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SearchHit {
+    pub id: u64,
+    /// ms since unix epoch
+    pub time: u64,
+    pub from: String,
+    /// empty for private messages
+    pub room: String,
+    /// text of the message, or name of the file
+    pub text: String,
+}
+
+/// availability of connected user; offline users have no presence
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Away,
+    DoNotDisturb,
+}
+
+impl std::fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresenceState::Online => write!(f, "online"),
+            PresenceState::Away => write!(f, "away"),
+            PresenceState::DoNotDisturb => write!(f, "dnd"),
+        }
+    }
 }
 
-pub const STREAM_READ_TIMEOUT: Duration = Duration::from_millis(100);
+impl std::str::FromStr for PresenceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(PresenceState::Online),
+            "away" => Ok(PresenceState::Away),
+            "dnd" => Ok(PresenceState::DoNotDisturb),
+            _ => Err(format!("Unknown presence {}, use online, away or dnd", s)),
+        }
+    }
+}
+
+/// room every client starts in
+pub const DEFAULT_ROOM: &str = "general";
+
+/// how often the client pings the server
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// default for how long (in seconds) the other side may be silent before the connection is considered dead
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 30;
+/// default for the biggest frame (serialized message) we are willing to receive; can be changed from command line
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ReceiveMessageError {
@@ -27,44 +234,113 @@ pub enum ReceiveMessageError {
     #[error("Client disconnected")]
     RemoteDisconnected(#[source] std::io::Error),
     #[error("Unable to deserialize message")]
-    DeserializationError(#[from] BincodeError),
+    DeserializationError(#[from] CodecError),
+    #[error("Frame of {size} bytes exceeds the limit of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
 }
 
 impl Message {
-    pub fn serialize(&self) -> Result<Vec<u8>, BincodeError> {
-        let mut res = bincode::serialize(&self)?;
-        if chaos::is_time_for_random_error() {
-            res.remove(0);
-            warn!("Chaos monkey is removing first byte from serialized message");
+    /// capabilities the receiving side has to support in order to get this message
+    pub fn required_capabilities(&self) -> Capabilities {
+        match self {
+            Message::File { .. } => Capabilities::FILES,
+            Message::Image { .. } => Capabilities::IMAGES,
+            Message::FileOffer { .. } |
+            Message::FileAccept { .. } |
+            Message::FileChunk { .. } |
+            Message::FileComplete { .. } |
+            Message::FileFailed { .. } => Capabilities::FILE_TRANSFER,
+            Message::Stored { message, .. } => message.required_capabilities().union(Capabilities::MESSAGE_IDS),
+            Message::Accepted { .. } |
+            Message::Rejected { .. } |
+            Message::Received { .. } => Capabilities::MESSAGE_IDS,
+            Message::Login { .. } |
+            Message::Register { .. } |
+            Message::AuthOk |
+            Message::AuthFailed { .. } => Capabilities::AUTH,
+            Message::Ping |
+            Message::Pong => Capabilities::HEARTBEAT,
+            Message::CreateRoom { .. } |
+            Message::JoinRoom { .. } |
+            Message::LeaveRoom |
+            Message::ListRooms |
+            Message::RoomList { .. } |
+            Message::RoomJoined { .. } |
+            Message::RoomFailed { .. } => Capabilities::ROOMS,
+            Message::Direct { .. } => Capabilities::DIRECT_MESSAGES,
+            Message::Presence { .. } |
+            Message::Typing { .. } => Capabilities::PRESENCE,
+            Message::Edit { .. } |
+            Message::Delete { .. } => Capabilities::EDITS,
+            Message::React { .. } |
+            Message::Reactions { .. } => Capabilities::REACTIONS,
+            Message::Reply { message, .. } => message.required_capabilities().union(Capabilities::THREADS),
+            Message::ServerShutdown { .. } => Capabilities::SHUTDOWN_NOTICE,
+            Message::Resume { .. } => Capabilities::RESUME,
+            Message::Search { .. } |
+            Message::SearchResults { .. } => Capabilities::SEARCH,
+            _ => Capabilities::NONE,
         }
-        Ok(res)
     }
-    pub fn deserialize(mut from: &[u8]) -> Result<Self, BincodeError> {
-        if chaos::is_time_for_random_error() {
-            warn!("Chaos monkey is removing first byte from message before deserialization");
-            from = &from[1..];
+
+    /// the message itself, or the message inside `Message::Reply`
+    /// chat message the server stores (and sends wrapped in `Stored`); anything else in `Stored` didn't come from the server's storage
+    pub fn is_storable(&self) -> bool {
+        matches!(self.unwrap_reply(), Message::Text { .. } | Message::Image { .. } | Message::File { .. } | Message::Direct { .. } | Message::FileOffer { .. })
+    }
+
+    pub fn unwrap_reply(&self) -> &Message {
+        match self {
+            Message::Reply { message, .. } => message,
+            message => message,
         }
-        bincode::deserialize(&from)
     }
 
-    pub async fn send(&self, tcp_stream: &mut OwnedWriteHalf) -> Result<(), Box<dyn Error>> {
-        let data = self.serialize()?;
-        let data_len = data.len() as u32;
-        tcp_stream.write(&data_len.to_be_bytes()).await?;
-        tcp_stream.write_all(&data).await?;
+    /// bincode; used for storage and hello messages
+    pub fn serialize(&self) -> Result<Vec<u8>, CodecError> {
+        self.encode(CodecKind::Bincode)
+    }
+    pub fn deserialize(from: &[u8]) -> Result<Self, CodecError> {
+        Self::decode(CodecKind::Bincode, from)
+    }
+
+    pub fn encode(&self, codec: CodecKind) -> Result<Vec<u8>, CodecError> {
+        codec.codec().encode(self)
+    }
+    pub fn decode(codec: CodecKind, from: &[u8]) -> Result<Self, CodecError> {
+        codec.codec().decode(from)
+    }
+
+    /// note: used only for the handshake (small messages), so the size is limited just by the frame header
+    pub async fn send(&self, tcp_stream: &mut (impl AsyncWrite + Unpin), format: WireFormat) -> Result<(), Box<dyn Error>> {
+        let data = self.encode(format.codec)?;
+        let (header, payload) = frame::encode(data, format.compression, usize::MAX)?;
+        tcp_stream.write_all(&header.to_be_bytes()).await?;
+        tcp_stream.write_all(&payload).await?;
+        // TLS stream may buffer
+        tcp_stream.flush().await?;
         Ok(())
     }
 
-    pub async fn receive(stream: &mut OwnedReadHalf) -> Result<Message, ReceiveMessageError> {
+    pub async fn receive(stream: &mut (impl AsyncRead + Unpin), max_frame_size: usize, format: WireFormat) -> Result<Message, ReceiveMessageError> {
+        let frame = Self::receive_frame(stream, max_frame_size).await?;
+        Ok(Message::decode(format.codec, &frame)?)
+    }
+
+    /// reads one length-prefixed frame without deserializing it; compressed frame is decompressed
+    ///
+    /// frames longer than `max_frame_size` are refused before any memory is allocated for them,
+    /// the same limit applies to decompressed content
+    pub async fn receive_frame(stream: &mut (impl AsyncRead + Unpin), max_frame_size: usize) -> Result<Vec<u8>, ReceiveMessageError> {
 
         use ReceiveMessageError::*;
         
         debug!("reading data len");
-        let data_len = {
+        let (data_len, compressed) = {
             let mut len_bytes = [0u8; 4];
             match stream.read_exact(&mut len_bytes).await
             {
-                Ok(_) => u32::from_be_bytes(len_bytes) as usize,
+                Ok(_) => frame::parse_header(u32::from_be_bytes(len_bytes)),
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 
                 { 
                     debug!("error reading data: {:?}", e); 
@@ -77,13 +353,146 @@ impl Message {
             }
         };
         debug!("data len: {}", data_len);
+        if data_len > max_frame_size {
+            return Err(FrameTooLarge { size: data_len, max: max_frame_size });
+        }
 
-        let message = {
-            let mut buffer = vec![0u8; data_len];
-            stream.read_exact(&mut buffer).await?;
-            Message::deserialize(&buffer)?
-        };
-        Ok(message)
+        let mut buffer = vec![0u8; data_len];
+        stream.read_exact(&mut buffer).await?;
+        if !compressed {
+            return Ok(buffer);
+        }
+        match frame::decompress(&buffer, max_frame_size).map_err(CodecError::Deflate)? {
+            Some(data) => Ok(data),
+            // the real size is not known, we stopped reading at the limit
+            None => Err(FrameTooLarge { size: max_frame_size + 1, max: max_frame_size }),
+        }
+    }
+}
+
+pub mod handshake {
+    use serde::{Deserialize, Serialize};
+    use crate::{Message, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+    /// version of clients from before the versioning - their hello is just `ClientHello { from }`
+    pub const LEGACY_PROTOCOL_VERSION: u16 = 0;
+
+    // position of `Message::ClientHello` in the enum
+    const CLIENT_HELLO_TAG: u32 = 3;
+
+    // the part of serialized `Message::ClientHello` that never changes between versions
+    #[derive(Deserialize)]
+    struct ClientHelloHeader {
+        tag: u32,
+        version: u16,
+    }
+
+    // the whole `Message::ClientHello` of legacy client
+    #[derive(Serialize, Deserialize)]
+    struct LegacyClientHello {
+        tag: u32,
+        from: String,
+    }
+
+    /// reads protocol version from serialized `Message::ClientHello`
+    ///
+    /// works even for clients speaking different version of the protocol whose hello can't be deserialized;
+    /// returns `None` if the frame is not a client hello at all, `LEGACY_PROTOCOL_VERSION` for hello without version
+    pub fn client_hello_version(frame: &[u8]) -> Option<u16> {
+        // note: legacy hello has to be checked first, length of its name would be read as the version
+        if is_legacy_client_hello(frame) {
+            return Some(LEGACY_PROTOCOL_VERSION);
+        }
+        match bincode::deserialize::<ClientHelloHeader>(frame) {
+            Ok(ClientHelloHeader { tag: CLIENT_HELLO_TAG, version }) => Some(version),
+            _ => None,
+        }
+    }
+
+    // legacy hello is the tag and the name, nothing else; hello with version can't be read like that (name would be too long)
+    fn is_legacy_client_hello(frame: &[u8]) -> bool {
+        match bincode::deserialize::<LegacyClientHello>(frame) {
+            Ok(hello) => hello.tag == CLIENT_HELLO_TAG && bincode::serialized_size(&hello).ok() == Some(frame.len() as u64),
+            Err(_) => false,
+        }
+    }
+
+    /// refusal the legacy client is able to decode - it knows only the first messages (`Text`, ...), not `ServerRefused`
+    ///
+    /// note: it prints just "Unexpected message from server", but it doesn't fail on deserialization
+    pub fn legacy_refusal(reason: String) -> Message {
+        Message::Text { from: "server".into(), content: reason }
+    }
+
+    /// checks whether we are able to talk to the other side; returns human readable reason if not
+    pub fn check_version(other_version: u16) -> Result<(), String> {
+        if other_version < MIN_SUPPORTED_PROTOCOL_VERSION {
+            Err(format!("Protocol version {} is too old, supported versions are {}..={}", other_version, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION))
+        } else if other_version > PROTOCOL_VERSION {
+            Err(format!("Protocol version {} is too new, supported versions are {}..={}", other_version, MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION))
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::{Capabilities, Message};
+
+        #[test]
+        fn test_client_hello_version_is_readable() {
+            let hello = Message::ClientHello { version: 42, from: "hugo".into(), capabilities: Capabilities::all() };
+            let frame = bincode::serialize(&hello).unwrap();
+            assert_eq!(client_hello_version(&frame), Some(42));
+        }
+
+        #[test]
+        fn test_client_hello_version_is_none_for_other_messages() {
+            let text = Message::Text { from: "hugo".into(), content: "hello".into() };
+            let frame = bincode::serialize(&text).unwrap();
+            assert_eq!(client_hello_version(&frame), None);
+            assert_eq!(client_hello_version(&[]), None);
+        }
+
+        #[test]
+        fn test_legacy_client_hello_is_recognized() {
+            for from in ["", "a", "hugo", "someone with a long name"] {
+                let legacy = LegacyClientHello { tag: CLIENT_HELLO_TAG, from: from.into() };
+                let frame = bincode::serialize(&legacy).unwrap();
+                assert_eq!(client_hello_version(&frame), Some(LEGACY_PROTOCOL_VERSION), "{:?}", from);
+            }
+            for version in [MIN_SUPPORTED_PROTOCOL_VERSION, PROTOCOL_VERSION] {
+                let hello = Message::ClientHello { version, from: "a".into(), capabilities: Capabilities::all() };
+                let frame = bincode::serialize(&hello).unwrap();
+                assert_eq!(client_hello_version(&frame), Some(version));
+            }
+            assert!(check_version(LEGACY_PROTOCOL_VERSION).is_err());
+        }
+
+        #[test]
+        fn test_legacy_refusal_is_decoded_as_legacy_text() {
+            // the first version of the protocol, as the legacy client knows it
+            #[derive(Deserialize, Debug, PartialEq)]
+            enum LegacyMessage {
+                Text { from: String, content: String },
+                Image { from: String, content: Vec<u8> },
+                File { from: String, name: String, content: Vec<u8> },
+                ClientHello { from: String },
+                ServerHello,
+                ClientQuit { from: String },
+            }
+            let frame = bincode::serialize(&legacy_refusal("Upgrade".into())).unwrap();
+            let decoded: LegacyMessage = bincode::deserialize(&frame).unwrap();
+            assert_eq!(decoded, LegacyMessage::Text { from: "server".into(), content: "Upgrade".into() });
+        }
+
+        #[test]
+        fn test_check_version() {
+            assert!(check_version(PROTOCOL_VERSION).is_ok());
+            assert!(check_version(PROTOCOL_VERSION + 1).is_err());
+            assert!(check_version(MIN_SUPPORTED_PROTOCOL_VERSION - 1).is_err());
+        }
     }
 }
 
@@ -96,13 +505,3 @@ pub mod logging {
         init_from_env(Env::default().default_filter_or("info"));
     }
 }
-
-pub mod chaos {
-
-    pub fn enabled() -> bool {
-        std::env::var("CHAOS_MONKEY").is_ok()
-    }
-    pub fn is_time_for_random_error() -> bool {
-        enabled() && rand::random::<u8>() < 30
-    }
-}
\ No newline at end of file
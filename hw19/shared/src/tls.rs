@@ -0,0 +1,178 @@
+//! optional TLS transport (rustls)
+//!
+//! both plain and TLS connections are split into boxed halves (`StreamReader`, `StreamWriter`),
+//! so the rest of the code doesn't care which one is used
+//!
+//! the client doesn't use system certificates, it trusts only the CA given on command line (pinning);
+//! for development the server is able to generate self-signed certificate that serves as the CA as well
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+pub type StreamReader = Box<dyn AsyncRead + Unpin + Send>;
+pub type StreamWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("TLS error: {0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("Unable to generate certificate: {0}")]
+    Generate(#[from] rcgen::RcgenError),
+    #[error("No certificate found in {0}")]
+    NoCertificate(String),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("Invalid server name {0}")]
+    InvalidServerName(String),
+}
+
+pub fn plain(stream: TcpStream) -> (StreamReader, StreamWriter) {
+    let (reader, writer) = stream.into_split();
+    (Box::new(reader), Box::new(writer))
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates: Vec<_> = rustls_pemfile::certs(&mut reader)?.into_iter().map(Certificate).collect();
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate(path.display().to_string()));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) |
+            rustls_pemfile::Item::RSAKey(key) |
+            rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+/// server side; certificate chain and private key are PEM files
+pub fn acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, TlsError> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certificates(cert_path)?, load_private_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// client side; only servers with certificate signed by the CA (PEM file) are trusted
+pub fn connector(ca_path: &Path) -> Result<TlsConnector, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_path)? {
+        roots.add(&certificate)?;
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> Result<(StreamReader, StreamWriter), TlsError> {
+    let stream = acceptor.accept(stream).await?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// `server_name` (dns name or ip address) has to match the server certificate
+pub async fn connect(connector: &TlsConnector, server_name: &str, stream: TcpStream) -> Result<(StreamReader, StreamWriter), TlsError> {
+    let name = ServerName::try_from(server_name).map_err(|_| TlsError::InvalidServerName(server_name.into()))?;
+    let stream = connector.connect(name, stream).await?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// self-signed certificate for development; returns certificate and private key (both PEM)
+///
+/// names that are ip addresses are stored as such, so that clients may connect to e.g. 127.0.0.1
+pub fn generate_dev_certificate(names: &[&str]) -> Result<(String, String), TlsError> {
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new());
+    params.subject_alt_names = names.iter()
+        .map(|name| match name.parse() {
+            Ok(ip) => rcgen::SanType::IpAddress(ip),
+            Err(_) => rcgen::SanType::DnsName(name.to_string()),
+        })
+        .collect();
+    params.distinguished_name.push(rcgen::DnType::CommonName, "chatapp development");
+    let certificate = rcgen::Certificate::from_params(params)?;
+    Ok((certificate.serialize_pem()?, certificate.serialize_private_key_pem()))
+}
+
+/// writes dev certificate for localhost to given paths (see `generate_dev_certificate`)
+pub fn write_dev_certificate(cert_path: &Path, key_path: &Path) -> Result<(), TlsError> {
+    let (certificate, key) = generate_dev_certificate(&["localhost", "127.0.0.1", "::1"])?;
+    std::fs::write(cert_path, certificate)?;
+    std::fs::write(key_path, key)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Message;
+    use crate::frame::WireFormat;
+    use tokio::net::TcpListener;
+
+    fn write_certificate(dir: &Path, prefix: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        std::fs::create_dir_all(dir).unwrap();
+        let cert = dir.join(format!("{}.crt", prefix));
+        let key = dir.join(format!("{}.key", prefix));
+        write_dev_certificate(&cert, &key).unwrap();
+        (cert, key)
+    }
+
+    #[tokio::test]
+    async fn test_message_goes_through_tls() {
+        let dir = std::env::temp_dir().join(format!("chatapp_tls_{}", std::process::id()));
+        let (cert, key) = write_certificate(&dir, "ok");
+        let acceptor = acceptor(&cert, &key).unwrap();
+        let connector = connector(&cert).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, _writer) = accept(&acceptor, stream).await.unwrap();
+            Message::receive(&mut reader, 1024, WireFormat::HELLO).await.unwrap()
+        });
+
+        let (_reader, mut writer) = connect(&connector, "127.0.0.1", TcpStream::connect(addr).await.unwrap()).await.unwrap();
+        let message = Message::Text { from: "hugo".into(), content: "secret".into() };
+        message.send(&mut writer, WireFormat::HELLO).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn test_server_with_other_certificate_is_refused() {
+        let dir = std::env::temp_dir().join(format!("chatapp_tls_{}", std::process::id()));
+        let (cert, key) = write_certificate(&dir, "server");
+        let (other_ca, _) = write_certificate(&dir, "other");
+        let acceptor = acceptor(&cert, &key).unwrap();
+        let connector = connector(&other_ca).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = accept(&acceptor, stream).await;
+        });
+
+        assert!(connect(&connector, "127.0.0.1", TcpStream::connect(addr).await.unwrap()).await.is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! building blocks of the chunked file transfer
+//!
+//! Flow (sender -> server -> other clients):
+//! 1. sender sends `FileOffer` (without id)
+//! 2. server replies `FileAccept` with id of the transfer and the chunk to continue from 
+//!    (non-zero when the same file was partially uploaded before)
+//! 3. server announces `FileOffer` (with id) to other clients
+//! 4. sender streams `FileChunk`s from disk, server stores and forwards them
+//! 5. sender sends `FileComplete`; server verifies the checksum and forwards it (or replies `FileFailed`)
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// size of one `Message::FileChunk`; the last chunk may be shorter
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransferKind {
+    File,
+    Image,
+}
+
+/// count of chunks needed for content of given size
+pub fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE as u64)
+}
+
+/// length of the chunk in content of given size, `None` if the content has no such chunk
+pub fn chunk_len(size: u64, index: u64) -> Option<usize> {
+    (index < chunk_count(size)).then(|| (size - chunk_offset(index)).min(CHUNK_SIZE as u64) as usize)
+}
+
+/// offset of the chunk in the transferred content
+pub fn chunk_offset(index: u64) -> u64 {
+    index * CHUNK_SIZE as u64
+}
+
+/// incremental sha256 of the transferred content; the result is hex encoded
+#[derive(Default)]
+pub struct Checksum(Sha256);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        self.0.finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunk_count() {
+        assert_eq!(chunk_count(0), 0);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), 2);
+    }
+
+    #[test]
+    fn test_chunk_len() {
+        let size = CHUNK_SIZE as u64 + 10;
+        assert_eq!(chunk_len(size, 0), Some(CHUNK_SIZE));
+        assert_eq!(chunk_len(size, 1), Some(10));
+        assert_eq!(chunk_len(size, 2), None);
+        assert_eq!(chunk_len(CHUNK_SIZE as u64, 0), Some(CHUNK_SIZE));
+        assert_eq!(chunk_len(CHUNK_SIZE as u64, 1), None);
+        assert_eq!(chunk_len(0, 0), None);
+    }
+
+    #[test]
+    fn test_checksum_is_same_for_chunked_content() {
+        let mut whole = Checksum::new();
+        whole.update(b"hello world");
+        let mut chunked = Checksum::new();
+        chunked.update(b"hello ");
+        chunked.update(b"world");
+        let whole = whole.finish();
+        assert_eq!(whole, chunked.finish());
+        assert_eq!(whole, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+    }
+}